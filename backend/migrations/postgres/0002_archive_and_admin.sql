@@ -0,0 +1,5 @@
+ALTER TABLE retros ADD COLUMN archived_at TEXT;
+
+CREATE INDEX retros_archived_at ON retros(archived_at);
+
+ALTER TABLE users ADD COLUMN admin BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,4 @@
+ALTER TABLE users ADD COLUMN guest BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- Guests can join a retro while it has an invite
+ALTER TABLE retros ADD COLUMN guest_invite TEXT;
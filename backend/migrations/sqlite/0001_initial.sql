@@ -0,0 +1,53 @@
+CREATE TABLE users (
+    id TEXT PRIMARY KEY NOT NULL,
+    username TEXT NOT NULL
+);
+
+CREATE TABLE retros (
+    id TEXT PRIMARY KEY NOT NULL,
+    retro_name TEXT NOT NULL,
+    creator_id TEXT NOT NULL,
+    step TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    version INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE TABLE lanes (
+    id TEXT PRIMARY KEY NOT NULL,
+    retro_id TEXT NOT NULL REFERENCES retros(id) ON DELETE CASCADE,
+    title TEXT NOT NULL,
+    priority INTEGER NOT NULL
+);
+
+CREATE INDEX lanes_retro_id ON lanes(retro_id);
+
+-- Subcards point at their parent card; top-level cards have no parent
+CREATE TABLE cards (
+    id TEXT PRIMARY KEY NOT NULL,
+    retro_id TEXT NOT NULL REFERENCES retros(id) ON DELETE CASCADE,
+    lane_id TEXT NOT NULL REFERENCES lanes(id) ON DELETE CASCADE,
+    parent_card_id TEXT REFERENCES cards(id) ON DELETE CASCADE,
+    creator_id TEXT NOT NULL,
+    text TEXT NOT NULL,
+    position INTEGER NOT NULL
+);
+
+CREATE INDEX cards_retro_id ON cards(retro_id);
+
+CREATE TABLE votes (
+    card_id TEXT NOT NULL REFERENCES cards(id) ON DELETE CASCADE,
+    retro_id TEXT NOT NULL REFERENCES retros(id) ON DELETE CASCADE,
+    user_id TEXT NOT NULL,
+    PRIMARY KEY (card_id, user_id)
+);
+
+CREATE INDEX votes_retro_id ON votes(retro_id);
+
+CREATE TABLE participants (
+    retro_id TEXT NOT NULL REFERENCES retros(id) ON DELETE CASCADE,
+    user_id TEXT NOT NULL,
+    position INTEGER NOT NULL,
+    PRIMARY KEY (retro_id, user_id)
+);
+
+CREATE INDEX participants_user_id ON participants(user_id);
@@ -0,0 +1,5 @@
+-- Only set for users who registered through local auth
+ALTER TABLE users ADD COLUMN password_hash TEXT;
+
+-- Users from an external identity store may share names, but local logins must be unambiguous
+CREATE UNIQUE INDEX users_local_username ON users(username) WHERE password_hash IS NOT NULL;
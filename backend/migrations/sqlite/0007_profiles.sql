@@ -0,0 +1,3 @@
+-- Profile details from the claims of the tokens users sign in with
+ALTER TABLE users ADD COLUMN email TEXT;
+ALTER TABLE users ADD COLUMN display_name TEXT;
@@ -1,9 +1,514 @@
-use actix_jwt_auth_middleware::FromRequest;
+use std::sync::{Arc, OnceLock};
+
+use actix_web::{dev::Payload, error, http::{header, StatusCode}, web::{self, Data, Json}, FromRequest, HttpRequest, HttpResponse};
+use argon2::{password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString}, Argon2};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
+use derive_more::derive::{Display, Error};
+use futures::future::{ready, Ready};
+use jwt_compact::{alg::{Hs512, Hs512Key}, AlgorithmExt, Header, TimeOptions, Token, UntrustedToken};
 use mongodb::bson::oid::ObjectId;
-use serde::{Deserialize, Serialize};
+use rand::RngCore;
+use serde::{de::DeserializeOwned, Deserialize, Serialize, Serializer};
+use sha2::{Digest, Sha256, Sha512};
+
+use crate::database::{PersistenceError, PersistenceManager};
+use crate::keys::{KeySet, TokenError};
+use crate::models::{LocalAuthConfig, LoginRequest, LoginResponse, RefreshRequest, RefreshToken, RefreshTokenStatus, TokenConfig, User};
+
+pub const ACCESS_TOKEN_NAME: &str = "access_token";
+// Tells personal API tokens apart from JWTs wherever an access token is accepted
+pub const API_TOKEN_PREFIX: &str = "retro_pat_";
+pub const MAX_USERNAME_LENGTH: usize = 64;
+pub const MAX_EMAIL_LENGTH: usize = 254;
+// Argon2 takes the whole input, so cap it rather than hash arbitrarily large bodies
+const MAX_PASSWORD_LENGTH: usize = 1024;
 
-#[derive(Clone, Debug, Deserialize, Serialize, FromRequest)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Claims {
     #[serde(serialize_with = "mongodb::bson::serde_helpers::serialize_object_id_as_hex_string")]
     pub subject_id: ObjectId,
+    // Set on guest tokens, which only reach this one retro
+    #[serde(default, skip_serializing_if = "Option::is_none", serialize_with = "serialize_optional_object_id")]
+    pub retro_id: Option<ObjectId>,
+    // Profile claims from whoever issued the token, under their OpenID Connect names. Users are
+    // created from them on their first request and kept up to date on later ones.
+    #[serde(default, rename = "preferred_username", skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(default, rename = "name", skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+}
+
+// Trims a profile claim and drops control characters, ignoring it when nothing is left
+pub fn profile_claim(value: Option<&str>, max_chars: usize) -> Option<String> {
+    let value: String = value?.trim().chars().filter(|c| !c.is_control()).take(max_chars).collect();
+    (!value.is_empty()).then_some(value)
+}
+
+impl Claims {
+    fn new(subject_id: ObjectId, retro_id: Option<ObjectId>) -> Self {
+        Claims { subject_id, retro_id, username: None, email: None, display_name: None }
+    }
+
+    // The user these claims describe. Claims that are left out keep what is stored, and a new
+    // user without a username claim goes by their display name, never their email.
+    fn profile(&self, stored: Option<&User>) -> User {
+        let username = profile_claim(self.username.as_deref(), MAX_USERNAME_LENGTH);
+        let email = profile_claim(self.email.as_deref(), MAX_EMAIL_LENGTH);
+        let display_name = profile_claim(self.display_name.as_deref(), MAX_USERNAME_LENGTH);
+        match stored {
+            Some(user) => User {
+                username: username.unwrap_or_else(|| user.username.clone()),
+                email: email.or_else(|| user.email.clone()),
+                display_name: display_name.or_else(|| user.display_name.clone()),
+                ..user.clone()
+            },
+            None => User {
+                _id: self.subject_id,
+                username: username.or_else(|| display_name.clone()).unwrap_or_else(|| self.subject_id.to_hex()),
+                admin: false,
+                guest: false,
+                email,
+                display_name,
+            },
+        }
+    }
+
+    // The profile to store for these claims, or None when the stored one is already up to date
+    pub fn profile_update(&self, stored: Option<&User>) -> Option<User> {
+        let profile = self.profile(stored);
+        (stored != Some(&profile)).then_some(profile)
+    }
+}
+
+fn serialize_optional_object_id<S: Serializer>(id: &Option<ObjectId>, serializer: S) -> Result<S::Ok, S::Error> {
+    match id {
+        Some(id) => serializer.serialize_some(&id.to_hex()),
+        None => serializer.serialize_none(),
+    }
+}
+
+// A key for something only this service signs and checks, derived from JWT_SECRET so that
+// nothing signed for one purpose verifies for another
+pub fn derived_key(secret: &str, purpose: &str) -> Hs512Key {
+    let mut hasher = Sha512::new();
+    hasher.update(purpose.as_bytes());
+    hasher.update([0]);
+    hasher.update(secret.as_bytes());
+    Hs512Key::new(hasher.finalize())
+}
+
+// URL-safe and unguessable, for anything handed out as a bearer secret
+pub fn random_string() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn from_access_token_header<T>(req: &HttpRequest, parse: impl FnOnce(&str, &KeySet) -> Result<T, TokenError>) -> Result<T, TokenError> {
+    match (req.app_data::<Data<KeySet>>(), req.headers().get(ACCESS_TOKEN_NAME)) {
+        (Some(key_set), Some(token)) => token.to_str()
+            .map_err(|_| TokenError::Invalid)
+            .and_then(|token| parse(token, key_set)),
+        (None, _) => {
+            log::error!("No access token key set is registered");
+            Err(TokenError::Invalid)
+        }
+        (_, None) => Err(TokenError::Missing),
+    }
+}
+
+// Protected handlers take Claims, verified against the same key set as subscriptions
+impl FromRequest for Claims {
+    type Error = TokenError;
+    type Future = Ready<Result<Claims, TokenError>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(from_access_token_header(req, |token, key_set| key_set.verify(token)))
+    }
+}
+
+// What the GraphQL endpoints accept: a verified JWT, or a personal API token that still has to
+// be looked up in persistence
+pub enum Credentials {
+    // With the time the token stops verifying, if it ever does
+    Jwt(Claims, Option<DateTime<Utc>>),
+    ApiToken(String),
+}
+
+impl Credentials {
+    pub fn parse(token: &str, key_set: &KeySet) -> Result<Credentials, TokenError> {
+        if token.starts_with(API_TOKEN_PREFIX) {
+            Ok(Credentials::ApiToken(token.to_string()))
+        } else {
+            key_set.verify_with_expiry(token).map(|(claims, valid_until)| Credentials::Jwt(claims, valid_until))
+        }
+    }
+}
+
+impl FromRequest for Credentials {
+    type Error = TokenError;
+    type Future = Ready<Result<Credentials, TokenError>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(from_access_token_header(req, Credentials::parse))
+    }
+}
+
+// The access token a websocket upgrade carries, for clients that leave it out of the
+// connection-init payload: the same header as the HTTP routes, or else a cookie of that name
+pub fn upgrade_access_token(req: &HttpRequest) -> Option<String> {
+    if let Some(token) = req.headers().get(ACCESS_TOKEN_NAME) {
+        return token.to_str().ok().map(str::to_string);
+    }
+    // Browsers attach cookies to cross-site upgrades too and CORS doesn't cover websockets, so
+    // only same-origin pages get to use the cookie
+    let origin = req.headers().get(header::ORIGIN).and_then(|origin| origin.to_str().ok())?;
+    let host = req.headers().get(header::HOST).and_then(|host| host.to_str().ok())?;
+    let (_, origin_host) = origin.split_once("://")?;
+    if !origin_host.eq_ignore_ascii_case(host) {
+        return None;
+    }
+    req.cookie(ACCESS_TOKEN_NAME).map(|cookie| cookie.value().to_string())
+}
+
+pub fn new_api_token() -> String {
+    format!("{}{}", API_TOKEN_PREFIX, random_string())
+}
+
+// API tokens are random enough that a plain digest is all the protection a stored hash needs
+pub fn hash_api_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+#[derive(Debug, Display, Error)]
+pub enum AuthError {
+    #[display("Invalid username or password.")]
+    InvalidCredentials,
+    #[display("That username is already taken.")]
+    UsernameTaken,
+    #[display("Registration is disabled.")]
+    RegistrationDisabled,
+    #[display("The refresh token is invalid or has expired.")]
+    InvalidRefreshToken,
+    #[display("{_0}")]
+    InvalidRequest(#[error(not(source))] String),
+    #[display("An internal error occurred. Please try again later.")]
+    Internal,
+    #[display("The service is temporarily unavailable. Please try again later.")]
+    Unavailable,
+}
+
+impl error::ResponseError for AuthError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AuthError::InvalidCredentials | AuthError::InvalidRefreshToken => StatusCode::UNAUTHORIZED,
+            AuthError::UsernameTaken => StatusCode::CONFLICT,
+            AuthError::RegistrationDisabled => StatusCode::FORBIDDEN,
+            AuthError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+            AuthError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+            AuthError::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+impl From<PersistenceError> for AuthError {
+    fn from(error: PersistenceError) -> Self {
+        match error {
+            PersistenceError::Conflict(_) => AuthError::UsernameTaken,
+            PersistenceError::NotFound(_) => AuthError::InvalidCredentials,
+            e => {
+                log::error!("Local auth failed to reach persistence: {}", e);
+                AuthError::Unavailable
+            }
+        }
+    }
+}
+
+// What a refresh token carries; everything else about it lives in persistence
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct RefreshClaims {
+    #[serde(serialize_with = "mongodb::bson::serde_helpers::serialize_object_id_as_hex_string")]
+    token_id: ObjectId,
+    #[serde(serialize_with = "mongodb::bson::serde_helpers::serialize_object_id_as_hex_string")]
+    family_id: ObjectId,
+}
+
+// Signs the tokens handed out at login, shared as app data whenever local auth or OIDC is on
+pub struct TokenIssuer {
+    pub config: TokenConfig,
+    pub signing_key: Hs512Key,
+    // For OIDC login state, which must never pass for an access token or the other way round
+    pub login_key: Hs512Key,
+    // Set whenever `config.refresh` is
+    pub refresh_key: Option<Hs512Key>,
+    pub persistence_manager: PersistenceManager,
+}
+
+fn validate_hs512<T: DeserializeOwned + Clone>(key: &Hs512Key, token_string: &str, check_expiry: bool) -> Option<T> {
+    let token = UntrustedToken::new(token_string).ok()?;
+    let token: Token<T> = Hs512.validator(key).validate(&token).ok()?;
+    if check_expiry {
+        token.claims().validate_expiration(&TimeOptions::default()).ok()?;
+    }
+    Some(token.claims().custom.clone())
+}
+
+impl TokenIssuer {
+    pub fn sign<T: Serialize>(&self, custom: T, ttl_secs: u64, key: &Hs512Key) -> Result<String, AuthError> {
+        let claims = jwt_compact::Claims::new(custom)
+            .set_duration_and_issuance(&TimeOptions::default(), chrono::Duration::seconds(ttl_secs as i64));
+        Hs512.token(&Header::empty(), &claims, key).map_err(|e| {
+            log::error!("Failed to sign token: {}", e);
+            AuthError::Internal
+        })
+    }
+
+    // Checks a token this service signed for itself, such as OIDC login state
+    pub fn verify<T: DeserializeOwned + Clone>(&self, token_string: &str, key: &Hs512Key) -> Option<T> {
+        validate_hs512(key, token_string, true)
+    }
+
+    fn access_token(&self, user_id: ObjectId) -> Result<String, AuthError> {
+        self.sign(Claims::new(user_id, None), self.config.ttl_secs, &self.signing_key)
+    }
+
+    // Guests get no refresh token; once it lapses they join again
+    pub fn guest_token(&self, user_id: ObjectId, retro_id: ObjectId, ttl_secs: u64) -> Result<String, AuthError> {
+        self.sign(Claims::new(user_id, Some(retro_id)), ttl_secs, &self.signing_key)
+    }
+
+    fn refresh_token(&self, token: &RefreshToken) -> Result<Option<String>, AuthError> {
+        let (Some(refresh), Some(key)) = (&self.config.refresh, &self.refresh_key) else {
+            return Ok(None);
+        };
+        let claims = RefreshClaims { token_id: token._id, family_id: token.family_id };
+        self.sign(claims, refresh.ttl_secs, key).map(Some)
+    }
+
+    fn refresh_expiry(&self) -> i64 {
+        let ttl_secs = self.config.refresh.as_ref().map_or(0, |refresh| refresh.ttl_secs);
+        Utc::now().timestamp() + ttl_secs as i64
+    }
+
+    // A login starts a new refresh token family
+    pub async fn start_session(&self, user_id: ObjectId) -> Result<LoginResponse, AuthError> {
+        let refresh_token = match self.config.refresh {
+            Some(_) => {
+                let token = RefreshToken {
+                    _id: ObjectId::new(),
+                    family_id: ObjectId::new(),
+                    user_id,
+                    expires_at: self.refresh_expiry(),
+                    status: RefreshTokenStatus::Active,
+                };
+                self.persistence_manager.create_refresh_token(token.clone()).await?;
+                self.refresh_token(&token)?
+            }
+            None => None,
+        };
+        Ok(LoginResponse { token: self.access_token(user_id)?, refresh_token })
+    }
+
+    fn verify_refresh_token(&self, token_string: &str, check_expiry: bool) -> Result<RefreshClaims, AuthError> {
+        let key = self.refresh_key.as_ref().ok_or(AuthError::InvalidRefreshToken)?;
+        validate_hs512(key, token_string, check_expiry).ok_or(AuthError::InvalidRefreshToken)
+    }
+}
+
+// Everything the local register and login endpoints need, shared as app data when local auth is on
+pub struct LocalAuth {
+    pub config: LocalAuthConfig,
+    pub tokens: Arc<TokenIssuer>,
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/api/v1/auth/register").route(web::post().to(register)))
+        .service(web::resource("/api/v1/auth/login").route(web::post().to(login)));
+}
+
+pub fn configure_refresh(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/api/v1/retro/token/refresh").route(web::post().to(refresh)))
+        .service(web::resource("/api/v1/retro/token/revoke").route(web::post().to(revoke)));
+}
+
+pub fn hash_password(password: &str) -> Result<String, AuthError> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default().hash_password(password.as_bytes(), &salt).map_err(|e| {
+        log::error!("Failed to hash password: {}", e);
+        AuthError::Internal
+    })?;
+    Ok(hash.to_string())
+}
+
+fn verify_password(password: &str, password_hash: &str) -> bool {
+    match PasswordHash::new(password_hash) {
+        Ok(parsed) => Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok(),
+        Err(e) => {
+            log::error!("Stored password hash is unreadable: {}", e);
+            false
+        }
+    }
+}
+
+// Logins for unknown users still verify a hash, so response times don't reveal who exists
+fn dummy_hash() -> &'static str {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    DUMMY_HASH.get_or_init(|| hash_password("not a real password").unwrap_or_default())
+}
+
+fn validate(request: &LoginRequest, config: &LocalAuthConfig) -> Result<(), AuthError> {
+    let username = request.username.trim();
+    if username.is_empty() || username.len() > MAX_USERNAME_LENGTH || username != request.username {
+        return Err(AuthError::InvalidRequest(format!(
+            "Usernames must be 1 to {} characters without leading or trailing spaces.", MAX_USERNAME_LENGTH
+        )));
+    }
+    if username.chars().any(char::is_control) {
+        return Err(AuthError::InvalidRequest("Usernames cannot contain control characters.".to_string()));
+    }
+    if request.password.chars().count() < config.min_password_length || request.password.len() > MAX_PASSWORD_LENGTH {
+        return Err(AuthError::InvalidRequest(format!(
+            "Passwords must be between {} and {} characters.", config.min_password_length, MAX_PASSWORD_LENGTH
+        )));
+    }
+    Ok(())
+}
+
+async fn register(request: Json<LoginRequest>, local_auth: Data<LocalAuth>) -> Result<HttpResponse, AuthError> {
+    if !local_auth.config.allow_registration {
+        return Err(AuthError::RegistrationDisabled);
+    }
+    let request = request.into_inner();
+    validate(&request, &local_auth.config)?;
+
+    let password = request.password;
+    let password_hash = web::block(move || hash_password(&password)).await.map_err(|_| AuthError::Internal)??;
+    let user = User {
+        _id: ObjectId::new(),
+        username: request.username,
+        admin: false,
+        guest: false,
+        email: None,
+        display_name: None,
+    };
+    let user = local_auth.tokens.persistence_manager.create_user(user, password_hash).await?;
+    log::info!("Registered local user {} ({})", user.username, user._id);
+
+    Ok(HttpResponse::Created().json(local_auth.tokens.start_session(user._id).await?))
+}
+
+async fn login(request: Json<LoginRequest>, local_auth: Data<LocalAuth>) -> Result<HttpResponse, AuthError> {
+    let request = request.into_inner();
+    if request.password.len() > MAX_PASSWORD_LENGTH {
+        return Err(AuthError::InvalidCredentials);
+    }
+
+    let credentials = match local_auth.tokens.persistence_manager.get_credentials(&request.username).await {
+        Ok(credentials) => Some(credentials),
+        Err(PersistenceError::NotFound(_)) => None,
+        Err(e) => return Err(e.into()),
+    };
+
+    let password_hash = match &credentials {
+        Some((_, password_hash)) => password_hash.clone(),
+        None => dummy_hash().to_string(),
+    };
+    let password = request.password;
+    let verified = web::block(move || verify_password(&password, &password_hash)).await.map_err(|_| AuthError::Internal)?;
+
+    match credentials {
+        Some((user, _)) if verified => Ok(HttpResponse::Ok().json(local_auth.tokens.start_session(user._id).await?)),
+        _ => Err(AuthError::InvalidCredentials),
+    }
+}
+
+// Trades a refresh token for a new access token and a new refresh token. Each refresh token
+// works once: presenting a rotated one again means it was copied, so its whole family is revoked.
+async fn refresh(request: Json<RefreshRequest>, tokens: Data<TokenIssuer>) -> Result<HttpResponse, AuthError> {
+    let claims = tokens.verify_refresh_token(&request.refresh_token, true)?;
+    let persistence_manager = &tokens.persistence_manager;
+
+    let next = match persistence_manager.rotate_refresh_token(&claims.token_id, ObjectId::new(), tokens.refresh_expiry()).await {
+        Ok(next) => next,
+        Err(PersistenceError::NotFound(_)) => return Err(AuthError::InvalidRefreshToken),
+        Err(PersistenceError::Conflict(reason)) => {
+            let revoked = persistence_manager.revoke_refresh_tokens(&claims.family_id).await?;
+            log::warn!("Rejected refresh token ({}); revoked {} remaining tokens in family {}", reason, revoked, claims.family_id);
+            return Err(AuthError::InvalidRefreshToken);
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    match persistence_manager.get_user(&next.user_id).await {
+        Ok(_) => {}
+        Err(PersistenceError::NotFound(_)) => {
+            persistence_manager.revoke_refresh_tokens(&next.family_id).await?;
+            return Err(AuthError::InvalidRefreshToken);
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    Ok(HttpResponse::Ok().json(LoginResponse {
+        token: tokens.access_token(next.user_id)?,
+        refresh_token: tokens.refresh_token(&next)?,
+    }))
+}
+
+// Logging out revokes the refresh token family, expired or not
+async fn revoke(request: Json<RefreshRequest>, tokens: Data<TokenIssuer>) -> Result<HttpResponse, AuthError> {
+    let claims = tokens.verify_refresh_token(&request.refresh_token, false)?;
+    tokens.persistence_manager.revoke_refresh_tokens(&claims.family_id).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims(username: Option<&str>, email: Option<&str>, display_name: Option<&str>) -> Claims {
+        Claims {
+            username: username.map(str::to_string),
+            email: email.map(str::to_string),
+            display_name: display_name.map(str::to_string),
+            ..Claims::new(ObjectId::new(), None)
+        }
+    }
+
+    #[test]
+    fn new_users_are_named_from_their_claims() {
+        let profile = claims(Some("  alice\u{7}"), Some("alice@example.com"), Some("Alice")).profile(None);
+        assert_eq!((profile.username.as_str(), profile.email.as_deref(), profile.display_name.as_deref()), ("alice", Some("alice@example.com"), Some("Alice")));
+        assert!(!profile.admin && !profile.guest);
+
+        assert_eq!(claims(None, Some("alice@example.com"), Some("Alice")).profile(None).username, "Alice");
+        let anonymous = claims(None, Some("alice@example.com"), Some("   "));
+        assert_eq!(anonymous.profile(None).username, anonymous.subject_id.to_hex());
+        assert_eq!(anonymous.profile(None).display_name, None);
+
+        let long = "a".repeat(MAX_USERNAME_LENGTH + 10);
+        assert_eq!(claims(Some(&long), None, None).profile(None).username.len(), MAX_USERNAME_LENGTH);
+    }
+
+    #[test]
+    fn stored_profiles_keep_what_the_claims_leave_out() {
+        let update = claims(None, Some("new@example.com"), None);
+        let stored = User {
+            _id: update.subject_id,
+            username: "alice".to_string(),
+            admin: true,
+            guest: false,
+            email: Some("old@example.com".to_string()),
+            display_name: Some("Alice".to_string()),
+        };
+
+        let profile = update.profile(Some(&stored));
+        assert_eq!(profile, User { email: Some("new@example.com".to_string()), ..stored.clone() });
+        assert_eq!(update.profile_update(Some(&stored)), Some(profile.clone()));
+        assert_eq!(update.profile_update(Some(&profile)), None);
+        assert_eq!(claims(None, None, None).profile_update(Some(&stored)), None);
+        assert!(update.profile_update(None).is_some());
+    }
 }
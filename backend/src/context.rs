@@ -1,40 +1,34 @@
-use crate::models::{SubscriptionUpdate, User};
+use crate::models::{ApiTokenScope, Retro, User};
+use crate::errors::{ApiError, ApiResult};
 use juniper::Context as JuniperContext;
-use tokio::sync::broadcast;
 use crate::database::PersistenceManager;
+use crate::events::EventBus;
+use crate::loaders::Loader;
+use mongodb::bson::oid::ObjectId;
 
 
 #[derive(Clone)]
 pub struct ContextBuilder {
     pub persistence_manager: PersistenceManager,
     pub active_user: Option<User>,
-    pub card_addition_sender: broadcast::Sender<SubscriptionUpdate>,
-    pub user_update_sender: broadcast::Sender<SubscriptionUpdate>,
-    pub step_update_sender: broadcast::Sender<SubscriptionUpdate>,
+    pub event_bus: EventBus,
+    pub cache_loads: bool,
+    // Whether users are created and updated from their token claims
+    pub provision_users: bool,
+    pub retro_scope: Option<ObjectId>,
+    pub api_scopes: Option<Vec<ApiTokenScope>>,
 }
 
 impl ContextBuilder {
-    pub fn new(persistence_manager: PersistenceManager) -> Self {
-        let (card_addition_sender, _) = broadcast::channel(100);
-        let (user_update_sender, _) = broadcast::channel(100);
-        let (step_update_sender, _) = broadcast::channel(100);
-
+    pub fn new(persistence_manager: PersistenceManager, event_bus: EventBus) -> Self {
         ContextBuilder {
             persistence_manager,
             active_user: None,
-            card_addition_sender,
-            user_update_sender,
-            step_update_sender,
-        }
-    }
-
-    pub fn from_self(&self) -> Self {
-        ContextBuilder {
-            persistence_manager: self.persistence_manager.clone(),
-            active_user: self.active_user.clone(),
-            card_addition_sender: self.card_addition_sender.clone(),
-            user_update_sender: self.user_update_sender.clone(),
-            step_update_sender: self.step_update_sender.clone(),
+            event_bus,
+            cache_loads: true,
+            provision_users: true,
+            retro_scope: None,
+            api_scopes: None,
         }
     }
 
@@ -43,13 +37,38 @@ impl ContextBuilder {
         self
     }
 
+    pub fn with_user_provisioning(mut self, provision_users: bool) -> Self {
+        self.provision_users = provision_users;
+        self
+    }
+
+    // Guest tokens only reach the retro they were issued for
+    pub fn with_retro_scope(mut self, retro_id: Option<ObjectId>) -> Self {
+        self.retro_scope = retro_id;
+        self
+    }
+
+    // Requests made with a personal API token can only run the mutations its scopes allow
+    pub fn with_api_scopes(mut self, scopes: Vec<ApiTokenScope>) -> Self {
+        self.api_scopes = Some(scopes);
+        self
+    }
+
+    // For contexts that outlive a single request, where cached loads would go stale
+    pub fn without_load_cache(mut self) -> Self {
+        self.cache_loads = false;
+        self
+    }
+
     pub fn build(self) -> Context {
         Context {
+            users: Loader::new(self.persistence_manager.clone(), self.cache_loads),
+            retros: Loader::new(self.persistence_manager.clone(), self.cache_loads),
             persistence_manager: self.persistence_manager,
             active_user: self.active_user.unwrap(),
-            card_addition_sender: self.card_addition_sender,
-            user_update_sender: self.user_update_sender,
-            step_update_sender: self.step_update_sender,
+            event_bus: self.event_bus,
+            retro_scope: self.retro_scope,
+            api_scopes: self.api_scopes,
         }
     }
 }
@@ -58,9 +77,43 @@ impl ContextBuilder {
 pub struct Context {
     pub persistence_manager: PersistenceManager,
     pub active_user: User,
-    pub card_addition_sender: broadcast::Sender<SubscriptionUpdate>,
-    pub user_update_sender: broadcast::Sender<SubscriptionUpdate>,
-    pub step_update_sender: broadcast::Sender<SubscriptionUpdate>,
+    pub event_bus: EventBus,
+    pub users: Loader<User>,
+    pub retros: Loader<Retro>,
+    pub retro_scope: Option<ObjectId>,
+    pub api_scopes: Option<Vec<ApiTokenScope>>,
+}
+
+impl Context {
+    pub fn require_retro_access(&self, retro_id: &ObjectId) -> ApiResult<()> {
+        match self.retro_scope {
+            Some(scope) if scope != *retro_id => Err(ApiError::Forbidden("Guests can only reach the retro they joined".to_string())),
+            _ => Ok(()),
+        }
+    }
+
+    // For anything that spans retros or users, which guests never see
+    pub fn require_unscoped(&self) -> ApiResult<()> {
+        match self.retro_scope {
+            Some(_) => Err(ApiError::Forbidden("Guests can only reach the retro they joined".to_string())),
+            None => Ok(()),
+        }
+    }
+
+    pub fn require_api_scope(&self, scope: ApiTokenScope) -> ApiResult<()> {
+        match &self.api_scopes {
+            Some(scopes) if !scopes.contains(&scope) => Err(ApiError::Forbidden(format!("This API token lacks the {} scope", scope.as_str()))),
+            _ => Ok(()),
+        }
+    }
+
+    // API tokens can't list, mint or revoke API tokens, so a leaked one can't outlive its revocation
+    pub fn require_login(&self) -> ApiResult<()> {
+        match self.api_scopes {
+            Some(_) => Err(ApiError::Forbidden("API tokens cannot manage API tokens".to_string())),
+            None => Ok(()),
+        }
+    }
 }
 
 impl JuniperContext for Context {}
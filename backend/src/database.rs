@@ -1,24 +1,208 @@
+use std::{collections::HashMap, path::Path, sync::{Arc, RwLock}, time::Duration};
+
 use async_trait::async_trait;
+use chrono::Utc;
+use derive_more::derive::{Display, Error};
 use futures::stream::StreamExt;
-use mongodb::{bson::{self, doc, oid::ObjectId, Document}, Collection};
+use juniper::{graphql_value, FieldError, IntoFieldError, ScalarValue};
+use mongodb::{bson::{self, doc, oid::ObjectId, Document}, error::{ErrorKind, WriteError, WriteFailure}, options::{FindOneAndUpdateOptions, IndexOptions, ReturnDocument}, Collection, IndexModel};
+
+use crate::models::{ApiToken, CacheConfig, Card, DbConfig, Identity, RefreshToken, RefreshTokenStatus, PostgresConfig, Retro, RetroPage, RetroPageQuery, RetroParticipant, RetroStep, ServiceConfig, SharedRetros, SharedUsers, SortDirection, User};
+
+mod cached;
+pub mod migrations;
+mod postgres;
+mod snapshot;
+mod sql;
+mod sqlite;
+
+pub use cached::CachedHandler;
+pub use postgres::PostgresHandler;
+pub use sqlite::SqliteHandler;
 
-use crate::models::{DbConfig, Retro, ServiceConfig, SharedRetros, SharedUsers, User};
+use snapshot::{Credentials, JournalEntry, Snapshot, SnapshotStore, Written};
+
+const DUPLICATE_KEY_CODE: i32 = 11000;
+const META_COLLECTION: &str = "meta";
+const SCHEMA_DOCUMENT_ID: &str = "schema";
+const MONGO_SCHEMA_VERSION: i32 = 1;
+const REFRESH_TOKENS_COLLECTION: &str = "refresh_tokens";
+const API_TOKENS_COLLECTION: &str = "api_tokens";
+const IDENTITIES_COLLECTION: &str = "identities";
+
+#[derive(Debug, Clone, Display, Error)]
+pub enum PersistenceError {
+    #[display("{_0} not found")]
+    NotFound(#[error(not(source))] &'static str),
+    #[display("Conflicting write: {_0}")]
+    Conflict(#[error(not(source))] String),
+    #[display("Persistence backend unavailable: {_0}")]
+    Unavailable(#[error(not(source))] String),
+    #[display("Stored data could not be read: {_0}")]
+    Corrupt(#[error(not(source))] String),
+    #[display("Access to the persistence backend was denied: {_0}")]
+    Forbidden(#[error(not(source))] String),
+    #[display("Persistence is misconfigured: {_0}")]
+    Misconfigured(#[error(not(source))] String),
+}
 
+impl PersistenceError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            PersistenceError::NotFound(_) => "NOT_FOUND",
+            PersistenceError::Conflict(_) => "CONFLICT",
+            PersistenceError::Unavailable(_) => "UNAVAILABLE",
+            PersistenceError::Corrupt(_) => "CORRUPT",
+            PersistenceError::Forbidden(_) => "FORBIDDEN",
+            PersistenceError::Misconfigured(_) => "MISCONFIGURED",
+        }
+    }
+}
+
+impl From<mongodb::error::Error> for PersistenceError {
+    fn from(error: mongodb::error::Error) -> Self {
+        match *error.kind {
+            ErrorKind::Authentication { ref message, .. } => PersistenceError::Forbidden(message.clone()),
+            ErrorKind::BsonDeserialization(ref e) => PersistenceError::Corrupt(e.to_string()),
+            ErrorKind::BsonSerialization(ref e) => PersistenceError::Corrupt(e.to_string()),
+            ErrorKind::Write(WriteFailure::WriteError(WriteError { code: DUPLICATE_KEY_CODE, ref message, .. })) => {
+                PersistenceError::Conflict(message.clone())
+            }
+            _ => PersistenceError::Unavailable(error.to_string()),
+        }
+    }
+}
+
+impl From<bson::de::Error> for PersistenceError {
+    fn from(error: bson::de::Error) -> Self {
+        PersistenceError::Corrupt(error.to_string())
+    }
+}
+
+impl From<bson::ser::Error> for PersistenceError {
+    fn from(error: bson::ser::Error) -> Self {
+        PersistenceError::Corrupt(error.to_string())
+    }
+}
+
+impl From<sqlx::Error> for PersistenceError {
+    fn from(error: sqlx::Error) -> Self {
+        match error {
+            sqlx::Error::RowNotFound => PersistenceError::NotFound("Row"),
+            sqlx::Error::Database(ref e) if e.is_unique_violation() => PersistenceError::Conflict(e.message().to_string()),
+            sqlx::Error::Decode(_) | sqlx::Error::ColumnDecode { .. } | sqlx::Error::ColumnNotFound(_) => {
+                PersistenceError::Corrupt(error.to_string())
+            }
+            _ => PersistenceError::Unavailable(error.to_string()),
+        }
+    }
+}
+
+impl From<sqlx::migrate::MigrateError> for PersistenceError {
+    fn from(error: sqlx::migrate::MigrateError) -> Self {
+        PersistenceError::Unavailable(format!("Failed to apply migrations: {}", error))
+    }
+}
+
+impl<S: ScalarValue> IntoFieldError<S> for PersistenceError {
+    fn into_field_error(self) -> FieldError<S> {
+        let code = self.code();
+        let message = match self {
+            PersistenceError::NotFound(_) | PersistenceError::Conflict(_) => self.to_string(),
+            // Backend details stay in the server log rather than reaching clients
+            _ => {
+                log::error!("{}", self);
+                "An internal error occurred. Please try again later.".to_string()
+            }
+        };
+        FieldError::new(message, graphql_value!({ "code": code }))
+    }
+}
+
+// Targeted updates return None when the lane, card or participant they address does not
+// match or there was nothing to change, and bump the retro version whenever they change something.
+// Those taking an `expected_version` only write while the retro is still at that version and
+// fail with Conflict otherwise, for callers that checked something about the retro first.
 #[async_trait]
 trait PersistenceHandler: Clone {
-    async fn get_retro(&self, retro_id: &ObjectId) -> Result<Retro, String>;
-    async fn get_retros(&self) -> Result<Vec<Retro>, String>;
-    async fn get_user(&self, user_id: &ObjectId) -> Result<User, String>;
-    async fn get_users(&self) -> Result<Vec<User>, String>;
-    async fn create_retro(&self, retro: Retro) -> Result<Retro, String>;
-    async fn update_retro(&self, retro: Retro) -> Result<Retro, String>;
+    async fn get_retro(&self, retro_id: &ObjectId) -> Result<Retro, PersistenceError>;
+    async fn get_retros(&self, include_archived: bool) -> Result<Vec<Retro>, PersistenceError>;
+    async fn get_retro_page(&self, query: &RetroPageQuery) -> Result<RetroPage, PersistenceError>;
+    async fn get_user(&self, user_id: &ObjectId) -> Result<User, PersistenceError>;
+    async fn get_users(&self) -> Result<Vec<User>, PersistenceError>;
+    // Batch lookups leave out ids that don't exist and return the rest in no particular order
+    async fn get_users_by_ids(&self, user_ids: &[ObjectId]) -> Result<Vec<User>, PersistenceError>;
+    async fn get_retros_by_ids(&self, retro_ids: &[ObjectId]) -> Result<Vec<Retro>, PersistenceError>;
+    // Registers a local user, failing with Conflict when the username is taken
+    async fn create_user(&self, user: User, password_hash: String) -> Result<User, PersistenceError>;
+    // Only users registered locally have a password hash; anyone else is NotFound
+    async fn get_credentials(&self, username: &str) -> Result<(User, String), PersistenceError>;
+    // NotFound until a user has been linked to the identity
+    async fn get_user_by_identity(&self, issuer: &str, subject: &str) -> Result<User, PersistenceError>;
+    // Creates a user linked to an external identity, failing with Conflict when the identity is
+    // already linked. Their usernames come from the provider and need not be unique.
+    async fn create_user_with_identity(&self, user: User, identity: Identity) -> Result<User, PersistenceError>;
+    // Guests have no way to sign in again, so nothing about them needs to be unique
+    async fn create_guest(&self, user: User) -> Result<User, PersistenceError>;
+    // Creates the user, or brings the stored username, email and display name up to date.
+    // Admin and guest flags are never touched.
+    async fn upsert_user(&self, user: User) -> Result<User, PersistenceError>;
+    async fn create_refresh_token(&self, token: RefreshToken) -> Result<(), PersistenceError>;
+    // Retires an active refresh token and stores its successor in the same family. NotFound for
+    // unknown tokens, Conflict for ones that were already rotated or revoked.
+    async fn rotate_refresh_token(&self, token_id: &ObjectId, next_id: ObjectId, expires_at: i64) -> Result<RefreshToken, PersistenceError>;
+    // Returns how many tokens were still usable
+    async fn revoke_refresh_tokens(&self, family_id: &ObjectId) -> Result<u64, PersistenceError>;
+    async fn create_api_token(&self, token: ApiToken) -> Result<(), PersistenceError>;
+    // NotFound for hashes of unknown or revoked tokens; expired ones are still returned
+    async fn get_api_token(&self, token_hash: &str) -> Result<ApiToken, PersistenceError>;
+    async fn get_api_tokens(&self, user_id: &ObjectId) -> Result<Vec<ApiToken>, PersistenceError>;
+    // Revokes one of the user's tokens, returning false when they have no such token
+    async fn delete_api_token(&self, user_id: &ObjectId, token_id: &ObjectId) -> Result<bool, PersistenceError>;
+    async fn create_retro(&self, retro: Retro) -> Result<Retro, PersistenceError>;
+    async fn push_card(&self, retro_id: &ObjectId, lane_id: &ObjectId, card: Card, expected_version: Option<i64>) -> Result<Option<Retro>, PersistenceError>;
+    async fn update_card_text(&self, retro_id: &ObjectId, card_id: &ObjectId, text: String, expected_version: Option<i64>) -> Result<Option<Retro>, PersistenceError>;
+    async fn add_vote(&self, retro_id: &ObjectId, card_id: &ObjectId, user_id: &ObjectId, expected_version: Option<i64>) -> Result<Option<Retro>, PersistenceError>;
+    async fn remove_vote(&self, retro_id: &ObjectId, card_id: &ObjectId, user_id: &ObjectId, expected_version: Option<i64>) -> Result<Option<Retro>, PersistenceError>;
+    async fn set_step(&self, retro_id: &ObjectId, step: RetroStep) -> Result<Option<Retro>, PersistenceError>;
+    // Returns None when the user is already a participant
+    async fn add_participant(&self, retro_id: &ObjectId, participant: RetroParticipant) -> Result<Option<Retro>, PersistenceError>;
+    async fn remove_participant(&self, retro_id: &ObjectId, user_id: &ObjectId) -> Result<Option<Retro>, PersistenceError>;
+    // Archives the retro when `archived_at` is set and restores it when it is None
+    async fn set_archived(&self, retro_id: &ObjectId, archived_at: Option<String>, expected_version: Option<i64>) -> Result<Option<Retro>, PersistenceError>;
+    // Opens the retro to guests holding `invite`, or closes it to guests when None
+    async fn set_guest_invite(&self, retro_id: &ObjectId, invite: Option<String>, expected_version: Option<i64>) -> Result<Option<Retro>, PersistenceError>;
+    async fn delete_retro(&self, retro_id: &ObjectId, expected_version: Option<i64>) -> Result<(), PersistenceError>;
+    // Replaces every card author with ANONYMOUS_USER_ID; None when there was nobody left to replace
+    async fn anonymize_card_authors(&self, retro_id: &ObjectId) -> Result<Option<Retro>, PersistenceError>;
+    // Upgrades every stored retro to the current schema, returning how many needed it
+    async fn migrate(&self) -> Result<u64, PersistenceError>;
+}
+
+// Fails with Conflict when the retro has moved on from the version a write was based on
+fn check_version(retro: &Retro, expected_version: Option<i64>) -> Result<(), PersistenceError> {
+    match expected_version {
+        Some(version) if version != retro.version => Err(version_conflict(&retro._id)),
+        _ => Ok(()),
+    }
 }
 
+fn version_conflict(retro_id: &ObjectId) -> PersistenceError {
+    PersistenceError::Conflict(format!("Retro {} changed since it was read", retro_id))
+}
 
 #[derive(Clone)]
 pub struct MemoryHandler {
     retros: SharedRetros,
     users: SharedUsers,
+    // Password hashes by user id; always locked after `users`
+    credentials: Arc<RwLock<HashMap<ObjectId, String>>>,
+    // User ids by issuer and subject; always locked after `users`
+    identities: Arc<RwLock<HashMap<(String, String), ObjectId>>>,
+    refresh_tokens: Arc<RwLock<HashMap<ObjectId, RefreshToken>>>,
+    // By hash, since every request made with one looks it up that way
+    api_tokens: Arc<RwLock<HashMap<String, ApiToken>>>,
+    snapshots: Option<Arc<SnapshotStore>>,
 }
 
 impl MemoryHandler {
@@ -26,130 +210,1060 @@ impl MemoryHandler {
         MemoryHandler {
             retros,
             users,
+            credentials: Arc::new(RwLock::new(HashMap::new())),
+            identities: Arc::new(RwLock::new(HashMap::new())),
+            refresh_tokens: Arc::new(RwLock::new(HashMap::new())),
+            api_tokens: Arc::new(RwLock::new(HashMap::new())),
+            snapshots: None,
+        }
+    }
+
+    // Queues a change for the journal. Called with the lock of whatever it changes held, so the
+    // journal keeps the same order as memory; callers wait on the result once they let go of it.
+    // A change whose write fails is still visible and reaches disk with the next snapshot.
+    fn journal(&self, entry: JournalEntry) -> Written {
+        match &self.snapshots {
+            Some(store) => store.append(entry),
+            None => Written::done(),
         }
     }
+
+    pub async fn write_snapshot(&self) -> Result<(), PersistenceError> {
+        let Some(store) = &self.snapshots else {
+            return Ok(());
+        };
+        let written = {
+            let retros = self.retros.read().unwrap();
+            let users = self.users.read().unwrap();
+            let credentials = self.credentials.read().unwrap();
+            let identities = self.identities.read().unwrap();
+            let refresh_tokens = self.refresh_tokens.read().unwrap();
+            let api_tokens = self.api_tokens.read().unwrap();
+            let now = Utc::now().timestamp();
+            let snapshot = Snapshot {
+                retros: retros.values().cloned().collect(),
+                users: users.values().cloned().collect(),
+                credentials: credentials.iter()
+                    .map(|(user_id, password_hash)| Credentials { user_id: *user_id, password_hash: password_hash.clone() })
+                    .collect(),
+                identities: identities.iter()
+                    .map(|((issuer, subject), user_id)| Identity { issuer: issuer.clone(), subject: subject.clone(), user_id: *user_id })
+                    .collect(),
+                // Expired tokens can't be used anymore, so they stop here
+                refresh_tokens: refresh_tokens.values().filter(|t| t.expires_at > now).cloned().collect(),
+                api_tokens: api_tokens.values().cloned().collect(),
+            };
+            store.write_snapshot(snapshot)
+        };
+        written.wait().await
+    }
+
+    // Applies `modify` to a copy of the stored retro under the write lock, keeping the change only if it reports one
+    async fn modify_retro<F>(&self, retro_id: &ObjectId, expected_version: Option<i64>, modify: F) -> Result<Option<Retro>, PersistenceError>
+    where
+        F: FnOnce(&mut Retro) -> bool + Send,
+    {
+        let (retro, written) = {
+            let mut retros = self.retros.write().unwrap();
+            let mut retro = retros.get(retro_id).ok_or(PersistenceError::NotFound("Retro"))?.clone();
+            check_version(&retro, expected_version)?;
+            if !modify(&mut retro) {
+                return Ok(None);
+            }
+            retro.version += 1;
+            let written = self.journal(JournalEntry::PutRetro(retro.clone()));
+            retros.insert(*retro_id, retro.clone());
+            (retro, written)
+        };
+        written.wait().await?;
+        Ok(Some(retro))
+    }
+
+    async fn modify_card<F>(&self, retro_id: &ObjectId, card_id: &ObjectId, expected_version: Option<i64>, modify: F) -> Result<Option<Retro>, PersistenceError>
+    where
+        F: FnOnce(&mut Card) -> bool + Send,
+    {
+        self.modify_retro(retro_id, expected_version, |retro| {
+            let card = retro.lanes.iter_mut()
+                .flat_map(|l| l.cards.iter_mut())
+                .find(|c| c.id == *card_id);
+            match card {
+                Some(card) => modify(card),
+                None => false,
+            }
+        }).await
+    }
 }
 
 #[async_trait]
 impl PersistenceHandler for MemoryHandler {
-    async fn get_retro(&self, retro_id: &ObjectId) -> Result<Retro, String> {
+    async fn get_retro(&self, retro_id: &ObjectId) -> Result<Retro, PersistenceError> {
         let retros = self.retros.read().unwrap();
         match retros.get(retro_id) {
             Some(retro) => Ok(retro.clone()),
-            None => Err("Retro not found".to_string()),
+            None => Err(PersistenceError::NotFound("Retro")),
         }
     }
 
-    async fn get_retros(&self) -> Result<Vec<Retro>, String> {
+    async fn get_retros(&self, include_archived: bool) -> Result<Vec<Retro>, PersistenceError> {
         let retros = self.retros.read().unwrap();
-        let retros: Vec<Retro> = retros.values().cloned().collect();
+        let retros: Vec<Retro> = retros.values()
+            .filter(|r| include_archived || r.archived_at.is_none())
+            .cloned()
+            .collect();
         Ok(retros)
     }
 
-    async fn get_user(&self, user_id: &ObjectId) -> Result<User, String> {
+    async fn get_retro_page(&self, query: &RetroPageQuery) -> Result<RetroPage, PersistenceError> {
+        let retros = self.retros.read().unwrap();
+        let mut matching: Vec<&Retro> = retros.values()
+            .filter(|r| query.filter.matches(r) && query.follows_cursor(r))
+            .collect();
+        matching.sort_by(|a, b| query.compare(a, b));
+        Ok(RetroPage {
+            has_next_page: matching.len() > query.first,
+            retros: matching.into_iter().take(query.first).cloned().collect(),
+        })
+    }
+
+    async fn get_user(&self, user_id: &ObjectId) -> Result<User, PersistenceError> {
         let users = self.users.read().unwrap();
         match users.get(user_id) {
             Some(user) => Ok(user.clone()),
-            None => Err("User not found".to_string()),
+            None => Err(PersistenceError::NotFound("User")),
         }
     }
 
-    async fn get_users(&self) -> Result<Vec<User>, String> {
+    async fn get_users(&self) -> Result<Vec<User>, PersistenceError> {
         let users = self.users.read().unwrap();
         let users: Vec<User> = users.values().cloned().collect();
         Ok(users)
     }
 
-    async fn create_retro(&self, retro: Retro) -> Result<Retro, String> {
-        let mut retros = self.retros.write().unwrap();
-        retros.insert(retro._id, retro.clone());
-        Ok(retro)
+    async fn get_users_by_ids(&self, user_ids: &[ObjectId]) -> Result<Vec<User>, PersistenceError> {
+        let users = self.users.read().unwrap();
+        Ok(user_ids.iter().filter_map(|id| users.get(id).cloned()).collect())
+    }
+
+    async fn get_retros_by_ids(&self, retro_ids: &[ObjectId]) -> Result<Vec<Retro>, PersistenceError> {
+        let retros = self.retros.read().unwrap();
+        Ok(retro_ids.iter().filter_map(|id| retros.get(id).cloned()).collect())
+    }
+
+    async fn create_user(&self, user: User, password_hash: String) -> Result<User, PersistenceError> {
+        let written = {
+            let mut users = self.users.write().unwrap();
+            let mut credentials = self.credentials.write().unwrap();
+            // Only local users need unique names, like the partial unique index in SQL
+            if users.values().any(|u| u.username == user.username && credentials.contains_key(&u._id)) {
+                return Err(PersistenceError::Conflict(format!("Username {:?} is taken", user.username)));
+            }
+            let written = self.journal(JournalEntry::PutUser(user.clone(), Credentials { user_id: user._id, password_hash: password_hash.clone() }));
+            users.insert(user._id, user.clone());
+            credentials.insert(user._id, password_hash);
+            written
+        };
+        written.wait().await?;
+        Ok(user)
+    }
+
+    async fn get_credentials(&self, username: &str) -> Result<(User, String), PersistenceError> {
+        let users = self.users.read().unwrap();
+        let credentials = self.credentials.read().unwrap();
+        // Guests and users from an identity provider may go by the same name
+        users.values()
+            .find(|u| u.username == username && credentials.contains_key(&u._id))
+            .map(|u| (u.clone(), credentials[&u._id].clone()))
+            .ok_or(PersistenceError::NotFound("User"))
+    }
+
+    async fn get_user_by_identity(&self, issuer: &str, subject: &str) -> Result<User, PersistenceError> {
+        let users = self.users.read().unwrap();
+        let identities = self.identities.read().unwrap();
+        identities.get(&(issuer.to_string(), subject.to_string()))
+            .and_then(|user_id| users.get(user_id).cloned())
+            .ok_or(PersistenceError::NotFound("User"))
+    }
+
+    async fn create_user_with_identity(&self, user: User, identity: Identity) -> Result<User, PersistenceError> {
+        let written = {
+            let mut users = self.users.write().unwrap();
+            let mut identities = self.identities.write().unwrap();
+            let key = (identity.issuer.clone(), identity.subject.clone());
+            if identities.contains_key(&key) {
+                return Err(PersistenceError::Conflict(format!("Identity {:?} from {} is already linked", identity.subject, identity.issuer)));
+            }
+            let written = self.journal(JournalEntry::PutIdentity(user.clone(), identity));
+            users.insert(user._id, user.clone());
+            identities.insert(key, user._id);
+            written
+        };
+        written.wait().await?;
+        Ok(user)
+    }
+
+    async fn create_guest(&self, user: User) -> Result<User, PersistenceError> {
+        let written = {
+            let mut users = self.users.write().unwrap();
+            let written = self.journal(JournalEntry::PutProfile(user.clone()));
+            users.insert(user._id, user.clone());
+            written
+        };
+        written.wait().await?;
+        Ok(user)
+    }
+
+    async fn upsert_user(&self, user: User) -> Result<User, PersistenceError> {
+        let (user, written) = {
+            let mut users = self.users.write().unwrap();
+            let user = match users.get(&user._id) {
+                Some(existing) => User {
+                    username: user.username,
+                    email: user.email,
+                    display_name: user.display_name,
+                    ..existing.clone()
+                },
+                None => user,
+            };
+            let written = self.journal(JournalEntry::PutProfile(user.clone()));
+            users.insert(user._id, user.clone());
+            (user, written)
+        };
+        written.wait().await?;
+        Ok(user)
+    }
+
+    async fn create_refresh_token(&self, token: RefreshToken) -> Result<(), PersistenceError> {
+        let written = {
+            let mut refresh_tokens = self.refresh_tokens.write().unwrap();
+            let written = self.journal(JournalEntry::PutRefreshTokens(vec![token.clone()]));
+            refresh_tokens.insert(token._id, token);
+            written
+        };
+        written.wait().await
+    }
+
+    async fn rotate_refresh_token(&self, token_id: &ObjectId, next_id: ObjectId, expires_at: i64) -> Result<RefreshToken, PersistenceError> {
+        let (next, written) = {
+            let mut refresh_tokens = self.refresh_tokens.write().unwrap();
+            let mut current = refresh_tokens.get(token_id).ok_or(PersistenceError::NotFound("Refresh token"))?.clone();
+            if current.status != RefreshTokenStatus::Active {
+                return Err(PersistenceError::Conflict(format!("Refresh token {} is {}", token_id, current.status.as_str())));
+            }
+            current.status = RefreshTokenStatus::Rotated;
+            let next = RefreshToken {
+                _id: next_id,
+                family_id: current.family_id,
+                user_id: current.user_id,
+                expires_at,
+                status: RefreshTokenStatus::Active,
+            };
+            let written = self.journal(JournalEntry::PutRefreshTokens(vec![current.clone(), next.clone()]));
+            refresh_tokens.insert(current._id, current);
+            refresh_tokens.insert(next._id, next.clone());
+            (next, written)
+        };
+        written.wait().await?;
+        Ok(next)
+    }
+
+    async fn revoke_refresh_tokens(&self, family_id: &ObjectId) -> Result<u64, PersistenceError> {
+        let (count, written) = {
+            let mut refresh_tokens = self.refresh_tokens.write().unwrap();
+            let revoked: Vec<RefreshToken> = refresh_tokens.values()
+                .filter(|t| t.family_id == *family_id && t.status == RefreshTokenStatus::Active)
+                .map(|t| RefreshToken { status: RefreshTokenStatus::Revoked, ..t.clone() })
+                .collect();
+            if revoked.is_empty() {
+                return Ok(0);
+            }
+            let written = self.journal(JournalEntry::PutRefreshTokens(revoked.clone()));
+            let count = revoked.len() as u64;
+            for token in revoked {
+                refresh_tokens.insert(token._id, token);
+            }
+            (count, written)
+        };
+        written.wait().await?;
+        Ok(count)
+    }
+
+    async fn create_api_token(&self, token: ApiToken) -> Result<(), PersistenceError> {
+        let written = {
+            let mut api_tokens = self.api_tokens.write().unwrap();
+            let written = self.journal(JournalEntry::PutApiToken(token.clone()));
+            api_tokens.insert(token.token_hash.clone(), token);
+            written
+        };
+        written.wait().await
     }
 
-    async fn update_retro(&self, retro: Retro) -> Result<Retro, String> {
-        let mut retros = self.retros.write().unwrap();
-        retros.insert(retro._id, retro.clone());
+    async fn get_api_token(&self, token_hash: &str) -> Result<ApiToken, PersistenceError> {
+        let api_tokens = self.api_tokens.read().unwrap();
+        api_tokens.get(token_hash).cloned().ok_or(PersistenceError::NotFound("API token"))
+    }
+
+    async fn get_api_tokens(&self, user_id: &ObjectId) -> Result<Vec<ApiToken>, PersistenceError> {
+        let api_tokens = self.api_tokens.read().unwrap();
+        let mut result: Vec<ApiToken> = api_tokens.values().filter(|t| t.user_id == *user_id).cloned().collect();
+        result.sort_by(|a, b| a.created_at.cmp(&b.created_at));
+        Ok(result)
+    }
+
+    async fn delete_api_token(&self, user_id: &ObjectId, token_id: &ObjectId) -> Result<bool, PersistenceError> {
+        let written = {
+            let mut api_tokens = self.api_tokens.write().unwrap();
+            let Some(token_hash) = api_tokens.values()
+                .find(|t| t._id == *token_id && t.user_id == *user_id)
+                .map(|t| t.token_hash.clone()) else {
+                return Ok(false);
+            };
+            let written = self.journal(JournalEntry::DeleteApiToken(*token_id));
+            api_tokens.remove(&token_hash);
+            written
+        };
+        written.wait().await?;
+        Ok(true)
+    }
+
+    async fn create_retro(&self, retro: Retro) -> Result<Retro, PersistenceError> {
+        let written = {
+            let mut retros = self.retros.write().unwrap();
+            if retros.contains_key(&retro._id) {
+                return Err(PersistenceError::Conflict(format!("Retro {} already exists", retro._id)));
+            }
+            let written = self.journal(JournalEntry::PutRetro(retro.clone()));
+            retros.insert(retro._id, retro.clone());
+            written
+        };
+        written.wait().await?;
         Ok(retro)
     }
+
+    async fn push_card(&self, retro_id: &ObjectId, lane_id: &ObjectId, card: Card, expected_version: Option<i64>) -> Result<Option<Retro>, PersistenceError> {
+        self.modify_retro(retro_id, expected_version, |retro| {
+            match retro.lanes.iter_mut().find(|l| l.id == *lane_id) {
+                Some(lane) => {
+                    lane.cards.push(card);
+                    true
+                }
+                None => false,
+            }
+        }).await
+    }
+
+    async fn update_card_text(&self, retro_id: &ObjectId, card_id: &ObjectId, text: String, expected_version: Option<i64>) -> Result<Option<Retro>, PersistenceError> {
+        self.modify_card(retro_id, card_id, expected_version, |card| {
+            card.text = text;
+            true
+        }).await
+    }
+
+    async fn add_vote(&self, retro_id: &ObjectId, card_id: &ObjectId, user_id: &ObjectId, expected_version: Option<i64>) -> Result<Option<Retro>, PersistenceError> {
+        self.modify_card(retro_id, card_id, expected_version, |card| {
+            card.votes.insert(*user_id);
+            true
+        }).await
+    }
+
+    async fn remove_vote(&self, retro_id: &ObjectId, card_id: &ObjectId, user_id: &ObjectId, expected_version: Option<i64>) -> Result<Option<Retro>, PersistenceError> {
+        self.modify_card(retro_id, card_id, expected_version, |card| {
+            card.votes.remove(user_id);
+            true
+        }).await
+    }
+
+    async fn set_step(&self, retro_id: &ObjectId, step: RetroStep) -> Result<Option<Retro>, PersistenceError> {
+        self.modify_retro(retro_id, None, |retro| {
+            if retro.step == step {
+                return false;
+            }
+            retro.step = step;
+            true
+        }).await
+    }
+
+    async fn add_participant(&self, retro_id: &ObjectId, participant: RetroParticipant) -> Result<Option<Retro>, PersistenceError> {
+        self.modify_retro(retro_id, None, |retro| {
+            if retro.participants.iter().any(|p| p.user == participant.user) {
+                return false;
+            }
+            retro.participants.push(participant);
+            true
+        }).await
+    }
+
+    async fn remove_participant(&self, retro_id: &ObjectId, user_id: &ObjectId) -> Result<Option<Retro>, PersistenceError> {
+        self.modify_retro(retro_id, None, |retro| {
+            let before = retro.participants.len();
+            retro.participants.retain(|p| p.user != *user_id);
+            retro.participants.len() != before
+        }).await
+    }
+
+    async fn set_archived(&self, retro_id: &ObjectId, archived_at: Option<String>, expected_version: Option<i64>) -> Result<Option<Retro>, PersistenceError> {
+        self.modify_retro(retro_id, expected_version, |retro| {
+            if retro.archived_at == archived_at {
+                return false;
+            }
+            retro.archived_at = archived_at;
+            true
+        }).await
+    }
+
+    async fn set_guest_invite(&self, retro_id: &ObjectId, invite: Option<String>, expected_version: Option<i64>) -> Result<Option<Retro>, PersistenceError> {
+        self.modify_retro(retro_id, expected_version, |retro| {
+            if retro.guest_invite == invite {
+                return false;
+            }
+            retro.guest_invite = invite;
+            true
+        }).await
+    }
+
+    async fn delete_retro(&self, retro_id: &ObjectId, expected_version: Option<i64>) -> Result<(), PersistenceError> {
+        let written = {
+            let mut retros = self.retros.write().unwrap();
+            let retro = retros.get(retro_id).ok_or(PersistenceError::NotFound("Retro"))?;
+            check_version(retro, expected_version)?;
+            let written = self.journal(JournalEntry::DeleteRetro(*retro_id));
+            retros.remove(retro_id);
+            written
+        };
+        written.wait().await
+    }
+
+    async fn anonymize_card_authors(&self, retro_id: &ObjectId) -> Result<Option<Retro>, PersistenceError> {
+        self.modify_retro(retro_id, None, |retro| retro.anonymize_cards()).await
+    }
+
+    // Snapshots are upgraded as they are loaded, so this rewrites every retro in the current shape
+    // and reports how many of them were stored by an older build
+    async fn migrate(&self) -> Result<u64, PersistenceError> {
+        self.write_snapshot().await?;
+        Ok(self.snapshots.as_ref().map_or(0, |store| store.upgraded_retros()))
+    }
 }
 
 #[derive(Clone)]
 pub struct MongoHandler {
-    client: mongodb::Client,
     db: mongodb::Database,
+    users: Collection<Document>,
+    user_id_field: String,
+    username_field: String,
+    admin_field: String,
+    password_field: String,
+    guest_field: String,
+    email_field: String,
+    display_name_field: String,
 }
 
 impl MongoHandler {
-    pub async fn new(db_config: &DbConfig) -> MongoHandler {
-        let client = mongodb::Client::with_options(db_config.clone().into()).unwrap();
+    pub async fn new(db_config: &DbConfig) -> Result<MongoHandler, PersistenceError> {
+        let client = mongodb::Client::with_options(db_config.client_options().await?)?;
         let db = client.database(&db_config.database);
-        MongoHandler { client, db }
+        let users = client.database(&db_config.users_database).collection(&db_config.users_collection);
+        let handler = MongoHandler {
+            db,
+            users,
+            user_id_field: db_config.user_id_field.clone(),
+            username_field: db_config.username_field.clone(),
+            admin_field: db_config.admin_field.clone(),
+            password_field: db_config.password_field.clone(),
+            guest_field: db_config.guest_field.clone(),
+            email_field: db_config.email_field.clone(),
+            display_name_field: db_config.display_name_field.clone(),
+        };
+        handler.bootstrap().await?;
+        Ok(handler)
+    }
+
+    // Safe to run on every start: index creation is a no-op when the index already exists, and
+    // the schema version is only recorded for a fresh database.
+    async fn bootstrap(&self) -> Result<(), PersistenceError> {
+        let retros: Collection<Document> = self.db.collection("retros");
+        // No team_id index: retros don't belong to teams yet, so nothing would use it
+        let indexes = [
+            ("participants_user", doc! { "participants.user": 1 }),
+            ("creator_id", doc! { "creator_id": 1 }),
+            ("created_at", doc! { "created_at": -1 }),
+            ("archived_at", doc! { "archived_at": 1 }),
+        ].into_iter().map(|(name, keys)| {
+            IndexModel::builder()
+                .keys(keys)
+                .options(IndexOptions::builder().name(name.to_string()).build())
+                .build()
+        });
+        retros.create_indexes(indexes).await?;
+
+        // `expires` only exists for the TTL index, which drops tokens once they can't be used
+        let refresh_tokens: Collection<Document> = self.db.collection(REFRESH_TOKENS_COLLECTION);
+        refresh_tokens.create_indexes([
+            IndexModel::builder()
+                .keys(doc! { "family_id": 1 })
+                .options(IndexOptions::builder().name("family_id".to_string()).build())
+                .build(),
+            IndexModel::builder()
+                .keys(doc! { "expires": 1 })
+                .options(IndexOptions::builder().name("expires".to_string()).expire_after(Duration::ZERO).build())
+                .build(),
+        ]).await?;
+
+        let api_tokens: Collection<Document> = self.db.collection(API_TOKENS_COLLECTION);
+        api_tokens.create_indexes([
+            IndexModel::builder()
+                .keys(doc! { "token_hash": 1 })
+                .options(IndexOptions::builder().name("token_hash".to_string()).unique(true).build())
+                .build(),
+            IndexModel::builder()
+                .keys(doc! { "user_id": 1 })
+                .options(IndexOptions::builder().name("user_id".to_string()).build())
+                .build(),
+        ]).await?;
+
+        let identities: Collection<Document> = self.db.collection(IDENTITIES_COLLECTION);
+        identities.create_index(
+            IndexModel::builder()
+                .keys(doc! { "issuer": 1, "subject": 1 })
+                .options(IndexOptions::builder().name("issuer_subject".to_string()).unique(true).build())
+                .build(),
+        ).await?;
+
+        let meta: Collection<Document> = self.db.collection(META_COLLECTION);
+        let schema = meta.find_one_and_update(
+                doc! { "_id": SCHEMA_DOCUMENT_ID },
+                doc! { "$setOnInsert": { "version": MONGO_SCHEMA_VERSION } },
+            )
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .await?
+            .ok_or(PersistenceError::NotFound("Schema version"))?;
+        let version = schema.get_i32("version")
+            .map_err(|e| PersistenceError::Corrupt(format!("schema version: {}", e)))?;
+        if version > MONGO_SCHEMA_VERSION {
+            return Err(PersistenceError::Misconfigured(format!(
+                "database schema version {} is newer than this build supports ({})", version, MONGO_SCHEMA_VERSION
+            )));
+        }
+        log::info!("Mongo schema is at version {}", version);
+        Ok(())
+    }
+
+    // Maps a document from the configured users collection onto a User
+    fn user_from_document(&self, doc: &Document) -> Result<User, PersistenceError> {
+        let _id = doc.get_object_id(&self.user_id_field)
+            .map_err(|e| PersistenceError::Corrupt(format!("user field {:?}: {}", self.user_id_field, e)))?;
+        let username = doc.get_str(&self.username_field)
+            .map_err(|e| PersistenceError::Corrupt(format!("user field {:?}: {}", self.username_field, e)))?;
+        // Identity stores without an admin flag simply have no admins
+        let admin = doc.get_bool(&self.admin_field).unwrap_or(false);
+        let guest = doc.get_bool(&self.guest_field).unwrap_or(false);
+        let email = doc.get_str(&self.email_field).ok().map(str::to_string);
+        let display_name = doc.get_str(&self.display_name_field).ok().map(str::to_string);
+        Ok(User { _id, username: username.to_string(), admin, guest, email, display_name })
+    }
+
+    // Upgrades an outdated retro as it is read and writes the upgraded document back. The write
+    // only lands if nothing else touched the retro in between; otherwise the next read retries.
+    async fn load_retro(&self, doc: Document) -> Result<Retro, PersistenceError> {
+        let mut upgraded = doc.clone();
+        if migrations::upgrade(&mut upgraded)? {
+            let retros: Collection<Document> = self.db.collection("retros");
+            retros.replace_one(unchanged_filter(&doc), upgraded.clone()).await?;
+        }
+        Ok(bson::from_document(upgraded)?)
+    }
+
+    // Runs a single atomic update against one retro and returns the document as it is afterwards
+    async fn update_one_retro(&self, retro_id: &ObjectId, expected_version: Option<i64>, filter: Document, update: Document, array_filters: Option<Vec<Document>>) -> Result<Option<Retro>, PersistenceError> {
+        let retros: Collection<Document> = self.db.collection("retros");
+        let mut filter = filter;
+        filter.insert("_id", retro_id);
+        if let Some(version) = expected_version {
+            filter.insert("version", version);
+        }
+        let mut update = update;
+        update.insert("$inc", doc! { "version": 1 });
+        let options = FindOneAndUpdateOptions::builder()
+            .array_filters(array_filters)
+            .return_document(ReturnDocument::After)
+            .build();
+
+        let result = retros.find_one_and_update(filter, update).with_options(options).await?;
+        match result {
+            Some(doc) => Ok(Some(self.load_retro(doc).await?)),
+            // Nothing matched: the retro is gone, has moved on, or the addressed element is gone
+            None => {
+                let retro = self.get_retro(retro_id).await?;
+                check_version(&retro, expected_version)?;
+                Ok(None)
+            }
+        }
     }
 }
 
+// Matches `text` literally inside a $regex
+fn escape_regex(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "\\.^$|?*+()[]{}".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// Matches `doc` only while its version and schema version are still the ones it was read with
+fn unchanged_filter(doc: &Document) -> Document {
+    let mut filter = doc! { "_id": doc.get("_id").cloned().unwrap_or(bson::Bson::Null) };
+    for field in ["version", "schema_version"] {
+        match doc.get(field) {
+            Some(value) => filter.insert(field, value.clone()),
+            None => filter.insert(field, doc! { "$exists": false }),
+        };
+    }
+    filter
+}
+
 #[async_trait]
 impl PersistenceHandler for MongoHandler {
-    async fn get_retro(&self, retro_id: &ObjectId) -> Result<Retro, String> {
+    async fn get_retro(&self, retro_id: &ObjectId) -> Result<Retro, PersistenceError> {
         let retros = self.db.collection("retros");
         let filter = doc! { "_id": retro_id };
-        let result = retros.find_one(filter).await.unwrap();
+        let result = retros.find_one(filter).await?;
         match result {
-            Some(doc) => {
-                let retro: Retro = bson::from_bson(bson::Bson::Document(doc)).unwrap();
-                Ok(retro)
-            }
-            None => Err("Retro not found".to_string()),
+            Some(doc) => self.load_retro(doc).await,
+            None => Err(PersistenceError::NotFound("Retro")),
         }
     }
 
-    async fn get_retros(&self) -> Result<Vec<Retro>, String> {
+    async fn get_retros(&self, include_archived: bool) -> Result<Vec<Retro>, PersistenceError> {
         let retros = self.db.collection("retros");
-        let mut cursor = retros.find(doc! {}).await.unwrap();
+        // A null match also covers documents written before archiving existed
+        let filter = if include_archived { doc! {} } else { doc! { "archived_at": null } };
+        let mut cursor = retros.find(filter).await?;
         let mut result = vec![];
         while let Some(doc) = cursor.next().await {
-            let retro: Retro = bson::from_bson(bson::Bson::Document(doc.unwrap())).unwrap();
-            result.push(retro);
+            result.push(self.load_retro(doc?).await?);
         }
         Ok(result)
     }
 
-    async fn get_user(&self, user_id: &ObjectId) -> Result<User, String> {
-        let users = self.client.database("flavrs").collection("users");
-        let filter = doc! { "_id": user_id };
-        let result = users.find_one(filter).await.unwrap();
+    async fn get_retro_page(&self, query: &RetroPageQuery) -> Result<RetroPage, PersistenceError> {
+        let filter = &query.filter;
+        let mut conditions = vec![];
+        if !filter.include_archived {
+            conditions.push(doc! { "archived_at": null });
+        }
+        if let Some(creator_id) = &filter.creator_id {
+            conditions.push(doc! { "creator_id": creator_id });
+        }
+        if let Some(participant_id) = &filter.participant_id {
+            conditions.push(doc! { "participants.user": participant_id });
+        }
+        if let Some(step) = &filter.step {
+            conditions.push(doc! { "step": step.as_str() });
+        }
+        if let Some(name) = &filter.name_contains {
+            conditions.push(doc! { "retro_name": { "$regex": escape_regex(name), "$options": "i" } });
+        }
+        if let Some(from) = &filter.created_from {
+            conditions.push(doc! { "created_at": { "$gte": from } });
+        }
+        if let Some(to) = &filter.created_to {
+            conditions.push(doc! { "created_at": { "$lte": to } });
+        }
+
+        let column = query.order_by.column();
+        let (direction, comparison) = match query.direction {
+            SortDirection::Asc => (1, "$gt"),
+            SortDirection::Desc => (-1, "$lt"),
+        };
+        if let Some(after) = &query.after {
+            conditions.push(doc! {
+                "$or": [
+                    { column: { comparison: &after.key } },
+                    { column: &after.key, "_id": { comparison: after.id } },
+                ]
+            });
+        }
+        let filter = if conditions.is_empty() { doc! {} } else { doc! { "$and": conditions } };
+
+        let retros: Collection<Document> = self.db.collection("retros");
+        let mut cursor = retros.find(filter)
+            .sort(doc! { column: direction, "_id": direction })
+            .limit(query.first as i64 + 1)
+            .await?;
+        let mut result = vec![];
+        while let Some(doc) = cursor.next().await {
+            result.push(self.load_retro(doc?).await?);
+        }
+        let has_next_page = result.len() > query.first;
+        result.truncate(query.first);
+        Ok(RetroPage { retros: result, has_next_page })
+    }
+
+    async fn get_user(&self, user_id: &ObjectId) -> Result<User, PersistenceError> {
+        let filter = doc! { &self.user_id_field: user_id };
+        let result = self.users.find_one(filter).await?;
         match result {
-            Some(doc) => {
-                let user: User = bson::from_bson(bson::Bson::Document(doc)).unwrap();
-                Ok(user)
+            Some(doc) => self.user_from_document(&doc),
+            None => Err(PersistenceError::NotFound("User")),
+        }
+    }
+
+    async fn get_users(&self) -> Result<Vec<User>, PersistenceError> {
+        let mut cursor = self.users.find(doc! {}).await?;
+        let mut result = vec![];
+        while let Some(doc) = cursor.next().await {
+            result.push(self.user_from_document(&doc?)?);
+        }
+        Ok(result)
+    }
+
+    async fn get_users_by_ids(&self, user_ids: &[ObjectId]) -> Result<Vec<User>, PersistenceError> {
+        let mut cursor = self.users.find(doc! { &self.user_id_field: { "$in": user_ids } }).await?;
+        let mut result = vec![];
+        while let Some(doc) = cursor.next().await {
+            result.push(self.user_from_document(&doc?)?);
+        }
+        Ok(result)
+    }
+
+    async fn get_retros_by_ids(&self, retro_ids: &[ObjectId]) -> Result<Vec<Retro>, PersistenceError> {
+        let retros: Collection<Document> = self.db.collection("retros");
+        let mut cursor = retros.find(doc! { "_id": { "$in": retro_ids } }).await?;
+        let mut result = vec![];
+        while let Some(doc) = cursor.next().await {
+            result.push(self.load_retro(doc?).await?);
+        }
+        Ok(result)
+    }
+
+    // The users collection may belong to another service, so uniqueness is checked here rather
+    // than by an index of our own; concurrent registrations only conflict if it has one
+    async fn create_user(&self, user: User, password_hash: String) -> Result<User, PersistenceError> {
+        let taken = doc! { &self.username_field: &user.username, &self.password_field: { "$type": "string" } };
+        if self.users.find_one(taken).await?.is_some() {
+            return Err(PersistenceError::Conflict(format!("Username {:?} is taken", user.username)));
+        }
+        let mut document = doc! {
+            &self.user_id_field: user._id,
+            &self.username_field: &user.username,
+            &self.admin_field: user.admin,
+            &self.password_field: password_hash,
+        };
+        if self.user_id_field != "_id" {
+            document.insert("_id", ObjectId::new());
+        }
+        self.users.insert_one(document).await?;
+        Ok(user)
+    }
+
+    async fn get_credentials(&self, username: &str) -> Result<(User, String), PersistenceError> {
+        let filter = doc! { &self.username_field: username, &self.password_field: { "$type": "string" } };
+        let doc = self.users.find_one(filter).await?.ok_or(PersistenceError::NotFound("User"))?;
+        let password_hash = doc.get_str(&self.password_field)
+            .map_err(|e| PersistenceError::Corrupt(format!("user field {:?}: {}", self.password_field, e)))?;
+        Ok((self.user_from_document(&doc)?, password_hash.to_string()))
+    }
+
+    async fn get_user_by_identity(&self, issuer: &str, subject: &str) -> Result<User, PersistenceError> {
+        let identities: Collection<Identity> = self.db.collection(IDENTITIES_COLLECTION);
+        let identity = identities.find_one(doc! { "issuer": issuer, "subject": subject }).await?
+            .ok_or(PersistenceError::NotFound("User"))?;
+        self.get_user(&identity.user_id).await
+    }
+
+    // The unique index on identities settles concurrent first logins; the loser's user is never written
+    async fn create_user_with_identity(&self, user: User, identity: Identity) -> Result<User, PersistenceError> {
+        let identities: Collection<Identity> = self.db.collection(IDENTITIES_COLLECTION);
+        identities.insert_one(&identity).await?;
+        let mut document = doc! {
+            &self.user_id_field: user._id,
+            &self.username_field: &user.username,
+            &self.admin_field: user.admin,
+            &self.email_field: &user.email,
+            &self.display_name_field: &user.display_name,
+        };
+        if self.user_id_field != "_id" {
+            document.insert("_id", ObjectId::new());
+        }
+        if let Err(e) = self.users.insert_one(document).await {
+            // The insert failing is what the caller needs to hear about, not the cleanup
+            if let Err(cleanup) = identities.delete_one(doc! { "issuer": &identity.issuer, "subject": &identity.subject }).await {
+                log::error!("Failed to unlink identity {} at {} after creating its user failed: {}", identity.subject, identity.issuer, cleanup);
             }
-            None => Err("User not found".to_string()),
+            return Err(e.into());
         }
+        Ok(user)
     }
 
-    async fn get_users(&self) -> Result<Vec<User>, String> {
-        let users = self.client.database("flavrs").collection("users");
-        let mut cursor = users.find(doc! {}).await.unwrap();
+    async fn create_guest(&self, user: User) -> Result<User, PersistenceError> {
+        let mut document = doc! {
+            &self.user_id_field: user._id,
+            &self.username_field: &user.username,
+            &self.admin_field: false,
+            &self.guest_field: true,
+        };
+        if self.user_id_field != "_id" {
+            document.insert("_id", ObjectId::new());
+        }
+        self.users.insert_one(document).await?;
+        Ok(user)
+    }
+
+    async fn upsert_user(&self, user: User) -> Result<User, PersistenceError> {
+        let update = doc! {
+            "$set": {
+                &self.username_field: &user.username,
+                &self.email_field: &user.email,
+                &self.display_name_field: &user.display_name,
+            },
+            "$setOnInsert": {
+                &self.admin_field: false,
+                &self.guest_field: false,
+            },
+        };
+        let options = FindOneAndUpdateOptions::builder()
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .build();
+        let result = self.users.find_one_and_update(doc! { &self.user_id_field: user._id }, update)
+            .with_options(options)
+            .await?;
+        match result {
+            Some(doc) => self.user_from_document(&doc),
+            None => Err(PersistenceError::NotFound("User")),
+        }
+    }
+
+    async fn create_refresh_token(&self, token: RefreshToken) -> Result<(), PersistenceError> {
+        let refresh_tokens: Collection<Document> = self.db.collection(REFRESH_TOKENS_COLLECTION);
+        let mut document = bson::to_document(&token)?;
+        document.insert("expires", bson::DateTime::from_millis(token.expires_at.saturating_mul(1000)));
+        refresh_tokens.insert_one(document).await?;
+        Ok(())
+    }
+
+    async fn rotate_refresh_token(&self, token_id: &ObjectId, next_id: ObjectId, expires_at: i64) -> Result<RefreshToken, PersistenceError> {
+        let refresh_tokens: Collection<Document> = self.db.collection(REFRESH_TOKENS_COLLECTION);
+        let rotated = refresh_tokens.find_one_and_update(
+                doc! { "_id": token_id, "status": RefreshTokenStatus::Active.as_str() },
+                doc! { "$set": { "status": RefreshTokenStatus::Rotated.as_str() } },
+            )
+            .await?;
+        let current: RefreshToken = match rotated {
+            Some(doc) => bson::from_document(doc)?,
+            None => {
+                let doc = refresh_tokens.find_one(doc! { "_id": token_id }).await?
+                    .ok_or(PersistenceError::NotFound("Refresh token"))?;
+                let current: RefreshToken = bson::from_document(doc)?;
+                return Err(PersistenceError::Conflict(format!("Refresh token {} is {}", token_id, current.status.as_str())));
+            }
+        };
+
+        let next = RefreshToken {
+            _id: next_id,
+            family_id: current.family_id,
+            user_id: current.user_id,
+            expires_at,
+            status: RefreshTokenStatus::Active,
+        };
+        self.create_refresh_token(next.clone()).await?;
+        Ok(next)
+    }
+
+    async fn revoke_refresh_tokens(&self, family_id: &ObjectId) -> Result<u64, PersistenceError> {
+        let refresh_tokens: Collection<Document> = self.db.collection(REFRESH_TOKENS_COLLECTION);
+        let result = refresh_tokens.update_many(
+                doc! { "family_id": family_id, "status": RefreshTokenStatus::Active.as_str() },
+                doc! { "$set": { "status": RefreshTokenStatus::Revoked.as_str() } },
+            )
+            .await?;
+        Ok(result.modified_count)
+    }
+
+    async fn create_api_token(&self, token: ApiToken) -> Result<(), PersistenceError> {
+        let api_tokens: Collection<Document> = self.db.collection(API_TOKENS_COLLECTION);
+        api_tokens.insert_one(bson::to_document(&token)?).await?;
+        Ok(())
+    }
+
+    async fn get_api_token(&self, token_hash: &str) -> Result<ApiToken, PersistenceError> {
+        let api_tokens: Collection<Document> = self.db.collection(API_TOKENS_COLLECTION);
+        let doc = api_tokens.find_one(doc! { "token_hash": token_hash }).await?
+            .ok_or(PersistenceError::NotFound("API token"))?;
+        Ok(bson::from_document(doc)?)
+    }
+
+    async fn get_api_tokens(&self, user_id: &ObjectId) -> Result<Vec<ApiToken>, PersistenceError> {
+        let api_tokens: Collection<Document> = self.db.collection(API_TOKENS_COLLECTION);
+        let mut cursor = api_tokens.find(doc! { "user_id": user_id }).sort(doc! { "created_at": 1 }).await?;
         let mut result = vec![];
         while let Some(doc) = cursor.next().await {
-            let user: User = bson::from_bson(bson::Bson::Document(doc.unwrap())).unwrap();
-            result.push(user);
+            result.push(bson::from_document(doc?)?);
         }
         Ok(result)
     }
 
-    async fn create_retro(&self, retro: Retro) -> Result<Retro, String> {
+    async fn delete_api_token(&self, user_id: &ObjectId, token_id: &ObjectId) -> Result<bool, PersistenceError> {
+        let api_tokens: Collection<Document> = self.db.collection(API_TOKENS_COLLECTION);
+        let result = api_tokens.delete_one(doc! { "_id": token_id, "user_id": user_id }).await?;
+        Ok(result.deleted_count > 0)
+    }
+
+    async fn create_retro(&self, retro: Retro) -> Result<Retro, PersistenceError> {
         let retros = self.db.collection("retros");
-        let doc = bson::to_document(&retro).unwrap();
-        retros.insert_one(doc).await.unwrap();
+        let doc = bson::to_document(&retro)?;
+        retros.insert_one(doc).await?;
         Ok(retro)
     }
 
-    async fn update_retro(&self, retro: Retro) -> Result<Retro, String> {
+    async fn push_card(&self, retro_id: &ObjectId, lane_id: &ObjectId, card: Card, expected_version: Option<i64>) -> Result<Option<Retro>, PersistenceError> {
+        let card = bson::to_bson(&card)?;
+        self.update_one_retro(
+            retro_id,
+            expected_version,
+            doc! { "lanes.id": lane_id },
+            doc! { "$push": { "lanes.$.cards": card } },
+            None,
+        ).await
+    }
+
+    async fn update_card_text(&self, retro_id: &ObjectId, card_id: &ObjectId, text: String, expected_version: Option<i64>) -> Result<Option<Retro>, PersistenceError> {
+        self.update_one_retro(
+            retro_id,
+            expected_version,
+            doc! { "lanes.cards.id": card_id },
+            doc! { "$set": { "lanes.$[].cards.$[card].text": text } },
+            Some(vec![doc! { "card.id": card_id }]),
+        ).await
+    }
+
+    async fn add_vote(&self, retro_id: &ObjectId, card_id: &ObjectId, user_id: &ObjectId, expected_version: Option<i64>) -> Result<Option<Retro>, PersistenceError> {
+        self.update_one_retro(
+            retro_id,
+            expected_version,
+            doc! { "lanes.cards.id": card_id },
+            doc! { "$addToSet": { "lanes.$[].cards.$[card].votes": user_id } },
+            Some(vec![doc! { "card.id": card_id }]),
+        ).await
+    }
+
+    async fn remove_vote(&self, retro_id: &ObjectId, card_id: &ObjectId, user_id: &ObjectId, expected_version: Option<i64>) -> Result<Option<Retro>, PersistenceError> {
+        self.update_one_retro(
+            retro_id,
+            expected_version,
+            doc! { "lanes.cards.id": card_id },
+            doc! { "$pull": { "lanes.$[].cards.$[card].votes": user_id } },
+            Some(vec![doc! { "card.id": card_id }]),
+        ).await
+    }
+
+    async fn set_step(&self, retro_id: &ObjectId, step: RetroStep) -> Result<Option<Retro>, PersistenceError> {
+        let step = bson::to_bson(&step)?;
+        self.update_one_retro(
+            retro_id,
+            None,
+            doc! { "step": { "$ne": step.clone() } },
+            doc! { "$set": { "step": step } },
+            None,
+        ).await
+    }
+
+    async fn add_participant(&self, retro_id: &ObjectId, participant: RetroParticipant) -> Result<Option<Retro>, PersistenceError> {
+        let user_id = participant.user;
+        let participant = bson::to_bson(&participant)?;
+        self.update_one_retro(
+            retro_id,
+            None,
+            doc! { "participants.user": { "$ne": user_id } },
+            doc! { "$push": { "participants": participant } },
+            None,
+        ).await
+    }
+
+    async fn remove_participant(&self, retro_id: &ObjectId, user_id: &ObjectId) -> Result<Option<Retro>, PersistenceError> {
+        self.update_one_retro(
+            retro_id,
+            None,
+            doc! { "participants.user": user_id },
+            doc! { "$pull": { "participants": { "user": user_id } } },
+            None,
+        ).await
+    }
+
+    async fn set_archived(&self, retro_id: &ObjectId, archived_at: Option<String>, expected_version: Option<i64>) -> Result<Option<Retro>, PersistenceError> {
+        self.update_one_retro(
+            retro_id,
+            expected_version,
+            doc! { "archived_at": { "$ne": archived_at.clone() } },
+            doc! { "$set": { "archived_at": archived_at } },
+            None,
+        ).await
+    }
+
+    async fn set_guest_invite(&self, retro_id: &ObjectId, invite: Option<String>, expected_version: Option<i64>) -> Result<Option<Retro>, PersistenceError> {
+        self.update_one_retro(
+            retro_id,
+            expected_version,
+            doc! { "guest_invite": { "$ne": invite.clone() } },
+            doc! { "$set": { "guest_invite": invite } },
+            None,
+        ).await
+    }
+
+    async fn delete_retro(&self, retro_id: &ObjectId, expected_version: Option<i64>) -> Result<(), PersistenceError> {
         let retros: Collection<Document> = self.db.collection("retros");
-        let filter = doc! { "_id": retro._id };
-        let doc = bson::to_document(&retro).unwrap();
-        retros.replace_one(filter, doc).await.unwrap();
-        Ok(retro)
+        let mut filter = doc! { "_id": retro_id };
+        if let Some(version) = expected_version {
+            filter.insert("version", version);
+        }
+        let result = retros.delete_one(filter).await?;
+        if result.deleted_count == 0 {
+            // Either the retro is gone, which get_retro reports, or it has moved on
+            self.get_retro(retro_id).await?;
+            return Err(version_conflict(retro_id));
+        }
+        Ok(())
+    }
+
+    // Subcards can nest arbitrarily deep, which no single update can reach, so this rewrites the
+    // whole retro and only lands if nothing else changed it since it was read.
+    async fn anonymize_card_authors(&self, retro_id: &ObjectId) -> Result<Option<Retro>, PersistenceError> {
+        let mut retro = self.get_retro(retro_id).await?;
+        if !retro.anonymize_cards() {
+            return Ok(None);
+        }
+        let read_version = retro.version;
+        retro.version += 1;
+
+        let retros: Collection<Document> = self.db.collection("retros");
+        let result = retros.replace_one(
+            doc! { "_id": retro_id, "version": read_version },
+            bson::to_document(&retro)?,
+        ).await?;
+        if result.matched_count == 0 {
+            return Err(PersistenceError::Conflict(format!("Retro {} changed while it was being anonymized", retro_id)));
+        }
+        Ok(Some(retro))
+    }
+
+    async fn migrate(&self) -> Result<u64, PersistenceError> {
+        let retros: Collection<Document> = self.db.collection("retros");
+        let mut cursor = retros.find(migrations::outdated_filter()).await?;
+        let mut migrated = 0;
+        while let Some(doc) = cursor.next().await {
+            let doc = doc?;
+            let mut upgraded = doc.clone();
+            if migrations::upgrade(&mut upgraded)? {
+                let result = retros.replace_one(unchanged_filter(&doc), upgraded).await?;
+                migrated += result.modified_count;
+            }
+        }
+        Ok(migrated)
     }
 }
 
@@ -157,6 +1271,9 @@ impl PersistenceHandler for MongoHandler {
 pub enum PersistenceManager {
     Memory(MemoryHandler),
     Mongo(MongoHandler),
+    Sqlite(SqliteHandler),
+    Postgres(PostgresHandler),
+    Cached(CachedHandler),
 }
 
 impl PersistenceManager {
@@ -165,53 +1282,426 @@ impl PersistenceManager {
         PersistenceManager::Memory(handler)
     }
 
-    pub async fn new_mongo(config: &ServiceConfig) -> PersistenceManager {
-        let db_config = config.db.clone().unwrap();
+    // Memory mode backed by a journal and periodic snapshots in `dir`, seeded from whatever was saved there
+    pub fn new_snapshotted_memory(retros: SharedRetros, users: SharedUsers, dir: &str, interval: Duration) -> Result<PersistenceManager, PersistenceError> {
+        let (store, snapshot) = SnapshotStore::open(Path::new(dir))?;
+        {
+            let mut retros = retros.write().unwrap();
+            retros.extend(snapshot.retros.into_iter().map(|r| (r._id, r)));
+            if !snapshot.users.is_empty() {
+                let mut users = users.write().unwrap();
+                *users = snapshot.users.into_iter().map(|u| (u._id, u)).collect();
+            }
+        }
+
+        let credentials = snapshot.credentials.into_iter().map(|c| (c.user_id, c.password_hash)).collect();
+        let identities = snapshot.identities.into_iter().map(|i| ((i.issuer, i.subject), i.user_id)).collect();
+        let refresh_tokens = snapshot.refresh_tokens.into_iter().map(|t| (t._id, t)).collect();
+        let api_tokens = snapshot.api_tokens.into_iter().map(|t| (t.token_hash.clone(), t)).collect();
+        let handler = MemoryHandler {
+            retros,
+            users,
+            credentials: Arc::new(RwLock::new(credentials)),
+            identities: Arc::new(RwLock::new(identities)),
+            refresh_tokens: Arc::new(RwLock::new(refresh_tokens)),
+            api_tokens: Arc::new(RwLock::new(api_tokens)),
+            snapshots: Some(Arc::new(store)),
+        };
+
+        let snapshotter = handler.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                if let Err(e) = snapshotter.write_snapshot().await {
+                    log::error!("Failed to write snapshot: {}", e);
+                }
+            }
+        });
+
+        Ok(PersistenceManager::Memory(handler))
+    }
+
+    pub async fn new_mongo(config: &ServiceConfig) -> Result<PersistenceManager, PersistenceError> {
+        let db_config = config.db.as_ref()
+            .ok_or(PersistenceError::Misconfigured("db config must be set in Mongo mode".to_string()))?;
+
+        let handler = MongoHandler::new(db_config).await?;
+        Ok(PersistenceManager::Mongo(handler))
+    }
+
+    pub async fn new_sqlite(path: &str) -> Result<PersistenceManager, PersistenceError> {
+        let handler = sqlite::connect(path).await?;
+        Ok(PersistenceManager::Sqlite(handler))
+    }
+
+    pub async fn new_postgres(config: &ServiceConfig) -> Result<PersistenceManager, PersistenceError> {
+        let postgres_config: PostgresConfig = config.postgres.clone()
+            .ok_or(PersistenceError::Misconfigured("postgres config must be set in Postgres mode".to_string()))?;
 
+        let handler = postgres::connect(&postgres_config).await?;
+        Ok(PersistenceManager::Postgres(handler))
+    }
 
-        let handler = MongoHandler::new(&db_config).await;
-        PersistenceManager::Mongo(handler)
+    // Puts a bounded retro cache in front of any other backend
+    pub fn with_cache(self, config: &CacheConfig) -> Result<PersistenceManager, PersistenceError> {
+        let handler = CachedHandler::new(self, config)?;
+        Ok(PersistenceManager::Cached(handler))
     }
 
-    pub async fn get_retro(&self, retro_id: &ObjectId) -> Result<Retro, String> {
+    pub async fn get_retro(&self, retro_id: &ObjectId) -> Result<Retro, PersistenceError> {
         match self {
             PersistenceManager::Memory(handler) => handler.get_retro(retro_id).await,
             PersistenceManager::Mongo(handler) => handler.get_retro(retro_id).await,
+            PersistenceManager::Sqlite(handler) => handler.get_retro(retro_id).await,
+            PersistenceManager::Postgres(handler) => handler.get_retro(retro_id).await,
+            PersistenceManager::Cached(handler) => handler.get_retro(retro_id).await,
+        }
+    }
+
+    pub async fn get_retros(&self, include_archived: bool) -> Result<Vec<Retro>, PersistenceError> {
+        match self {
+            PersistenceManager::Memory(handler) => handler.get_retros(include_archived).await,
+            PersistenceManager::Mongo(handler) => handler.get_retros(include_archived).await,
+            PersistenceManager::Sqlite(handler) => handler.get_retros(include_archived).await,
+            PersistenceManager::Postgres(handler) => handler.get_retros(include_archived).await,
+            PersistenceManager::Cached(handler) => handler.get_retros(include_archived).await,
+        }
+    }
+
+    pub async fn get_users_by_ids(&self, user_ids: &[ObjectId]) -> Result<Vec<User>, PersistenceError> {
+        match self {
+            PersistenceManager::Memory(handler) => handler.get_users_by_ids(user_ids).await,
+            PersistenceManager::Mongo(handler) => handler.get_users_by_ids(user_ids).await,
+            PersistenceManager::Sqlite(handler) => handler.get_users_by_ids(user_ids).await,
+            PersistenceManager::Postgres(handler) => handler.get_users_by_ids(user_ids).await,
+            PersistenceManager::Cached(handler) => handler.get_users_by_ids(user_ids).await,
+        }
+    }
+
+    pub async fn get_retros_by_ids(&self, retro_ids: &[ObjectId]) -> Result<Vec<Retro>, PersistenceError> {
+        match self {
+            PersistenceManager::Memory(handler) => handler.get_retros_by_ids(retro_ids).await,
+            PersistenceManager::Mongo(handler) => handler.get_retros_by_ids(retro_ids).await,
+            PersistenceManager::Sqlite(handler) => handler.get_retros_by_ids(retro_ids).await,
+            PersistenceManager::Postgres(handler) => handler.get_retros_by_ids(retro_ids).await,
+            PersistenceManager::Cached(handler) => handler.get_retros_by_ids(retro_ids).await,
         }
     }
 
-    pub async fn get_retros(&self) -> Result<Vec<Retro>, String> {
+    pub async fn get_retro_page(&self, query: &RetroPageQuery) -> Result<RetroPage, PersistenceError> {
         match self {
-            PersistenceManager::Memory(handler) => handler.get_retros().await,
-            PersistenceManager::Mongo(handler) => handler.get_retros().await,
+            PersistenceManager::Memory(handler) => handler.get_retro_page(query).await,
+            PersistenceManager::Mongo(handler) => handler.get_retro_page(query).await,
+            PersistenceManager::Sqlite(handler) => handler.get_retro_page(query).await,
+            PersistenceManager::Postgres(handler) => handler.get_retro_page(query).await,
+            PersistenceManager::Cached(handler) => handler.get_retro_page(query).await,
         }
     }
 
-    pub async fn get_user(&self, user_id: &ObjectId) -> Result<User, String> {
+    pub async fn get_user(&self, user_id: &ObjectId) -> Result<User, PersistenceError> {
         match self {
             PersistenceManager::Memory(handler) => handler.get_user(user_id).await,
             PersistenceManager::Mongo(handler) => handler.get_user(user_id).await,
+            PersistenceManager::Sqlite(handler) => handler.get_user(user_id).await,
+            PersistenceManager::Postgres(handler) => handler.get_user(user_id).await,
+            PersistenceManager::Cached(handler) => handler.get_user(user_id).await,
         }
     }
 
-    pub async fn get_users(&self) -> Result<Vec<User>, String> {
+    pub async fn get_users(&self) -> Result<Vec<User>, PersistenceError> {
         match self {
             PersistenceManager::Memory(handler) => handler.get_users().await,
             PersistenceManager::Mongo(handler) => handler.get_users().await,
+            PersistenceManager::Sqlite(handler) => handler.get_users().await,
+            PersistenceManager::Postgres(handler) => handler.get_users().await,
+            PersistenceManager::Cached(handler) => handler.get_users().await,
+        }
+    }
+
+    pub async fn create_user(&self, user: User, password_hash: String) -> Result<User, PersistenceError> {
+        match self {
+            PersistenceManager::Memory(handler) => handler.create_user(user, password_hash).await,
+            PersistenceManager::Mongo(handler) => handler.create_user(user, password_hash).await,
+            PersistenceManager::Sqlite(handler) => handler.create_user(user, password_hash).await,
+            PersistenceManager::Postgres(handler) => handler.create_user(user, password_hash).await,
+            PersistenceManager::Cached(handler) => handler.create_user(user, password_hash).await,
         }
     }
 
-    pub async fn create_retro(&self, retro: Retro) -> Result<Retro, String> {
+    pub async fn get_credentials(&self, username: &str) -> Result<(User, String), PersistenceError> {
+        match self {
+            PersistenceManager::Memory(handler) => handler.get_credentials(username).await,
+            PersistenceManager::Mongo(handler) => handler.get_credentials(username).await,
+            PersistenceManager::Sqlite(handler) => handler.get_credentials(username).await,
+            PersistenceManager::Postgres(handler) => handler.get_credentials(username).await,
+            PersistenceManager::Cached(handler) => handler.get_credentials(username).await,
+        }
+    }
+
+    pub async fn get_user_by_identity(&self, issuer: &str, subject: &str) -> Result<User, PersistenceError> {
+        match self {
+            PersistenceManager::Memory(handler) => handler.get_user_by_identity(issuer, subject).await,
+            PersistenceManager::Mongo(handler) => handler.get_user_by_identity(issuer, subject).await,
+            PersistenceManager::Sqlite(handler) => handler.get_user_by_identity(issuer, subject).await,
+            PersistenceManager::Postgres(handler) => handler.get_user_by_identity(issuer, subject).await,
+            PersistenceManager::Cached(handler) => handler.get_user_by_identity(issuer, subject).await,
+        }
+    }
+
+    pub async fn create_user_with_identity(&self, user: User, identity: Identity) -> Result<User, PersistenceError> {
+        match self {
+            PersistenceManager::Memory(handler) => handler.create_user_with_identity(user, identity).await,
+            PersistenceManager::Mongo(handler) => handler.create_user_with_identity(user, identity).await,
+            PersistenceManager::Sqlite(handler) => handler.create_user_with_identity(user, identity).await,
+            PersistenceManager::Postgres(handler) => handler.create_user_with_identity(user, identity).await,
+            PersistenceManager::Cached(handler) => handler.create_user_with_identity(user, identity).await,
+        }
+    }
+
+    pub async fn create_guest(&self, user: User) -> Result<User, PersistenceError> {
+        match self {
+            PersistenceManager::Memory(handler) => handler.create_guest(user).await,
+            PersistenceManager::Mongo(handler) => handler.create_guest(user).await,
+            PersistenceManager::Sqlite(handler) => handler.create_guest(user).await,
+            PersistenceManager::Postgres(handler) => handler.create_guest(user).await,
+            PersistenceManager::Cached(handler) => handler.create_guest(user).await,
+        }
+    }
+
+    pub async fn upsert_user(&self, user: User) -> Result<User, PersistenceError> {
+        match self {
+            PersistenceManager::Memory(handler) => handler.upsert_user(user).await,
+            PersistenceManager::Mongo(handler) => handler.upsert_user(user).await,
+            PersistenceManager::Sqlite(handler) => handler.upsert_user(user).await,
+            PersistenceManager::Postgres(handler) => handler.upsert_user(user).await,
+            PersistenceManager::Cached(handler) => handler.upsert_user(user).await,
+        }
+    }
+
+    pub async fn create_refresh_token(&self, token: RefreshToken) -> Result<(), PersistenceError> {
+        match self {
+            PersistenceManager::Memory(handler) => handler.create_refresh_token(token).await,
+            PersistenceManager::Mongo(handler) => handler.create_refresh_token(token).await,
+            PersistenceManager::Sqlite(handler) => handler.create_refresh_token(token).await,
+            PersistenceManager::Postgres(handler) => handler.create_refresh_token(token).await,
+            PersistenceManager::Cached(handler) => handler.create_refresh_token(token).await,
+        }
+    }
+
+    pub async fn rotate_refresh_token(&self, token_id: &ObjectId, next_id: ObjectId, expires_at: i64) -> Result<RefreshToken, PersistenceError> {
+        match self {
+            PersistenceManager::Memory(handler) => handler.rotate_refresh_token(token_id, next_id, expires_at).await,
+            PersistenceManager::Mongo(handler) => handler.rotate_refresh_token(token_id, next_id, expires_at).await,
+            PersistenceManager::Sqlite(handler) => handler.rotate_refresh_token(token_id, next_id, expires_at).await,
+            PersistenceManager::Postgres(handler) => handler.rotate_refresh_token(token_id, next_id, expires_at).await,
+            PersistenceManager::Cached(handler) => handler.rotate_refresh_token(token_id, next_id, expires_at).await,
+        }
+    }
+
+    pub async fn revoke_refresh_tokens(&self, family_id: &ObjectId) -> Result<u64, PersistenceError> {
+        match self {
+            PersistenceManager::Memory(handler) => handler.revoke_refresh_tokens(family_id).await,
+            PersistenceManager::Mongo(handler) => handler.revoke_refresh_tokens(family_id).await,
+            PersistenceManager::Sqlite(handler) => handler.revoke_refresh_tokens(family_id).await,
+            PersistenceManager::Postgres(handler) => handler.revoke_refresh_tokens(family_id).await,
+            PersistenceManager::Cached(handler) => handler.revoke_refresh_tokens(family_id).await,
+        }
+    }
+
+    pub async fn create_api_token(&self, token: ApiToken) -> Result<(), PersistenceError> {
+        match self {
+            PersistenceManager::Memory(handler) => handler.create_api_token(token).await,
+            PersistenceManager::Mongo(handler) => handler.create_api_token(token).await,
+            PersistenceManager::Sqlite(handler) => handler.create_api_token(token).await,
+            PersistenceManager::Postgres(handler) => handler.create_api_token(token).await,
+            PersistenceManager::Cached(handler) => handler.create_api_token(token).await,
+        }
+    }
+
+    pub async fn get_api_token(&self, token_hash: &str) -> Result<ApiToken, PersistenceError> {
+        match self {
+            PersistenceManager::Memory(handler) => handler.get_api_token(token_hash).await,
+            PersistenceManager::Mongo(handler) => handler.get_api_token(token_hash).await,
+            PersistenceManager::Sqlite(handler) => handler.get_api_token(token_hash).await,
+            PersistenceManager::Postgres(handler) => handler.get_api_token(token_hash).await,
+            PersistenceManager::Cached(handler) => handler.get_api_token(token_hash).await,
+        }
+    }
+
+    pub async fn get_api_tokens(&self, user_id: &ObjectId) -> Result<Vec<ApiToken>, PersistenceError> {
+        match self {
+            PersistenceManager::Memory(handler) => handler.get_api_tokens(user_id).await,
+            PersistenceManager::Mongo(handler) => handler.get_api_tokens(user_id).await,
+            PersistenceManager::Sqlite(handler) => handler.get_api_tokens(user_id).await,
+            PersistenceManager::Postgres(handler) => handler.get_api_tokens(user_id).await,
+            PersistenceManager::Cached(handler) => handler.get_api_tokens(user_id).await,
+        }
+    }
+
+    pub async fn delete_api_token(&self, user_id: &ObjectId, token_id: &ObjectId) -> Result<bool, PersistenceError> {
+        match self {
+            PersistenceManager::Memory(handler) => handler.delete_api_token(user_id, token_id).await,
+            PersistenceManager::Mongo(handler) => handler.delete_api_token(user_id, token_id).await,
+            PersistenceManager::Sqlite(handler) => handler.delete_api_token(user_id, token_id).await,
+            PersistenceManager::Postgres(handler) => handler.delete_api_token(user_id, token_id).await,
+            PersistenceManager::Cached(handler) => handler.delete_api_token(user_id, token_id).await,
+        }
+    }
+
+    pub async fn create_retro(&self, retro: Retro) -> Result<Retro, PersistenceError> {
         match self {
             PersistenceManager::Memory(handler) => handler.create_retro(retro).await,
             PersistenceManager::Mongo(handler) => handler.create_retro(retro).await,
+            PersistenceManager::Sqlite(handler) => handler.create_retro(retro).await,
+            PersistenceManager::Postgres(handler) => handler.create_retro(retro).await,
+            PersistenceManager::Cached(handler) => handler.create_retro(retro).await,
+        }
+    }
+
+    pub async fn push_card(&self, retro_id: &ObjectId, lane_id: &ObjectId, card: Card, expected_version: Option<i64>) -> Result<Option<Retro>, PersistenceError> {
+        match self {
+            PersistenceManager::Memory(handler) => handler.push_card(retro_id, lane_id, card, expected_version).await,
+            PersistenceManager::Mongo(handler) => handler.push_card(retro_id, lane_id, card, expected_version).await,
+            PersistenceManager::Sqlite(handler) => handler.push_card(retro_id, lane_id, card, expected_version).await,
+            PersistenceManager::Postgres(handler) => handler.push_card(retro_id, lane_id, card, expected_version).await,
+            PersistenceManager::Cached(handler) => handler.push_card(retro_id, lane_id, card, expected_version).await,
+        }
+    }
+
+    pub async fn update_card_text(&self, retro_id: &ObjectId, card_id: &ObjectId, text: String, expected_version: Option<i64>) -> Result<Option<Retro>, PersistenceError> {
+        match self {
+            PersistenceManager::Memory(handler) => handler.update_card_text(retro_id, card_id, text, expected_version).await,
+            PersistenceManager::Mongo(handler) => handler.update_card_text(retro_id, card_id, text, expected_version).await,
+            PersistenceManager::Sqlite(handler) => handler.update_card_text(retro_id, card_id, text, expected_version).await,
+            PersistenceManager::Postgres(handler) => handler.update_card_text(retro_id, card_id, text, expected_version).await,
+            PersistenceManager::Cached(handler) => handler.update_card_text(retro_id, card_id, text, expected_version).await,
+        }
+    }
+
+    pub async fn add_vote(&self, retro_id: &ObjectId, card_id: &ObjectId, user_id: &ObjectId, expected_version: Option<i64>) -> Result<Option<Retro>, PersistenceError> {
+        match self {
+            PersistenceManager::Memory(handler) => handler.add_vote(retro_id, card_id, user_id, expected_version).await,
+            PersistenceManager::Mongo(handler) => handler.add_vote(retro_id, card_id, user_id, expected_version).await,
+            PersistenceManager::Sqlite(handler) => handler.add_vote(retro_id, card_id, user_id, expected_version).await,
+            PersistenceManager::Postgres(handler) => handler.add_vote(retro_id, card_id, user_id, expected_version).await,
+            PersistenceManager::Cached(handler) => handler.add_vote(retro_id, card_id, user_id, expected_version).await,
+        }
+    }
+
+    pub async fn remove_vote(&self, retro_id: &ObjectId, card_id: &ObjectId, user_id: &ObjectId, expected_version: Option<i64>) -> Result<Option<Retro>, PersistenceError> {
+        match self {
+            PersistenceManager::Memory(handler) => handler.remove_vote(retro_id, card_id, user_id, expected_version).await,
+            PersistenceManager::Mongo(handler) => handler.remove_vote(retro_id, card_id, user_id, expected_version).await,
+            PersistenceManager::Sqlite(handler) => handler.remove_vote(retro_id, card_id, user_id, expected_version).await,
+            PersistenceManager::Postgres(handler) => handler.remove_vote(retro_id, card_id, user_id, expected_version).await,
+            PersistenceManager::Cached(handler) => handler.remove_vote(retro_id, card_id, user_id, expected_version).await,
+        }
+    }
+
+    pub async fn set_step(&self, retro_id: &ObjectId, step: RetroStep) -> Result<Option<Retro>, PersistenceError> {
+        match self {
+            PersistenceManager::Memory(handler) => handler.set_step(retro_id, step).await,
+            PersistenceManager::Mongo(handler) => handler.set_step(retro_id, step).await,
+            PersistenceManager::Sqlite(handler) => handler.set_step(retro_id, step).await,
+            PersistenceManager::Postgres(handler) => handler.set_step(retro_id, step).await,
+            PersistenceManager::Cached(handler) => handler.set_step(retro_id, step).await,
+        }
+    }
+
+    pub async fn add_participant(&self, retro_id: &ObjectId, participant: RetroParticipant) -> Result<Option<Retro>, PersistenceError> {
+        match self {
+            PersistenceManager::Memory(handler) => handler.add_participant(retro_id, participant).await,
+            PersistenceManager::Mongo(handler) => handler.add_participant(retro_id, participant).await,
+            PersistenceManager::Sqlite(handler) => handler.add_participant(retro_id, participant).await,
+            PersistenceManager::Postgres(handler) => handler.add_participant(retro_id, participant).await,
+            PersistenceManager::Cached(handler) => handler.add_participant(retro_id, participant).await,
+        }
+    }
+
+    pub async fn remove_participant(&self, retro_id: &ObjectId, user_id: &ObjectId) -> Result<Option<Retro>, PersistenceError> {
+        match self {
+            PersistenceManager::Memory(handler) => handler.remove_participant(retro_id, user_id).await,
+            PersistenceManager::Mongo(handler) => handler.remove_participant(retro_id, user_id).await,
+            PersistenceManager::Sqlite(handler) => handler.remove_participant(retro_id, user_id).await,
+            PersistenceManager::Postgres(handler) => handler.remove_participant(retro_id, user_id).await,
+            PersistenceManager::Cached(handler) => handler.remove_participant(retro_id, user_id).await,
+        }
+    }
+
+    pub async fn set_archived(&self, retro_id: &ObjectId, archived_at: Option<String>, expected_version: Option<i64>) -> Result<Option<Retro>, PersistenceError> {
+        match self {
+            PersistenceManager::Memory(handler) => handler.set_archived(retro_id, archived_at, expected_version).await,
+            PersistenceManager::Mongo(handler) => handler.set_archived(retro_id, archived_at, expected_version).await,
+            PersistenceManager::Sqlite(handler) => handler.set_archived(retro_id, archived_at, expected_version).await,
+            PersistenceManager::Postgres(handler) => handler.set_archived(retro_id, archived_at, expected_version).await,
+            PersistenceManager::Cached(handler) => handler.set_archived(retro_id, archived_at, expected_version).await,
+        }
+    }
+
+    pub async fn set_guest_invite(&self, retro_id: &ObjectId, invite: Option<String>, expected_version: Option<i64>) -> Result<Option<Retro>, PersistenceError> {
+        match self {
+            PersistenceManager::Memory(handler) => handler.set_guest_invite(retro_id, invite, expected_version).await,
+            PersistenceManager::Mongo(handler) => handler.set_guest_invite(retro_id, invite, expected_version).await,
+            PersistenceManager::Sqlite(handler) => handler.set_guest_invite(retro_id, invite, expected_version).await,
+            PersistenceManager::Postgres(handler) => handler.set_guest_invite(retro_id, invite, expected_version).await,
+            PersistenceManager::Cached(handler) => handler.set_guest_invite(retro_id, invite, expected_version).await,
+        }
+    }
+
+    pub async fn delete_retro(&self, retro_id: &ObjectId, expected_version: Option<i64>) -> Result<(), PersistenceError> {
+        match self {
+            PersistenceManager::Memory(handler) => handler.delete_retro(retro_id, expected_version).await,
+            PersistenceManager::Mongo(handler) => handler.delete_retro(retro_id, expected_version).await,
+            PersistenceManager::Sqlite(handler) => handler.delete_retro(retro_id, expected_version).await,
+            PersistenceManager::Postgres(handler) => handler.delete_retro(retro_id, expected_version).await,
+            PersistenceManager::Cached(handler) => handler.delete_retro(retro_id, expected_version).await,
+        }
+    }
+
+    pub async fn anonymize_card_authors(&self, retro_id: &ObjectId) -> Result<Option<Retro>, PersistenceError> {
+        match self {
+            PersistenceManager::Memory(handler) => handler.anonymize_card_authors(retro_id).await,
+            PersistenceManager::Mongo(handler) => handler.anonymize_card_authors(retro_id).await,
+            PersistenceManager::Sqlite(handler) => handler.anonymize_card_authors(retro_id).await,
+            PersistenceManager::Postgres(handler) => handler.anonymize_card_authors(retro_id).await,
+            PersistenceManager::Cached(handler) => handler.anonymize_card_authors(retro_id).await,
         }
     }
 
-    pub async fn update_retro(&self, retro: Retro) -> Result<Retro, String> {
+    pub async fn migrate(&self) -> Result<u64, PersistenceError> {
         match self {
-            PersistenceManager::Memory(handler) => handler.update_retro(retro).await,
-            PersistenceManager::Mongo(handler) => handler.update_retro(retro).await,
+            PersistenceManager::Memory(handler) => handler.migrate().await,
+            PersistenceManager::Mongo(handler) => handler.migrate().await,
+            PersistenceManager::Sqlite(handler) => handler.migrate().await,
+            PersistenceManager::Postgres(handler) => handler.migrate().await,
+            PersistenceManager::Cached(handler) => handler.migrate().await,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memory() -> MemoryHandler {
+        MemoryHandler::new(Arc::new(RwLock::new(HashMap::new())), Arc::new(RwLock::new(HashMap::new())))
+    }
+
+    #[tokio::test]
+    async fn memory_targeted_writes() {
+        sql::tests::targeted_writes(&memory()).await;
+    }
+
+    // Memory mode has to agree with the SQL backends on who may share a username
+    #[tokio::test]
+    async fn memory_users_and_credentials() {
+        sql::tests::users_and_credentials(&memory()).await;
+    }
+
+    #[tokio::test]
+    async fn memory_identity_users() {
+        sql::tests::identity_users(&memory()).await;
+    }
+}
@@ -0,0 +1,445 @@
+use std::{
+    num::NonZeroUsize,
+    sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use lru::LruCache;
+use mongodb::bson::oid::ObjectId;
+
+use crate::models::{ApiToken, CacheConfig, Card, Identity, RefreshToken, Retro, RetroPage, RetroPageQuery, RetroParticipant, RetroStep, User};
+
+use super::{PersistenceError, PersistenceHandler, PersistenceManager};
+
+struct CacheEntry {
+    retro: Retro,
+    stored_at: Instant,
+}
+
+#[derive(Default)]
+struct CacheStats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+struct RetroCache {
+    entries: Mutex<LruCache<ObjectId, CacheEntry>>,
+    ttl: Duration,
+    // Bumped whenever a retro is dropped, so a read that started before the drop can't put it back
+    generation: AtomicU64,
+    stats: CacheStats,
+}
+
+impl RetroCache {
+    fn get(&self, retro_id: &ObjectId) -> Option<Retro> {
+        let mut entries = self.entries.lock().unwrap();
+        let fresh = match entries.get(retro_id) {
+            Some(entry) if entry.stored_at.elapsed() < self.ttl => Some(entry.retro.clone()),
+            Some(_) => {
+                entries.pop(retro_id);
+                None
+            }
+            None => None,
+        };
+        let counter = if fresh.is_some() { &self.stats.hits } else { &self.stats.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        fresh
+    }
+
+    // Keeps whichever copy is newer, since a slow read can finish after a faster write
+    fn put(&self, retro: &Retro) {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.peek(&retro._id) {
+            if entry.retro.version > retro.version {
+                return;
+            }
+        }
+        let entry = CacheEntry { retro: retro.clone(), stored_at: Instant::now() };
+        if let Some((evicted, _)) = entries.push(retro._id, entry) {
+            if evicted != retro._id {
+                self.stats.evictions.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn put_read(&self, retro: &Retro, generation: u64) {
+        if self.generation.load(Ordering::Acquire) == generation {
+            self.put(retro);
+        }
+    }
+
+    fn invalidate(&self, retro_id: &ObjectId) {
+        let mut entries = self.entries.lock().unwrap();
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        entries.pop(retro_id);
+    }
+
+    fn report(&self) {
+        let hits = self.stats.hits.load(Ordering::Relaxed);
+        let misses = self.stats.misses.load(Ordering::Relaxed);
+        let evictions = self.stats.evictions.load(Ordering::Relaxed);
+        let (size, capacity) = {
+            let entries = self.entries.lock().unwrap();
+            (entries.len(), entries.cap())
+        };
+        let hit_rate = if hits + misses == 0 { 0.0 } else { hits as f64 * 100.0 / (hits + misses) as f64 };
+        log::info!(
+            "Retro cache: {} hits, {} misses ({:.1}% hit rate), {} evictions, {}/{} entries",
+            hits, misses, hit_rate, evictions, size, capacity,
+        );
+    }
+}
+
+// Keeps recently read retros in memory in front of another backend. Every write through this
+// manager (or a clone of it) refreshes or drops the affected retro; writes made elsewhere, such
+// as by another instance sharing the database, are only picked up once entries expire.
+#[derive(Clone)]
+pub struct CachedHandler {
+    inner: Box<PersistenceManager>,
+    cache: Arc<RetroCache>,
+}
+
+impl CachedHandler {
+    pub fn new(inner: PersistenceManager, config: &CacheConfig) -> Result<CachedHandler, PersistenceError> {
+        let capacity = NonZeroUsize::new(config.capacity)
+            .ok_or(PersistenceError::Misconfigured("cache capacity must be greater than zero".to_string()))?;
+        let cache = Arc::new(RetroCache {
+            entries: Mutex::new(LruCache::new(capacity)),
+            ttl: Duration::from_secs(config.ttl_secs),
+            generation: AtomicU64::new(0),
+            stats: CacheStats::default(),
+        });
+
+        let reporter = cache.clone();
+        let interval = Duration::from_secs(config.report_interval_secs);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                reporter.report();
+            }
+        });
+
+        Ok(CachedHandler { inner: Box::new(inner), cache })
+    }
+
+    // Writers hand back the stored retro; anything else means our copy may be out of date
+    fn after_write(&self, retro_id: &ObjectId, result: &Result<Option<Retro>, PersistenceError>) {
+        match result {
+            Ok(Some(retro)) => self.cache.put(retro),
+            Ok(None) => (),
+            Err(_) => self.cache.invalidate(retro_id),
+        }
+    }
+}
+
+#[async_trait]
+impl PersistenceHandler for CachedHandler {
+    async fn get_retro(&self, retro_id: &ObjectId) -> Result<Retro, PersistenceError> {
+        if let Some(retro) = self.cache.get(retro_id) {
+            return Ok(retro);
+        }
+        let generation = self.cache.generation.load(Ordering::Acquire);
+        let retro = self.inner.get_retro(retro_id).await?;
+        self.cache.put_read(&retro, generation);
+        Ok(retro)
+    }
+
+    // Listings and pages depend on every stored retro, so they always go to the backend
+    async fn get_retros(&self, include_archived: bool) -> Result<Vec<Retro>, PersistenceError> {
+        self.inner.get_retros(include_archived).await
+    }
+
+    async fn get_retro_page(&self, query: &RetroPageQuery) -> Result<RetroPage, PersistenceError> {
+        self.inner.get_retro_page(query).await
+    }
+
+    async fn get_user(&self, user_id: &ObjectId) -> Result<User, PersistenceError> {
+        self.inner.get_user(user_id).await
+    }
+
+    async fn get_users(&self) -> Result<Vec<User>, PersistenceError> {
+        self.inner.get_users().await
+    }
+
+    async fn get_users_by_ids(&self, user_ids: &[ObjectId]) -> Result<Vec<User>, PersistenceError> {
+        self.inner.get_users_by_ids(user_ids).await
+    }
+
+    async fn get_retros_by_ids(&self, retro_ids: &[ObjectId]) -> Result<Vec<Retro>, PersistenceError> {
+        let mut retros = Vec::with_capacity(retro_ids.len());
+        let mut missing = Vec::new();
+        for retro_id in retro_ids {
+            match self.cache.get(retro_id) {
+                Some(retro) => retros.push(retro),
+                None => missing.push(*retro_id),
+            }
+        }
+        if missing.is_empty() {
+            return Ok(retros);
+        }
+
+        let generation = self.cache.generation.load(Ordering::Acquire);
+        for retro in self.inner.get_retros_by_ids(&missing).await? {
+            self.cache.put_read(&retro, generation);
+            retros.push(retro);
+        }
+        Ok(retros)
+    }
+
+    async fn create_user(&self, user: User, password_hash: String) -> Result<User, PersistenceError> {
+        self.inner.create_user(user, password_hash).await
+    }
+
+    async fn get_credentials(&self, username: &str) -> Result<(User, String), PersistenceError> {
+        self.inner.get_credentials(username).await
+    }
+
+    async fn get_user_by_identity(&self, issuer: &str, subject: &str) -> Result<User, PersistenceError> {
+        self.inner.get_user_by_identity(issuer, subject).await
+    }
+
+    async fn create_user_with_identity(&self, user: User, identity: Identity) -> Result<User, PersistenceError> {
+        self.inner.create_user_with_identity(user, identity).await
+    }
+
+    async fn create_guest(&self, user: User) -> Result<User, PersistenceError> {
+        self.inner.create_guest(user).await
+    }
+
+    async fn upsert_user(&self, user: User) -> Result<User, PersistenceError> {
+        self.inner.upsert_user(user).await
+    }
+
+    async fn create_refresh_token(&self, token: RefreshToken) -> Result<(), PersistenceError> {
+        self.inner.create_refresh_token(token).await
+    }
+
+    async fn rotate_refresh_token(&self, token_id: &ObjectId, next_id: ObjectId, expires_at: i64) -> Result<RefreshToken, PersistenceError> {
+        self.inner.rotate_refresh_token(token_id, next_id, expires_at).await
+    }
+
+    async fn revoke_refresh_tokens(&self, family_id: &ObjectId) -> Result<u64, PersistenceError> {
+        self.inner.revoke_refresh_tokens(family_id).await
+    }
+
+    async fn create_api_token(&self, token: ApiToken) -> Result<(), PersistenceError> {
+        self.inner.create_api_token(token).await
+    }
+
+    async fn get_api_token(&self, token_hash: &str) -> Result<ApiToken, PersistenceError> {
+        self.inner.get_api_token(token_hash).await
+    }
+
+    async fn get_api_tokens(&self, user_id: &ObjectId) -> Result<Vec<ApiToken>, PersistenceError> {
+        self.inner.get_api_tokens(user_id).await
+    }
+
+    async fn delete_api_token(&self, user_id: &ObjectId, token_id: &ObjectId) -> Result<bool, PersistenceError> {
+        self.inner.delete_api_token(user_id, token_id).await
+    }
+
+    async fn create_retro(&self, retro: Retro) -> Result<Retro, PersistenceError> {
+        let retro = self.inner.create_retro(retro).await?;
+        self.cache.put(&retro);
+        Ok(retro)
+    }
+
+    async fn push_card(&self, retro_id: &ObjectId, lane_id: &ObjectId, card: Card, expected_version: Option<i64>) -> Result<Option<Retro>, PersistenceError> {
+        let result = self.inner.push_card(retro_id, lane_id, card, expected_version).await;
+        self.after_write(retro_id, &result);
+        result
+    }
+
+    async fn update_card_text(&self, retro_id: &ObjectId, card_id: &ObjectId, text: String, expected_version: Option<i64>) -> Result<Option<Retro>, PersistenceError> {
+        let result = self.inner.update_card_text(retro_id, card_id, text, expected_version).await;
+        self.after_write(retro_id, &result);
+        result
+    }
+
+    async fn add_vote(&self, retro_id: &ObjectId, card_id: &ObjectId, user_id: &ObjectId, expected_version: Option<i64>) -> Result<Option<Retro>, PersistenceError> {
+        let result = self.inner.add_vote(retro_id, card_id, user_id, expected_version).await;
+        self.after_write(retro_id, &result);
+        result
+    }
+
+    async fn remove_vote(&self, retro_id: &ObjectId, card_id: &ObjectId, user_id: &ObjectId, expected_version: Option<i64>) -> Result<Option<Retro>, PersistenceError> {
+        let result = self.inner.remove_vote(retro_id, card_id, user_id, expected_version).await;
+        self.after_write(retro_id, &result);
+        result
+    }
+
+    async fn set_step(&self, retro_id: &ObjectId, step: RetroStep) -> Result<Option<Retro>, PersistenceError> {
+        let result = self.inner.set_step(retro_id, step).await;
+        self.after_write(retro_id, &result);
+        result
+    }
+
+    async fn add_participant(&self, retro_id: &ObjectId, participant: RetroParticipant) -> Result<Option<Retro>, PersistenceError> {
+        let result = self.inner.add_participant(retro_id, participant).await;
+        self.after_write(retro_id, &result);
+        result
+    }
+
+    async fn remove_participant(&self, retro_id: &ObjectId, user_id: &ObjectId) -> Result<Option<Retro>, PersistenceError> {
+        let result = self.inner.remove_participant(retro_id, user_id).await;
+        self.after_write(retro_id, &result);
+        result
+    }
+
+    async fn set_archived(&self, retro_id: &ObjectId, archived_at: Option<String>, expected_version: Option<i64>) -> Result<Option<Retro>, PersistenceError> {
+        let result = self.inner.set_archived(retro_id, archived_at, expected_version).await;
+        self.after_write(retro_id, &result);
+        result
+    }
+
+    async fn set_guest_invite(&self, retro_id: &ObjectId, invite: Option<String>, expected_version: Option<i64>) -> Result<Option<Retro>, PersistenceError> {
+        let result = self.inner.set_guest_invite(retro_id, invite, expected_version).await;
+        self.after_write(retro_id, &result);
+        result
+    }
+
+    async fn delete_retro(&self, retro_id: &ObjectId, expected_version: Option<i64>) -> Result<(), PersistenceError> {
+        let result = self.inner.delete_retro(retro_id, expected_version).await;
+        self.cache.invalidate(retro_id);
+        result
+    }
+
+    async fn anonymize_card_authors(&self, retro_id: &ObjectId) -> Result<Option<Retro>, PersistenceError> {
+        let result = self.inner.anonymize_card_authors(retro_id).await;
+        self.after_write(retro_id, &result);
+        result
+    }
+
+    // Migrations rewrite stored documents behind our back, so start over afterwards
+    async fn migrate(&self) -> Result<u64, PersistenceError> {
+        let result = self.inner.migrate().await;
+        self.cache.generation.fetch_add(1, Ordering::AcqRel);
+        self.cache.entries.lock().unwrap().clear();
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::RwLock;
+
+    use crate::models::SharedRetros;
+
+    use super::super::sql::tests::{sample_retro, targeted_writes};
+    use super::*;
+
+    fn config(capacity: usize) -> CacheConfig {
+        CacheConfig { capacity, ttl_secs: 3600, report_interval_secs: 3600 }
+    }
+
+    // The cache in front of a memory backend, along with that backend's retros so tests can
+    // change them behind the cache's back
+    fn cached(config: CacheConfig) -> (CachedHandler, SharedRetros) {
+        let retros: SharedRetros = Arc::new(RwLock::new(HashMap::new()));
+        let inner = PersistenceManager::new_memory(retros.clone(), Arc::new(RwLock::new(HashMap::new())));
+        (CachedHandler::new(inner, &config).unwrap(), retros)
+    }
+
+    fn rename_behind(retros: &SharedRetros, retro_id: &ObjectId, retro_name: &str) {
+        retros.write().unwrap().get_mut(retro_id).unwrap().retro_name = retro_name.to_string();
+    }
+
+    fn stats(handler: &CachedHandler) -> (u64, u64, u64) {
+        let stats = &handler.cache.stats;
+        (stats.hits.load(Ordering::Relaxed), stats.misses.load(Ordering::Relaxed), stats.evictions.load(Ordering::Relaxed))
+    }
+
+    #[tokio::test]
+    async fn cached_targeted_writes() {
+        targeted_writes(&cached(config(16)).0).await;
+    }
+
+    #[tokio::test]
+    async fn least_recently_used_retros_are_evicted() {
+        let (handler, _) = cached(config(2));
+        assert!(CachedHandler::new(PersistenceManager::new_memory(Default::default(), Default::default()), &config(0)).is_err());
+
+        let first = handler.create_retro(sample_retro("first")).await.unwrap();
+        let second = handler.create_retro(sample_retro("second")).await.unwrap();
+        handler.get_retro(&first._id).await.unwrap();
+        let third = handler.create_retro(sample_retro("third")).await.unwrap();
+        assert_eq!(handler.cache.entries.lock().unwrap().len(), 2);
+        assert_eq!(stats(&handler), (1, 0, 1));
+
+        // The second was the least recently used, so it is the one read back from the backend
+        for retro in [&first, &third, &second] {
+            handler.get_retro(&retro._id).await.unwrap();
+        }
+        assert_eq!(stats(&handler), (3, 1, 2));
+        assert!(!handler.cache.entries.lock().unwrap().contains(&first._id));
+    }
+
+    #[tokio::test]
+    async fn expired_entries_are_read_again() {
+        let (handler, retros) = cached(CacheConfig { ttl_secs: 1, ..config(16) });
+        let retro = handler.create_retro(sample_retro("sprint")).await.unwrap();
+        rename_behind(&retros, &retro._id, "renamed elsewhere");
+        assert_eq!(handler.get_retro(&retro._id).await.unwrap().retro_name, "sprint");
+
+        handler.cache.entries.lock().unwrap().peek_mut(&retro._id).unwrap().stored_at -= handler.cache.ttl;
+        assert_eq!(handler.get_retro(&retro._id).await.unwrap().retro_name, "renamed elsewhere");
+        assert_eq!(stats(&handler), (1, 1, 0));
+        assert_eq!(handler.get_retro(&retro._id).await.unwrap().retro_name, "renamed elsewhere");
+        assert_eq!(stats(&handler), (2, 1, 0));
+    }
+
+    #[tokio::test]
+    async fn older_copies_never_replace_newer_ones() {
+        let (handler, _) = cached(config(16));
+        let retro = handler.create_retro(sample_retro("sprint")).await.unwrap();
+        let newer = Retro { version: retro.version + 1, retro_name: "newer".to_string(), ..retro.clone() };
+        handler.cache.put(&newer);
+        handler.cache.put(&retro);
+        assert_eq!(handler.get_retro(&retro._id).await.unwrap().retro_name, "newer");
+
+        // A read that started before the retro was dropped mustn't bring its copy back
+        let generation = handler.cache.generation.load(Ordering::Acquire);
+        handler.cache.invalidate(&retro._id);
+        handler.cache.put_read(&newer, generation);
+        assert!(handler.cache.get(&retro._id).is_none());
+        handler.cache.put_read(&newer, handler.cache.generation.load(Ordering::Acquire));
+        assert!(handler.cache.get(&retro._id).is_some());
+    }
+
+    #[tokio::test]
+    async fn failed_writes_drop_the_entry() {
+        let (handler, retros) = cached(config(16));
+        let retro = handler.create_retro(sample_retro("sprint")).await.unwrap();
+
+        let archived = handler.set_archived(&retro._id, Some("2024-01-01T00:00:00Z".to_string()), Some(retro.version)).await.unwrap().unwrap();
+        rename_behind(&retros, &retro._id, "renamed elsewhere");
+        assert_eq!(handler.get_retro(&retro._id).await.unwrap().archived_at, archived.archived_at);
+        assert_eq!(handler.get_retro(&retro._id).await.unwrap().retro_name, "sprint");
+
+        let stale = handler.set_archived(&retro._id, None, Some(retro.version)).await;
+        assert!(matches!(stale, Err(PersistenceError::Conflict(_))));
+        assert_eq!(handler.get_retro(&retro._id).await.unwrap().retro_name, "renamed elsewhere");
+    }
+
+    #[tokio::test]
+    async fn deletes_and_migrations_clear_entries() {
+        let (handler, retros) = cached(config(16));
+        let deleted = handler.create_retro(sample_retro("deleted")).await.unwrap();
+        let kept = handler.create_retro(sample_retro("kept")).await.unwrap();
+
+        handler.delete_retro(&deleted._id, None).await.unwrap();
+        assert!(matches!(handler.get_retro(&deleted._id).await, Err(PersistenceError::NotFound(_))));
+        assert!(handler.get_retros_by_ids(&[deleted._id]).await.unwrap().is_empty());
+
+        rename_behind(&retros, &kept._id, "renamed elsewhere");
+        assert_eq!(handler.get_retro(&kept._id).await.unwrap().retro_name, "kept");
+        handler.migrate().await.unwrap();
+        assert!(handler.cache.entries.lock().unwrap().is_empty());
+        assert_eq!(handler.get_retro(&kept._id).await.unwrap().retro_name, "renamed elsewhere");
+    }
+}
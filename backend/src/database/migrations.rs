@@ -0,0 +1,220 @@
+use mongodb::bson::{self, doc, Bson, Document};
+
+use crate::models::Retro;
+
+use super::PersistenceError;
+
+// Stored retros carry the schema version they were written with. Documents written before the
+// field existed count as version 0. Each step upgrades a document by exactly one version, so
+// a document at any older version reaches the current shape by running the steps after it.
+pub const CURRENT_SCHEMA_VERSION: i64 = 1;
+
+type Step = fn(&mut Document) -> Result<(), PersistenceError>;
+
+// STEPS[n] upgrades a document from version n to n + 1
+const STEPS: [Step; CURRENT_SCHEMA_VERSION as usize] = [
+    v0_add_version,
+];
+
+// Retros written before optimistic versioning have no `version`; start them at 0
+fn v0_add_version(doc: &mut Document) -> Result<(), PersistenceError> {
+    let version = match doc.get("version") {
+        None => 0,
+        Some(Bson::Int32(v)) => i64::from(*v),
+        Some(Bson::Int64(v)) => *v,
+        Some(other) => return Err(PersistenceError::Corrupt(format!("retro version {} is not an integer", other))),
+    };
+    doc.insert("version", version);
+    Ok(())
+}
+
+pub fn schema_version(doc: &Document) -> Result<i64, PersistenceError> {
+    match doc.get("schema_version") {
+        None => Ok(0),
+        Some(Bson::Int32(v)) => Ok(i64::from(*v)),
+        Some(Bson::Int64(v)) => Ok(*v),
+        Some(other) => Err(PersistenceError::Corrupt(format!("schema_version {} is not an integer", other))),
+    }
+}
+
+// Brings a stored retro up to the current schema, returning whether anything had to change
+pub fn upgrade(doc: &mut Document) -> Result<bool, PersistenceError> {
+    let from = schema_version(doc)?;
+    if from < 0 {
+        return Err(PersistenceError::Corrupt(format!("retro schema version {} is negative", from)));
+    }
+    if from > CURRENT_SCHEMA_VERSION {
+        return Err(PersistenceError::Corrupt(format!(
+            "retro schema version {} is newer than this build supports ({})", from, CURRENT_SCHEMA_VERSION
+        )));
+    }
+    if from == CURRENT_SCHEMA_VERSION {
+        return Ok(false);
+    }
+    for step in &STEPS[from as usize..] {
+        step(doc)?;
+    }
+    doc.insert("schema_version", CURRENT_SCHEMA_VERSION);
+    Ok(true)
+}
+
+// Matches documents that still need `upgrade`
+pub fn outdated_filter() -> Document {
+    doc! {
+        "$or": [
+            { "schema_version": { "$exists": false } },
+            { "schema_version": { "$lt": CURRENT_SCHEMA_VERSION } },
+        ]
+    }
+}
+
+pub fn retro_from_document(mut doc: Document) -> Result<Retro, PersistenceError> {
+    upgrade(&mut doc)?;
+    Ok(bson::from_document(doc)?)
+}
+
+// Snapshots and journals store retros as extended JSON, so they go through the same steps
+// Whether a retro read from JSON was stored by an older build and is upgraded as it loads
+pub fn json_is_outdated(value: &serde_json::Value) -> bool {
+    value.get("schema_version").and_then(|v| v.as_i64()).unwrap_or(0) < CURRENT_SCHEMA_VERSION
+}
+
+pub fn retro_from_json(value: serde_json::Value) -> Result<Retro, PersistenceError> {
+    match Bson::try_from(value) {
+        Ok(Bson::Document(doc)) => retro_from_document(doc),
+        Ok(other) => Err(PersistenceError::Corrupt(format!("stored retro is not a document: {}", other))),
+        Err(e) => Err(PersistenceError::Corrupt(e.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::oid::ObjectId;
+
+    use super::*;
+
+    // A retro as it was stored before versioning, with neither `version` nor `schema_version`
+    fn v0_document() -> Document {
+        let retro_id = ObjectId::new();
+        doc! {
+            "_id": retro_id,
+            "retro_name": "Sprint 1",
+            "creator_id": ObjectId::new(),
+            "step": "Writing",
+            "created_at": "2024-01-01T00:00:00+00:00",
+            "participants": [{ "user": ObjectId::new(), "retro_id": retro_id }],
+            "lanes": [{ "id": ObjectId::new(), "title": "Good", "cards": [], "priority": 1 }],
+        }
+    }
+
+    fn assert_corrupt<T: std::fmt::Debug>(result: Result<T, PersistenceError>) {
+        assert!(matches!(result, Err(PersistenceError::Corrupt(_))), "expected Corrupt, got {:?}", result);
+    }
+
+    #[test]
+    fn upgrade_brings_v0_documents_to_the_current_version() {
+        let mut doc = v0_document();
+        assert!(upgrade(&mut doc).unwrap());
+        assert_eq!(doc.get("version"), Some(&Bson::Int64(0)));
+        assert_eq!(doc.get("schema_version"), Some(&Bson::Int64(CURRENT_SCHEMA_VERSION)));
+    }
+
+    #[test]
+    fn upgrade_widens_int32_versions() {
+        let mut doc = v0_document();
+        doc.insert("version", 7i32);
+        assert!(upgrade(&mut doc).unwrap());
+        assert_eq!(doc.get("version"), Some(&Bson::Int64(7)));
+    }
+
+    #[test]
+    fn upgrade_rejects_non_integer_versions() {
+        let mut doc = v0_document();
+        doc.insert("version", "7");
+        assert_corrupt(upgrade(&mut doc));
+    }
+
+    #[test]
+    fn upgrade_leaves_current_documents_alone() {
+        let mut doc = v0_document();
+        doc.insert("version", 3i64);
+        doc.insert("schema_version", CURRENT_SCHEMA_VERSION);
+        let before = doc.clone();
+        assert!(!upgrade(&mut doc).unwrap());
+        assert_eq!(doc, before);
+    }
+
+    #[test]
+    fn upgrade_rejects_documents_from_newer_builds() {
+        let mut doc = v0_document();
+        doc.insert("schema_version", CURRENT_SCHEMA_VERSION + 1);
+        assert_corrupt(upgrade(&mut doc));
+    }
+
+    #[test]
+    fn upgrade_rejects_negative_schema_versions() {
+        let mut doc = v0_document();
+        doc.insert("schema_version", -1i64);
+        assert_corrupt(upgrade(&mut doc));
+    }
+
+    #[test]
+    fn retro_from_document_reads_v0_documents() {
+        let doc = v0_document();
+        let retro = retro_from_document(doc.clone()).unwrap();
+        assert_eq!(retro._id, doc.get_object_id("_id").unwrap());
+        assert_eq!(retro.retro_name, "Sprint 1");
+        assert_eq!(retro.version, 0);
+        assert_eq!(retro.schema_version, CURRENT_SCHEMA_VERSION);
+        assert_eq!(retro.lanes.len(), 1);
+        assert_eq!(retro.participants.len(), 1);
+    }
+
+    #[test]
+    fn retro_from_document_rejects_documents_from_newer_builds() {
+        let mut doc = v0_document();
+        doc.insert("schema_version", CURRENT_SCHEMA_VERSION + 1);
+        assert_corrupt(retro_from_document(doc));
+    }
+
+    #[test]
+    fn retro_from_json_reads_v0_extended_json() {
+        let doc = v0_document();
+        let value = Bson::Document(doc.clone()).into_relaxed_extjson();
+        let retro = retro_from_json(value).unwrap();
+        assert_eq!(retro._id, doc.get_object_id("_id").unwrap());
+        assert_eq!(retro.version, 0);
+        assert_eq!(retro.schema_version, CURRENT_SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn retro_from_json_reads_int32_versions() {
+        let mut doc = v0_document();
+        doc.insert("version", 4i32);
+        let retro = retro_from_json(Bson::Document(doc).into_relaxed_extjson()).unwrap();
+        assert_eq!(retro.version, 4);
+    }
+
+    #[test]
+    fn retro_from_json_rejects_bad_versions() {
+        let mut doc = v0_document();
+        doc.insert("version", 1.5);
+        assert_corrupt(retro_from_json(Bson::Document(doc).into_relaxed_extjson()));
+        assert_corrupt(retro_from_json(serde_json::json!(["not", "a", "retro"])));
+    }
+
+    #[test]
+    fn json_is_outdated_compares_schema_versions() {
+        let mut doc = v0_document();
+        assert!(json_is_outdated(&Bson::Document(doc.clone()).into_relaxed_extjson()));
+        doc.insert("schema_version", CURRENT_SCHEMA_VERSION);
+        assert!(!json_is_outdated(&Bson::Document(doc).into_relaxed_extjson()));
+    }
+
+    #[test]
+    fn retro_from_json_rejects_documents_from_newer_builds() {
+        let mut doc = v0_document();
+        doc.insert("schema_version", CURRENT_SCHEMA_VERSION + 1);
+        assert_corrupt(retro_from_json(Bson::Document(doc).into_relaxed_extjson()));
+    }
+}
@@ -0,0 +1,335 @@
+use std::{
+    collections::HashSet,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::mpsc,
+    thread,
+};
+
+use mongodb::bson::oid::ObjectId;
+use serde::{de, Deserialize, Deserializer, Serialize};
+use tokio::sync::oneshot;
+
+use crate::models::{ApiToken, Identity, RefreshToken, Retro, User};
+
+use super::{migrations, PersistenceError};
+
+const SNAPSHOT_FILE: &str = "snapshot.json";
+const SNAPSHOT_TMP_FILE: &str = "snapshot.json.tmp";
+const JOURNAL_FILE: &str = "journal.jsonl";
+
+// Retros are read through the document migrations so files written by older builds still load
+#[derive(Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    #[serde(deserialize_with = "deserialize_retros")]
+    pub retros: Vec<Retro>,
+    pub users: Vec<User>,
+    #[serde(default)]
+    pub credentials: Vec<Credentials>,
+    #[serde(default)]
+    pub identities: Vec<Identity>,
+    #[serde(default)]
+    pub refresh_tokens: Vec<RefreshToken>,
+    #[serde(default)]
+    pub api_tokens: Vec<ApiToken>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Credentials {
+    pub user_id: ObjectId,
+    pub password_hash: String,
+}
+
+// Entries hold the full state of what they touch, so replaying one twice is harmless
+#[derive(Serialize, Deserialize)]
+pub enum JournalEntry {
+    PutRetro(#[serde(deserialize_with = "deserialize_retro")] Retro),
+    DeleteRetro(ObjectId),
+    PutUser(User, Credentials),
+    PutIdentity(User, Identity),
+    // Users without credentials or an identity, such as guests and users created from token claims
+    PutProfile(User),
+    PutRefreshTokens(Vec<RefreshToken>),
+    PutApiToken(ApiToken),
+    DeleteApiToken(ObjectId),
+}
+
+fn deserialize_retro<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Retro, D::Error> {
+    let value = serde_json::Value::deserialize(deserializer)?;
+    migrations::retro_from_json(value).map_err(de::Error::custom)
+}
+
+fn deserialize_retros<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Retro>, D::Error> {
+    Vec::<serde_json::Value>::deserialize(deserializer)?
+        .into_iter()
+        .map(|value| migrations::retro_from_json(value).map_err(de::Error::custom))
+        .collect()
+}
+
+impl Snapshot {
+    fn apply(&mut self, entry: JournalEntry) {
+        match entry {
+            JournalEntry::PutRetro(retro) => {
+                match self.retros.iter_mut().find(|r| r._id == retro._id) {
+                    Some(existing) => *existing = retro,
+                    None => self.retros.push(retro),
+                }
+            }
+            JournalEntry::DeleteRetro(retro_id) => self.retros.retain(|r| r._id != retro_id),
+            JournalEntry::PutUser(user, credentials) => {
+                self.users.retain(|u| u._id != user._id);
+                self.users.push(user);
+                self.credentials.retain(|c| c.user_id != credentials.user_id);
+                self.credentials.push(credentials);
+            }
+            JournalEntry::PutIdentity(user, identity) => {
+                self.users.retain(|u| u._id != user._id);
+                self.users.push(user);
+                self.identities.retain(|i| i.issuer != identity.issuer || i.subject != identity.subject);
+                self.identities.push(identity);
+            }
+            JournalEntry::PutProfile(user) => {
+                self.users.retain(|u| u._id != user._id);
+                self.users.push(user);
+            }
+            JournalEntry::PutRefreshTokens(tokens) => {
+                for token in tokens {
+                    self.refresh_tokens.retain(|t| t._id != token._id);
+                    self.refresh_tokens.push(token);
+                }
+            }
+            JournalEntry::PutApiToken(token) => {
+                self.api_tokens.retain(|t| t._id != token._id);
+                self.api_tokens.push(token);
+            }
+            JournalEntry::DeleteApiToken(token_id) => self.api_tokens.retain(|t| t._id != token_id),
+        }
+    }
+}
+
+// Keeps memory mode durable across restarts: every change is appended to a journal, and a
+// periodic snapshot folds the journal into a single file so it doesn't grow forever. The files
+// are only touched from a writer thread, which handles requests in the order they were queued.
+pub struct SnapshotStore {
+    requests: mpsc::Sender<WriteRequest>,
+    // Retros that were stored by an older build when the store was opened
+    upgraded_retros: u64,
+}
+
+enum WriteRequest {
+    Append(JournalEntry, oneshot::Sender<Result<(), PersistenceError>>),
+    Snapshot(Box<Snapshot>, oneshot::Sender<Result<(), PersistenceError>>),
+}
+
+// Resolves once the journal entry or snapshot it was handed out for is on disk
+pub struct Written(Option<oneshot::Receiver<Result<(), PersistenceError>>>);
+
+impl Written {
+    // For changes that have nowhere to be written to
+    pub fn done() -> Written {
+        Written(None)
+    }
+
+    pub async fn wait(self) -> Result<(), PersistenceError> {
+        match self.0 {
+            Some(receiver) => receiver.await
+                .unwrap_or_else(|_| Err(PersistenceError::Unavailable("The snapshot writer has stopped".to_string()))),
+            None => Ok(()),
+        }
+    }
+}
+
+impl SnapshotStore {
+    // Opens the store, returning the state recovered from the last snapshot plus the journal. That
+    // state is folded into a fresh snapshot before the store accepts writes.
+    pub fn open(dir: &Path) -> Result<(SnapshotStore, Snapshot), PersistenceError> {
+        fs::create_dir_all(dir).map_err(|e| unavailable(dir, e))?;
+
+        let snapshot_path = dir.join(SNAPSHOT_FILE);
+        // Ids of the loaded retros that are still in an older shape than this build writes
+        let mut outdated = HashSet::new();
+        let mut snapshot = match File::open(&snapshot_path) {
+            Ok(file) => {
+                let corrupt = |e: serde_json::Error| PersistenceError::Corrupt(format!("{}: {}", snapshot_path.display(), e));
+                let value: serde_json::Value = serde_json::from_reader(BufReader::new(file)).map_err(corrupt)?;
+                let stale: Vec<bool> = value.get("retros").and_then(|r| r.as_array())
+                    .map(|retros| retros.iter().map(migrations::json_is_outdated).collect())
+                    .unwrap_or_default();
+                let snapshot: Snapshot = serde_json::from_value(value).map_err(corrupt)?;
+                outdated.extend(snapshot.retros.iter().zip(stale).filter(|(_, stale)| *stale).map(|(r, _)| r._id));
+                snapshot
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Snapshot::default(),
+            Err(e) => return Err(unavailable(&snapshot_path, e)),
+        };
+
+        let journal_path = dir.join(JOURNAL_FILE);
+        match File::open(&journal_path) {
+            Ok(file) => {
+                let lines: Vec<String> = BufReader::new(file).lines()
+                    .collect::<Result<_, _>>()
+                    .map_err(|e| unavailable(&journal_path, e))?;
+                let count = lines.len();
+                for (i, line) in lines.into_iter().enumerate() {
+                    let parsed = serde_json::from_str::<serde_json::Value>(&line).and_then(|value| {
+                        let stale = value.get("PutRetro").is_some_and(migrations::json_is_outdated);
+                        Ok((serde_json::from_value::<JournalEntry>(value)?, stale))
+                    });
+                    match parsed {
+                        Ok((entry, stale)) => {
+                            match &entry {
+                                JournalEntry::PutRetro(retro) if stale => { outdated.insert(retro._id); }
+                                JournalEntry::PutRetro(Retro { _id, .. }) | JournalEntry::DeleteRetro(_id) => { outdated.remove(_id); }
+                                _ => {}
+                            }
+                            snapshot.apply(entry)
+                        }
+                        // A crash mid-append can leave the last line incomplete; anything earlier is real damage
+                        Err(e) if i + 1 == count => log::warn!("Ignoring truncated journal entry in {}: {}", journal_path.display(), e),
+                        Err(e) => return Err(PersistenceError::Corrupt(format!("{} line {}: {}", journal_path.display(), i + 1, e))),
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(unavailable(&journal_path, e)),
+        }
+
+        let journal = OpenOptions::new().create(true).append(true).open(&journal_path)
+            .map_err(|e| unavailable(&journal_path, e))?;
+        let mut writer = SnapshotWriter { dir: dir.to_path_buf(), journal };
+        writer.write_snapshot(&snapshot)?;
+
+        let (requests, queue) = mpsc::channel();
+        thread::Builder::new()
+            .name("snapshot-writer".to_string())
+            .spawn(move || writer.run(queue))
+            .map_err(|e| unavailable(dir, e))?;
+        Ok((SnapshotStore { requests, upgraded_retros: outdated.len() as u64 }, snapshot))
+    }
+
+    pub fn upgraded_retros(&self) -> u64 {
+        self.upgraded_retros
+    }
+
+    // Queues an entry for the journal. Entries and snapshots land in the order they are queued.
+    pub fn append(&self, entry: JournalEntry) -> Written {
+        let (done, written) = oneshot::channel();
+        self.queue(WriteRequest::Append(entry, done), written)
+    }
+
+    // Callers must hold the retro and user locks while queueing, so no entry for a change the
+    // snapshot misses is queued before it and no entry for a change it holds is queued after it.
+    pub fn write_snapshot(&self, snapshot: Snapshot) -> Written {
+        let (done, written) = oneshot::channel();
+        self.queue(WriteRequest::Snapshot(Box::new(snapshot), done), written)
+    }
+
+    fn queue(&self, request: WriteRequest, written: oneshot::Receiver<Result<(), PersistenceError>>) -> Written {
+        // Should the writer be gone, dropping the request closes `written` and waiting reports it
+        let _ = self.requests.send(request);
+        Written(Some(written))
+    }
+}
+
+struct SnapshotWriter {
+    dir: PathBuf,
+    journal: File,
+}
+
+impl SnapshotWriter {
+    fn run(mut self, queue: mpsc::Receiver<WriteRequest>) {
+        // Failures go back to whoever queued the request, which reports them
+        for request in queue {
+            let _ = match request {
+                WriteRequest::Append(entry, done) => done.send(self.append(&entry)),
+                WriteRequest::Snapshot(snapshot, done) => done.send(self.write_snapshot(&snapshot)),
+            };
+        }
+    }
+
+    fn append(&mut self, entry: &JournalEntry) -> Result<(), PersistenceError> {
+        let mut line = serde_json::to_vec(entry).map_err(|e| PersistenceError::Corrupt(e.to_string()))?;
+        line.push(b'\n');
+
+        self.journal.write_all(&line)
+            .and_then(|_| self.journal.sync_data())
+            .map_err(|e| unavailable(&self.dir.join(JOURNAL_FILE), e))
+    }
+
+    fn write_snapshot(&mut self, snapshot: &Snapshot) -> Result<(), PersistenceError> {
+        let tmp_path = self.dir.join(SNAPSHOT_TMP_FILE);
+        let file = File::create(&tmp_path).map_err(|e| unavailable(&tmp_path, e))?;
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer(&mut writer, snapshot).map_err(|e| PersistenceError::Corrupt(e.to_string()))?;
+        writer.into_inner()
+            .map_err(|e| e.into_error())
+            .and_then(|file| file.sync_all())
+            .map_err(|e| unavailable(&tmp_path, e))?;
+
+        let snapshot_path = self.dir.join(SNAPSHOT_FILE);
+        fs::rename(&tmp_path, &snapshot_path).map_err(|e| unavailable(&snapshot_path, e))?;
+        // The rename itself is only durable once the directory is synced; until then a crash could
+        // bring back the old snapshot next to an emptied journal
+        File::open(&self.dir)
+            .and_then(|dir| dir.sync_all())
+            .map_err(|e| unavailable(&self.dir, e))?;
+
+        self.journal.set_len(0)
+            .and_then(|_| self.journal.sync_all())
+            .map_err(|e| unavailable(&self.dir.join(JOURNAL_FILE), e))
+    }
+}
+
+fn unavailable(path: &Path, error: io::Error) -> PersistenceError {
+    PersistenceError::Unavailable(format!("{}: {}", path.display(), error))
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::{doc, Bson};
+
+    use super::*;
+
+    // A retro as it was stored before versioning
+    fn v0_retro(retro_id: ObjectId) -> serde_json::Value {
+        Bson::Document(doc! {
+            "_id": retro_id,
+            "retro_name": "Sprint 1",
+            "creator_id": ObjectId::new(),
+            "step": "Writing",
+            "created_at": "2024-01-01T00:00:00+00:00",
+            "participants": [],
+            "lanes": [],
+        }).into_relaxed_extjson()
+    }
+
+    #[test]
+    fn open_counts_retros_that_are_still_outdated() {
+        let dir = std::env::temp_dir().join(format!("retro-snapshot-test-{}", ObjectId::new()));
+        fs::create_dir_all(&dir).unwrap();
+        let (deleted, rewritten, journaled) = (ObjectId::new(), ObjectId::new(), ObjectId::new());
+
+        let snapshot = serde_json::json!({ "retros": [v0_retro(deleted), v0_retro(rewritten)], "users": [] });
+        fs::write(dir.join(SNAPSHOT_FILE), snapshot.to_string()).unwrap();
+        let current = migrations::retro_from_json(v0_retro(rewritten)).unwrap();
+        let journal = [
+            serde_json::json!({ "DeleteRetro": deleted }),
+            serde_json::to_value(JournalEntry::PutRetro(current)).unwrap(),
+            serde_json::json!({ "PutRetro": v0_retro(journaled) }),
+        ];
+        let lines: Vec<String> = journal.iter().map(|entry| entry.to_string()).collect();
+        fs::write(dir.join(JOURNAL_FILE), lines.join("\n")).unwrap();
+
+        let (store, snapshot) = SnapshotStore::open(&dir).unwrap();
+        assert_eq!(snapshot.retros.len(), 2);
+        assert_eq!(store.upgraded_retros(), 1);
+
+        // The upgraded state was written back on open
+        drop(store);
+        let (store, _) = SnapshotStore::open(&dir).unwrap();
+        assert_eq!(store.upgraded_retros(), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
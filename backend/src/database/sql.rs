@@ -0,0 +1,1115 @@
+use std::{collections::{HashMap, HashSet}, str::FromStr};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use mongodb::bson::oid::ObjectId;
+
+use sqlx::{migrate::{Migrate, Migrator}, ColumnIndex, Database, Decode, Encode, Executor, FromRow, IntoArguments, Pool, Transaction, Type};
+
+use crate::models::{ApiToken, ApiTokenScope, Card, Identity, Lane, RefreshToken, RefreshTokenStatus, Retro, RetroPage, RetroPageQuery, RetroParticipant, RetroStep, SortDirection, User, ANONYMOUS_USER_ID};
+
+use super::{migrations, PersistenceError, PersistenceHandler};
+
+// Row shapes, assembly and the handler shared by the normalized SQL backends
+
+// id, retro_name, creator_id, step, created_at, version, archived_at, guest_invite
+pub type RetroRow = (String, String, String, String, String, i64, Option<String>, Option<String>);
+// id, retro_id, title, priority
+pub type LaneRow = (String, String, String, i64);
+// id, retro_id, lane_id, parent_card_id, creator_id, text
+pub type CardRow = (String, String, String, Option<String>, String, String);
+// id, username, admin, guest, email, display_name
+pub type UserRow = (String, String, bool, bool, Option<String>, Option<String>);
+// card_id, user_id
+pub type VoteRow = (String, String);
+// retro_id, user_id
+pub type ParticipantRow = (String, String);
+// id, family_id, user_id, expires_at, status
+pub type RefreshTokenRow = (String, String, String, i64, String);
+// id, user_id, name, token_hash, scopes, created_at, expires_at
+pub type ApiTokenRow = (String, String, String, String, String, String, Option<String>);
+
+pub fn parse_oid(value: &str) -> Result<ObjectId, PersistenceError> {
+    ObjectId::from_str(value).map_err(|_| PersistenceError::Corrupt(format!("Invalid object id {:?}", value)))
+}
+
+pub fn build_user((id, username, admin, guest, email, display_name): UserRow) -> Result<User, PersistenceError> {
+    Ok(User { _id: parse_oid(&id)?, username, admin, guest, email, display_name })
+}
+
+pub fn api_token_scopes(scopes: &[ApiTokenScope]) -> String {
+    scopes.iter().map(ApiTokenScope::as_str).collect::<Vec<_>>().join(",")
+}
+
+pub fn build_api_token((id, user_id, name, token_hash, scopes, created_at, expires_at): ApiTokenRow) -> Result<ApiToken, PersistenceError> {
+    let scopes = scopes.split(',')
+        .filter(|scope| !scope.is_empty())
+        .map(|scope| ApiTokenScope::from_str(scope).map_err(PersistenceError::Corrupt))
+        .collect::<Result<_, _>>()?;
+    Ok(ApiToken {
+        _id: parse_oid(&id)?,
+        user_id: parse_oid(&user_id)?,
+        name,
+        token_hash,
+        scopes,
+        created_at,
+        expires_at,
+    })
+}
+
+pub fn build_refresh_token((id, family_id, user_id, expires_at, status): RefreshTokenRow) -> Result<RefreshToken, PersistenceError> {
+    Ok(RefreshToken {
+        _id: parse_oid(&id)?,
+        family_id: parse_oid(&family_id)?,
+        user_id: parse_oid(&user_id)?,
+        expires_at,
+        status: RefreshTokenStatus::from_str(&status).map_err(PersistenceError::Corrupt)?,
+    })
+}
+
+fn build_card(row: &CardRow, children: &HashMap<String, Vec<&CardRow>>, votes: &HashMap<String, HashSet<ObjectId>>) -> Result<Card, PersistenceError> {
+    let (id, retro_id, _, _, creator_id, text) = row;
+    let subcards = children.get(id)
+        .map(|rows| rows.iter().map(|child| build_card(child, children, votes)).collect())
+        .unwrap_or(Ok(vec![]))?;
+
+    Ok(Card {
+        id: parse_oid(id)?,
+        creator_id: parse_oid(creator_id)?,
+        retro_id: parse_oid(retro_id)?,
+        text: text.clone(),
+        subcards,
+        votes: votes.get(id).cloned().unwrap_or_default(),
+    })
+}
+
+pub fn assemble_retros(retros: Vec<RetroRow>, lanes: Vec<LaneRow>, cards: Vec<CardRow>, votes: Vec<VoteRow>, participants: Vec<ParticipantRow>) -> Result<Vec<Retro>, PersistenceError> {
+    let mut votes_by_card: HashMap<String, HashSet<ObjectId>> = HashMap::new();
+    for (card_id, user_id) in votes {
+        votes_by_card.entry(card_id).or_default().insert(parse_oid(&user_id)?);
+    }
+
+    let mut cards_by_lane: HashMap<String, Vec<&CardRow>> = HashMap::new();
+    let mut cards_by_parent: HashMap<String, Vec<&CardRow>> = HashMap::new();
+    for card in &cards {
+        match &card.3 {
+            Some(parent_id) => cards_by_parent.entry(parent_id.clone()).or_default().push(card),
+            None => cards_by_lane.entry(card.2.clone()).or_default().push(card),
+        }
+    }
+
+    let mut lanes_by_retro: HashMap<String, Vec<Lane>> = HashMap::new();
+    for (id, retro_id, title, priority) in lanes {
+        let cards = cards_by_lane.get(&id)
+            .map(|rows| rows.iter().map(|row| build_card(row, &cards_by_parent, &votes_by_card)).collect())
+            .unwrap_or(Ok(vec![]))?;
+        lanes_by_retro.entry(retro_id).or_default().push(Lane {
+            id: parse_oid(&id)?,
+            title,
+            cards,
+            priority: priority as i32,
+        });
+    }
+
+    let mut participants_by_retro: HashMap<String, Vec<RetroParticipant>> = HashMap::new();
+    for (retro_id, user_id) in participants {
+        let participant = RetroParticipant {
+            user: parse_oid(&user_id)?,
+            retro_id: parse_oid(&retro_id)?,
+        };
+        participants_by_retro.entry(retro_id).or_default().push(participant);
+    }
+
+    retros.into_iter().map(|(id, retro_name, creator_id, step, created_at, version, archived_at, guest_invite)| {
+        Ok(Retro {
+            _id: parse_oid(&id)?,
+            retro_name,
+            creator_id: parse_oid(&creator_id)?,
+            step: RetroStep::from_str(&step).map_err(PersistenceError::Corrupt)?,
+            created_at,
+            participants: participants_by_retro.remove(&id).unwrap_or_default(),
+            lanes: lanes_by_retro.remove(&id).unwrap_or_default(),
+            version,
+            archived_at,
+            schema_version: migrations::CURRENT_SCHEMA_VERSION,
+            guest_invite,
+        })
+    }).collect()
+}
+
+// Flattens a card tree parents-first so every row can reference an already inserted parent
+pub fn flatten_cards<'a>(cards: &'a [Card], parent_id: Option<ObjectId>, rows: &mut Vec<(&'a Card, Option<ObjectId>, i64)>) {
+    for (position, card) in cards.iter().enumerate() {
+        rows.push((card, parent_id, position as i64));
+        flatten_cards(&card.subcards, Some(card.id), rows);
+    }
+}
+
+// Builds the id query for one page of retros, fetching one extra row to tell whether another
+// page follows. `find_function` is the backend's substring position function.
+fn retro_page_query(query: &RetroPageQuery, find_function: &str) -> DynamicQuery {
+    let filter = &query.filter;
+    let mut builder = DynamicQuery::new("SELECT id FROM retros WHERE 1 = 1");
+    if !filter.include_archived {
+        builder.push(" AND archived_at IS NULL");
+    }
+    if let Some(creator_id) = &filter.creator_id {
+        builder.push(" AND creator_id = ").push_bind(creator_id.to_hex());
+    }
+    if let Some(participant_id) = &filter.participant_id {
+        builder.push(" AND EXISTS (SELECT 1 FROM participants p WHERE p.retro_id = retros.id AND p.user_id = ")
+            .push_bind(participant_id.to_hex())
+            .push(")");
+    }
+    if let Some(step) = &filter.step {
+        builder.push(" AND step = ").push_bind(step.as_str().to_string());
+    }
+    if let Some(name) = &filter.name_contains {
+        builder.push(format!(" AND {}(lower(retro_name), lower(", find_function))
+            .push_bind(name.to_string())
+            .push(")) > 0");
+    }
+    if let Some(from) = &filter.created_from {
+        builder.push(" AND created_at >= ").push_bind(from.to_string());
+    }
+    if let Some(to) = &filter.created_to {
+        builder.push(" AND created_at <= ").push_bind(to.to_string());
+    }
+
+    let column = query.order_by.column();
+    let (direction, comparison) = match query.direction {
+        SortDirection::Asc => ("ASC", ">"),
+        SortDirection::Desc => ("DESC", "<"),
+    };
+    if let Some(after) = &query.after {
+        builder.push(format!(" AND ({} {} ", column, comparison)).push_bind(after.key.clone())
+            .push(format!(" OR ({} = ", column)).push_bind(after.key.clone())
+            .push(format!(" AND id {} ", comparison)).push_bind(after.id.to_hex())
+            .push("))");
+    }
+    builder.push(format!(" ORDER BY {} {}, id {} LIMIT {}", column, direction, direction, query.first + 1));
+    builder
+}
+
+// SQL put together at runtime along with the values for its `$n` placeholders. Unlike sqlx's
+// QueryBuilder it owns its values, which is what lets the generic handler run it.
+pub struct DynamicQuery {
+    sql: String,
+    binds: Vec<String>,
+}
+
+impl DynamicQuery {
+    pub fn new(sql: &str) -> Self {
+        DynamicQuery { sql: sql.to_string(), binds: vec![] }
+    }
+
+    pub fn push(&mut self, sql: impl AsRef<str>) -> &mut Self {
+        self.sql.push_str(sql.as_ref());
+        self
+    }
+
+    pub fn push_bind(&mut self, value: String) -> &mut Self {
+        self.binds.push(value);
+        self.sql.push_str(&format!("${}", self.binds.len()));
+        self
+    }
+
+    // Appends `(<value>, ...)` for an IN list
+    pub fn push_binds(&mut self, values: impl IntoIterator<Item = String>) -> &mut Self {
+        self.push("(");
+        for (i, value) in values.into_iter().enumerate() {
+            if i > 0 {
+                self.push(", ");
+            }
+            self.push_bind(value);
+        }
+        self.push(")")
+    }
+}
+
+// Puts loaded retros back into the order their ids were selected in
+pub fn order_by_ids(retros: Vec<Retro>, retro_ids: &[String]) -> Vec<Retro> {
+    let mut by_id: HashMap<String, Retro> = retros.into_iter().map(|r| (r._id.to_hex(), r)).collect();
+    retro_ids.iter().filter_map(|id| by_id.remove(id)).collect()
+}
+
+// What sets the SQL backends apart beyond how they connect; the queries are shared and use `$n`
+// placeholders, which SQLite accepts as well
+pub trait Dialect: Database {
+    // Returns the 1-based position of a substring, like instr in SQLite or strpos in Postgres
+    const FIND_FUNCTION: &'static str;
+    // Run first in a transaction that reads several tables, when the default isolation level would
+    // let it see writes committed in between
+    const READ_SNAPSHOT: Option<&'static str>;
+
+    fn rows_affected(result: &Self::QueryResult) -> u64;
+}
+
+pub struct SqlHandler<DB: Database> {
+    pool: Pool<DB>,
+    // Retros that were already stored when the schema migrations brought them up to date
+    upgraded_retros: u64,
+}
+
+impl<DB: Database> Clone for SqlHandler<DB> {
+    fn clone(&self) -> Self {
+        SqlHandler { pool: self.pool.clone(), upgraded_retros: self.upgraded_retros }
+    }
+}
+
+impl<DB> SqlHandler<DB>
+where
+    DB: Dialect,
+    for<'c> &'c mut DB::Connection: Executor<'c, Database = DB>,
+    for<'q> DB::Arguments<'q>: IntoArguments<'q, DB>,
+    for<'q> String: Encode<'q, DB>,
+    for<'q> &'q str: Encode<'q, DB>,
+    for<'q> i64: Encode<'q, DB>,
+    for<'q> bool: Encode<'q, DB>,
+    for<'q> Option<String>: Encode<'q, DB>,
+    for<'r> String: Decode<'r, DB>,
+    for<'r> i64: Decode<'r, DB>,
+    for<'r> bool: Decode<'r, DB>,
+    String: Type<DB>,
+    str: Type<DB>,
+    i64: Type<DB>,
+    bool: Type<DB>,
+    usize: ColumnIndex<DB::Row>,
+{
+    // Brings the schema up to date before handing out the handler. Retros live in plain tables
+    // here, so every stored retro counts as upgraded when a migration was still pending.
+    pub async fn open(pool: Pool<DB>, migrator: &Migrator) -> Result<Self, PersistenceError>
+    where
+        DB::Connection: Migrate,
+    {
+        let mut connection = pool.acquire().await?;
+        connection.ensure_migrations_table().await?;
+        let applied = connection.list_applied_migrations().await?;
+        let pending = migrator.iter()
+            .filter(|m| m.migration_type.is_up_migration())
+            .any(|m| !applied.iter().any(|a| a.version == m.version));
+        // Without any applied migration there is no retros table to count yet
+        let upgraded_retros = if pending && !applied.is_empty() {
+            let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM retros").fetch_one(&mut *connection).await?;
+            count as u64
+        } else {
+            0
+        };
+        drop(connection);
+
+        migrator.run(&pool).await?;
+        Ok(SqlHandler { pool, upgraded_retros })
+    }
+
+    async fn fetch_all<O>(connection: &mut DB::Connection, query: &DynamicQuery) -> Result<Vec<O>, PersistenceError>
+    where
+        O: for<'r> FromRow<'r, DB::Row> + Send + Unpin,
+    {
+        let mut bound = sqlx::query_as(&query.sql);
+        for value in &query.binds {
+            bound = bound.bind(value);
+        }
+        Ok(bound.fetch_all(connection).await?)
+    }
+
+    // Runs `<select> [WHERE <column> IN (...)] ORDER BY <order_by>`, unfiltered when `retro_ids` is None
+    async fn fetch_for_retros<O>(connection: &mut DB::Connection, select: &str, column: &str, retro_ids: Option<&[String]>, order_by: &str) -> Result<Vec<O>, PersistenceError>
+    where
+        O: for<'r> FromRow<'r, DB::Row> + Send + Unpin,
+    {
+        let mut query = DynamicQuery::new(select);
+        if let Some(retro_ids) = retro_ids {
+            query.push(format!(" WHERE {} IN ", column)).push_binds(retro_ids.iter().cloned());
+        }
+        query.push(" ORDER BY ").push(order_by);
+        Self::fetch_all(connection, &query).await
+    }
+
+    async fn load_retros(&self, retro_ids: Option<&[String]>) -> Result<Vec<Retro>, PersistenceError> {
+        if retro_ids.is_some_and(|ids| ids.is_empty()) {
+            return Ok(vec![]);
+        }
+
+        let mut tx = self.pool.begin().await?;
+        if let Some(statement) = DB::READ_SNAPSHOT {
+            sqlx::query(statement).execute(&mut *tx).await?;
+        }
+        let retros: Vec<RetroRow> = Self::fetch_for_retros(&mut tx, "SELECT id, retro_name, creator_id, step, created_at, version, archived_at, guest_invite FROM retros", "id", retro_ids, "created_at").await?;
+        let lanes: Vec<LaneRow> = Self::fetch_for_retros(&mut tx, "SELECT id, retro_id, title, priority FROM lanes", "retro_id", retro_ids, "priority").await?;
+        let cards: Vec<CardRow> = Self::fetch_for_retros(&mut tx, "SELECT id, retro_id, lane_id, parent_card_id, creator_id, text FROM cards", "retro_id", retro_ids, "position").await?;
+        let votes: Vec<VoteRow> = Self::fetch_for_retros(&mut tx, "SELECT card_id, user_id FROM votes", "retro_id", retro_ids, "user_id").await?;
+        let participants: Vec<ParticipantRow> = Self::fetch_for_retros(&mut tx, "SELECT retro_id, user_id FROM participants", "retro_id", retro_ids, "position").await?;
+        tx.commit().await?;
+
+        assemble_retros(retros, lanes, cards, votes, participants)
+    }
+
+    // Opens a transaction that has already bumped the retro version, failing if the retro is
+    // missing or no longer at `expected_version`
+    async fn begin_update(&self, retro_id: &ObjectId, expected_version: Option<i64>) -> Result<Transaction<'_, DB>, PersistenceError> {
+        let mut tx = self.pool.begin().await?;
+        let sql = match expected_version {
+            Some(_) => "UPDATE retros SET version = version + 1 WHERE id = $1 AND version = $2",
+            None => "UPDATE retros SET version = version + 1 WHERE id = $1",
+        };
+        let mut query = sqlx::query(sql).bind(retro_id.to_hex());
+        if let Some(version) = expected_version {
+            query = query.bind(version);
+        }
+        let result = query.execute(&mut *tx).await?;
+        if DB::rows_affected(&result) == 0 {
+            return Err(Self::missing_or_moved_on(&mut tx, retro_id).await);
+        }
+        Ok(tx)
+    }
+
+    // Explains why a write addressed to a retro at some version matched nothing
+    async fn missing_or_moved_on(connection: &mut DB::Connection, retro_id: &ObjectId) -> PersistenceError {
+        let result: Result<(i64,), _> = sqlx::query_as("SELECT COUNT(*) FROM retros WHERE id = $1")
+            .bind(retro_id.to_hex())
+            .fetch_one(connection)
+            .await;
+        match result {
+            Ok((0,)) => PersistenceError::NotFound("Retro"),
+            Ok(_) => PersistenceError::Conflict(format!("Retro {} changed since it was read", retro_id)),
+            Err(e) => e.into(),
+        }
+    }
+
+    async fn finish_update(&self, tx: Transaction<'_, DB>, retro_id: &ObjectId, changed: bool) -> Result<Option<Retro>, PersistenceError> {
+        if !changed {
+            tx.rollback().await?;
+            return Ok(None);
+        }
+        tx.commit().await?;
+        self.get_retro(retro_id).await.map(Some)
+    }
+
+    // Like the other backends, only top-level cards are written to; subcards are out of reach
+    async fn card_in_retro(tx: &mut Transaction<'_, DB>, retro_id: &ObjectId, card_id: &ObjectId) -> Result<bool, PersistenceError> {
+        let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM cards WHERE id = $1 AND retro_id = $2 AND parent_card_id IS NULL")
+            .bind(card_id.to_hex())
+            .bind(retro_id.to_hex())
+            .fetch_one(&mut **tx)
+            .await?;
+        Ok(count > 0)
+    }
+
+    async fn insert_refresh_token(tx: &mut Transaction<'_, DB>, token: &RefreshToken) -> Result<(), PersistenceError> {
+        sqlx::query("INSERT INTO refresh_tokens (id, family_id, user_id, expires_at, status) VALUES ($1, $2, $3, $4, $5)")
+            .bind(token._id.to_hex())
+            .bind(token.family_id.to_hex())
+            .bind(token.user_id.to_hex())
+            .bind(token.expires_at)
+            .bind(token.status.as_str())
+            .execute(&mut **tx)
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<DB> PersistenceHandler for SqlHandler<DB>
+where
+    DB: Dialect,
+    for<'c> &'c mut DB::Connection: Executor<'c, Database = DB>,
+    for<'q> DB::Arguments<'q>: IntoArguments<'q, DB>,
+    for<'q> String: Encode<'q, DB>,
+    for<'q> &'q str: Encode<'q, DB>,
+    for<'q> i64: Encode<'q, DB>,
+    for<'q> bool: Encode<'q, DB>,
+    for<'q> Option<String>: Encode<'q, DB>,
+    for<'r> String: Decode<'r, DB>,
+    for<'r> i64: Decode<'r, DB>,
+    for<'r> bool: Decode<'r, DB>,
+    String: Type<DB>,
+    str: Type<DB>,
+    i64: Type<DB>,
+    bool: Type<DB>,
+    usize: ColumnIndex<DB::Row>,
+{
+    async fn get_retro(&self, retro_id: &ObjectId) -> Result<Retro, PersistenceError> {
+        let retro_ids = [retro_id.to_hex()];
+        self.load_retros(Some(&retro_ids)).await?
+            .pop()
+            .ok_or(PersistenceError::NotFound("Retro"))
+    }
+
+    async fn get_retros(&self, include_archived: bool) -> Result<Vec<Retro>, PersistenceError> {
+        if include_archived {
+            return self.load_retros(None).await;
+        }
+        let retro_ids: Vec<String> = sqlx::query_scalar("SELECT id FROM retros WHERE archived_at IS NULL")
+            .fetch_all(&self.pool)
+            .await?;
+        self.load_retros(Some(&retro_ids)).await
+    }
+
+    async fn get_retro_page(&self, query: &RetroPageQuery) -> Result<RetroPage, PersistenceError> {
+        let rows: Vec<(String,)> = Self::fetch_all(&mut *self.pool.acquire().await?, &retro_page_query(query, DB::FIND_FUNCTION)).await?;
+        let mut retro_ids: Vec<String> = rows.into_iter().map(|(id,)| id).collect();
+        let has_next_page = retro_ids.len() > query.first;
+        retro_ids.truncate(query.first);
+
+        let retros = self.load_retros(Some(&retro_ids)).await?;
+        Ok(RetroPage {
+            retros: order_by_ids(retros, &retro_ids),
+            has_next_page,
+        })
+    }
+
+    async fn get_user(&self, user_id: &ObjectId) -> Result<User, PersistenceError> {
+        let row: Option<UserRow> = sqlx::query_as("SELECT id, username, admin, guest, email, display_name FROM users WHERE id = $1")
+            .bind(user_id.to_hex())
+            .fetch_optional(&self.pool)
+            .await?;
+        match row {
+            Some(row) => build_user(row),
+            None => Err(PersistenceError::NotFound("User")),
+        }
+    }
+
+    async fn get_users(&self) -> Result<Vec<User>, PersistenceError> {
+        let rows: Vec<UserRow> = sqlx::query_as("SELECT id, username, admin, guest, email, display_name FROM users ORDER BY username")
+            .fetch_all(&self.pool)
+            .await?;
+        rows.into_iter().map(build_user).collect()
+    }
+
+    async fn get_users_by_ids(&self, user_ids: &[ObjectId]) -> Result<Vec<User>, PersistenceError> {
+        if user_ids.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut query = DynamicQuery::new("SELECT id, username, admin, guest, email, display_name FROM users WHERE id IN ");
+        query.push_binds(user_ids.iter().map(|id| id.to_hex()));
+        let rows: Vec<UserRow> = Self::fetch_all(&mut *self.pool.acquire().await?, &query).await?;
+        rows.into_iter().map(build_user).collect()
+    }
+
+    async fn get_retros_by_ids(&self, retro_ids: &[ObjectId]) -> Result<Vec<Retro>, PersistenceError> {
+        let retro_ids: Vec<String> = retro_ids.iter().map(|id| id.to_hex()).collect();
+        self.load_retros(Some(&retro_ids)).await
+    }
+
+    async fn create_user(&self, user: User, password_hash: String) -> Result<User, PersistenceError> {
+        let mut tx = self.pool.begin().await?;
+        let taken: Option<(String,)> = sqlx::query_as("SELECT id FROM users WHERE username = $1 AND password_hash IS NOT NULL")
+            .bind(&user.username)
+            .fetch_optional(&mut *tx)
+            .await?;
+        if taken.is_some() {
+            return Err(PersistenceError::Conflict(format!("Username {:?} is taken", user.username)));
+        }
+        sqlx::query("INSERT INTO users (id, username, admin, password_hash) VALUES ($1, $2, $3, $4)")
+            .bind(user._id.to_hex())
+            .bind(&user.username)
+            .bind(user.admin)
+            .bind(&password_hash)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(user)
+    }
+
+    async fn get_credentials(&self, username: &str) -> Result<(User, String), PersistenceError> {
+        let row: Option<(String, String, bool, bool, Option<String>, Option<String>, String)> = sqlx::query_as("SELECT id, username, admin, guest, email, display_name, password_hash FROM users WHERE username = $1 AND password_hash IS NOT NULL")
+            .bind(username)
+            .fetch_optional(&self.pool)
+            .await?;
+        match row {
+            Some((id, username, admin, guest, email, display_name, password_hash)) => Ok((build_user((id, username, admin, guest, email, display_name))?, password_hash)),
+            None => Err(PersistenceError::NotFound("User")),
+        }
+    }
+
+    async fn get_user_by_identity(&self, issuer: &str, subject: &str) -> Result<User, PersistenceError> {
+        let row: Option<UserRow> = sqlx::query_as("SELECT users.id, users.username, users.admin, users.guest, users.email, users.display_name FROM identities JOIN users ON users.id = identities.user_id WHERE identities.issuer = $1 AND identities.subject = $2")
+            .bind(issuer)
+            .bind(subject)
+            .fetch_optional(&self.pool)
+            .await?;
+        match row {
+            Some(row) => build_user(row),
+            None => Err(PersistenceError::NotFound("User")),
+        }
+    }
+
+    async fn create_user_with_identity(&self, user: User, identity: Identity) -> Result<User, PersistenceError> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("INSERT INTO users (id, username, admin, email, display_name) VALUES ($1, $2, $3, $4, $5)")
+            .bind(user._id.to_hex())
+            .bind(&user.username)
+            .bind(user.admin)
+            .bind(&user.email)
+            .bind(&user.display_name)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("INSERT INTO identities (issuer, subject, user_id) VALUES ($1, $2, $3)")
+            .bind(&identity.issuer)
+            .bind(&identity.subject)
+            .bind(identity.user_id.to_hex())
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(user)
+    }
+
+    async fn create_guest(&self, user: User) -> Result<User, PersistenceError> {
+        sqlx::query("INSERT INTO users (id, username, admin, guest) VALUES ($1, $2, FALSE, TRUE)")
+            .bind(user._id.to_hex())
+            .bind(&user.username)
+            .execute(&self.pool)
+            .await?;
+        Ok(user)
+    }
+
+    async fn upsert_user(&self, user: User) -> Result<User, PersistenceError> {
+        let row: UserRow = sqlx::query_as(
+            "INSERT INTO users (id, username, email, display_name) VALUES ($1, $2, $3, $4) \
+             ON CONFLICT (id) DO UPDATE SET username = excluded.username, email = excluded.email, display_name = excluded.display_name \
+             RETURNING id, username, admin, guest, email, display_name"
+        )
+            .bind(user._id.to_hex())
+            .bind(&user.username)
+            .bind(&user.email)
+            .bind(&user.display_name)
+            .fetch_one(&self.pool)
+            .await?;
+        build_user(row)
+    }
+
+    async fn create_refresh_token(&self, token: RefreshToken) -> Result<(), PersistenceError> {
+        let mut tx = self.pool.begin().await?;
+        // Expired tokens are useless, so issuing a new one clears them out
+        sqlx::query("DELETE FROM refresh_tokens WHERE expires_at < $1")
+            .bind(Utc::now().timestamp())
+            .execute(&mut *tx)
+            .await?;
+        Self::insert_refresh_token(&mut tx, &token).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn rotate_refresh_token(&self, token_id: &ObjectId, next_id: ObjectId, expires_at: i64) -> Result<RefreshToken, PersistenceError> {
+        let mut tx = self.pool.begin().await?;
+        let row: Option<RefreshTokenRow> = sqlx::query_as("SELECT id, family_id, user_id, expires_at, status FROM refresh_tokens WHERE id = $1")
+            .bind(token_id.to_hex())
+            .fetch_optional(&mut *tx)
+            .await?;
+        let current = build_refresh_token(row.ok_or(PersistenceError::NotFound("Refresh token"))?)?;
+
+        let updated = sqlx::query("UPDATE refresh_tokens SET status = $1 WHERE id = $2 AND status = $3")
+            .bind(RefreshTokenStatus::Rotated.as_str())
+            .bind(token_id.to_hex())
+            .bind(RefreshTokenStatus::Active.as_str())
+            .execute(&mut *tx)
+            .await?;
+        if DB::rows_affected(&updated) == 0 {
+            return Err(PersistenceError::Conflict(format!("Refresh token {} is {}", token_id, current.status.as_str())));
+        }
+
+        let next = RefreshToken {
+            _id: next_id,
+            family_id: current.family_id,
+            user_id: current.user_id,
+            expires_at,
+            status: RefreshTokenStatus::Active,
+        };
+        Self::insert_refresh_token(&mut tx, &next).await?;
+        tx.commit().await?;
+        Ok(next)
+    }
+
+    async fn revoke_refresh_tokens(&self, family_id: &ObjectId) -> Result<u64, PersistenceError> {
+        let result = sqlx::query("UPDATE refresh_tokens SET status = $1 WHERE family_id = $2 AND status = $3")
+            .bind(RefreshTokenStatus::Revoked.as_str())
+            .bind(family_id.to_hex())
+            .bind(RefreshTokenStatus::Active.as_str())
+            .execute(&self.pool)
+            .await?;
+        Ok(DB::rows_affected(&result))
+    }
+
+    async fn create_api_token(&self, token: ApiToken) -> Result<(), PersistenceError> {
+        sqlx::query("INSERT INTO api_tokens (id, user_id, name, token_hash, scopes, created_at, expires_at) VALUES ($1, $2, $3, $4, $5, $6, $7)")
+            .bind(token._id.to_hex())
+            .bind(token.user_id.to_hex())
+            .bind(&token.name)
+            .bind(&token.token_hash)
+            .bind(api_token_scopes(&token.scopes))
+            .bind(&token.created_at)
+            .bind(&token.expires_at)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_api_token(&self, token_hash: &str) -> Result<ApiToken, PersistenceError> {
+        let row: Option<ApiTokenRow> = sqlx::query_as("SELECT id, user_id, name, token_hash, scopes, created_at, expires_at FROM api_tokens WHERE token_hash = $1")
+            .bind(token_hash)
+            .fetch_optional(&self.pool)
+            .await?;
+        build_api_token(row.ok_or(PersistenceError::NotFound("API token"))?)
+    }
+
+    async fn get_api_tokens(&self, user_id: &ObjectId) -> Result<Vec<ApiToken>, PersistenceError> {
+        let rows: Vec<ApiTokenRow> = sqlx::query_as("SELECT id, user_id, name, token_hash, scopes, created_at, expires_at FROM api_tokens WHERE user_id = $1 ORDER BY created_at")
+            .bind(user_id.to_hex())
+            .fetch_all(&self.pool)
+            .await?;
+        rows.into_iter().map(build_api_token).collect()
+    }
+
+    async fn delete_api_token(&self, user_id: &ObjectId, token_id: &ObjectId) -> Result<bool, PersistenceError> {
+        let result = sqlx::query("DELETE FROM api_tokens WHERE id = $1 AND user_id = $2")
+            .bind(token_id.to_hex())
+            .bind(user_id.to_hex())
+            .execute(&self.pool)
+            .await?;
+        Ok(DB::rows_affected(&result) > 0)
+    }
+
+    async fn create_retro(&self, retro: Retro) -> Result<Retro, PersistenceError> {
+        let retro_id = retro._id.to_hex();
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query("INSERT INTO retros (id, retro_name, creator_id, step, created_at, version, archived_at, guest_invite) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)")
+            .bind(&retro_id)
+            .bind(&retro.retro_name)
+            .bind(retro.creator_id.to_hex())
+            .bind(retro.step.as_str())
+            .bind(&retro.created_at)
+            .bind(retro.version)
+            .bind(&retro.archived_at)
+            .bind(&retro.guest_invite)
+            .execute(&mut *tx)
+            .await?;
+
+        for lane in &retro.lanes {
+            sqlx::query("INSERT INTO lanes (id, retro_id, title, priority) VALUES ($1, $2, $3, $4)")
+                .bind(lane.id.to_hex())
+                .bind(&retro_id)
+                .bind(&lane.title)
+                .bind(lane.priority as i64)
+                .execute(&mut *tx)
+                .await?;
+
+            let mut cards = vec![];
+            flatten_cards(&lane.cards, None, &mut cards);
+            for (card, parent_id, position) in cards {
+                sqlx::query("INSERT INTO cards (id, retro_id, lane_id, parent_card_id, creator_id, text, position) VALUES ($1, $2, $3, $4, $5, $6, $7)")
+                    .bind(card.id.to_hex())
+                    .bind(&retro_id)
+                    .bind(lane.id.to_hex())
+                    .bind(parent_id.map(|id| id.to_hex()))
+                    .bind(card.creator_id.to_hex())
+                    .bind(&card.text)
+                    .bind(position)
+                    .execute(&mut *tx)
+                    .await?;
+                for user_id in &card.votes {
+                    sqlx::query("INSERT INTO votes (card_id, retro_id, user_id) VALUES ($1, $2, $3)")
+                        .bind(card.id.to_hex())
+                        .bind(&retro_id)
+                        .bind(user_id.to_hex())
+                        .execute(&mut *tx)
+                        .await?;
+                }
+            }
+        }
+
+        for (position, participant) in retro.participants.iter().enumerate() {
+            sqlx::query("INSERT INTO participants (retro_id, user_id, position) VALUES ($1, $2, $3)")
+                .bind(&retro_id)
+                .bind(participant.user.to_hex())
+                .bind(position as i64)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        tx.commit().await?;
+        Ok(retro)
+    }
+
+    async fn push_card(&self, retro_id: &ObjectId, lane_id: &ObjectId, card: Card, expected_version: Option<i64>) -> Result<Option<Retro>, PersistenceError> {
+        let mut tx = self.begin_update(retro_id, expected_version).await?;
+        let result = sqlx::query(
+            "INSERT INTO cards (id, retro_id, lane_id, parent_card_id, creator_id, text, position) \
+             SELECT $1, retro_id, id, NULL, $2, $3, (SELECT COALESCE(MAX(position) + 1, 0) FROM cards WHERE lane_id = lanes.id AND parent_card_id IS NULL) \
+             FROM lanes WHERE id = $4 AND retro_id = $5")
+            .bind(card.id.to_hex())
+            .bind(card.creator_id.to_hex())
+            .bind(&card.text)
+            .bind(lane_id.to_hex())
+            .bind(retro_id.to_hex())
+            .execute(&mut *tx)
+            .await?;
+        let changed = DB::rows_affected(&result) > 0;
+        self.finish_update(tx, retro_id, changed).await
+    }
+
+    async fn update_card_text(&self, retro_id: &ObjectId, card_id: &ObjectId, text: String, expected_version: Option<i64>) -> Result<Option<Retro>, PersistenceError> {
+        let mut tx = self.begin_update(retro_id, expected_version).await?;
+        let result = sqlx::query("UPDATE cards SET text = $1 WHERE id = $2 AND retro_id = $3 AND parent_card_id IS NULL")
+            .bind(text)
+            .bind(card_id.to_hex())
+            .bind(retro_id.to_hex())
+            .execute(&mut *tx)
+            .await?;
+        let changed = DB::rows_affected(&result) > 0;
+        self.finish_update(tx, retro_id, changed).await
+    }
+
+    async fn add_vote(&self, retro_id: &ObjectId, card_id: &ObjectId, user_id: &ObjectId, expected_version: Option<i64>) -> Result<Option<Retro>, PersistenceError> {
+        let mut tx = self.begin_update(retro_id, expected_version).await?;
+        let changed = Self::card_in_retro(&mut tx, retro_id, card_id).await?;
+        if changed {
+            sqlx::query("INSERT INTO votes (card_id, retro_id, user_id) VALUES ($1, $2, $3) ON CONFLICT DO NOTHING")
+                .bind(card_id.to_hex())
+                .bind(retro_id.to_hex())
+                .bind(user_id.to_hex())
+                .execute(&mut *tx)
+                .await?;
+        }
+        self.finish_update(tx, retro_id, changed).await
+    }
+
+    async fn remove_vote(&self, retro_id: &ObjectId, card_id: &ObjectId, user_id: &ObjectId, expected_version: Option<i64>) -> Result<Option<Retro>, PersistenceError> {
+        let mut tx = self.begin_update(retro_id, expected_version).await?;
+        let changed = Self::card_in_retro(&mut tx, retro_id, card_id).await?;
+        if changed {
+            sqlx::query("DELETE FROM votes WHERE card_id = $1 AND user_id = $2")
+                .bind(card_id.to_hex())
+                .bind(user_id.to_hex())
+                .execute(&mut *tx)
+                .await?;
+        }
+        self.finish_update(tx, retro_id, changed).await
+    }
+
+    async fn set_step(&self, retro_id: &ObjectId, step: RetroStep) -> Result<Option<Retro>, PersistenceError> {
+        let mut tx = self.begin_update(retro_id, None).await?;
+        let result = sqlx::query("UPDATE retros SET step = $1 WHERE id = $2 AND step IS DISTINCT FROM $1")
+            .bind(step.as_str())
+            .bind(retro_id.to_hex())
+            .execute(&mut *tx)
+            .await?;
+        let changed = DB::rows_affected(&result) > 0;
+        self.finish_update(tx, retro_id, changed).await
+    }
+
+    async fn add_participant(&self, retro_id: &ObjectId, participant: RetroParticipant) -> Result<Option<Retro>, PersistenceError> {
+        let mut tx = self.begin_update(retro_id, None).await?;
+        let result = sqlx::query(
+            "INSERT INTO participants (retro_id, user_id, position) \
+             VALUES ($1, $2, (SELECT COALESCE(MAX(position) + 1, 0) FROM participants WHERE retro_id = $1)) \
+             ON CONFLICT DO NOTHING")
+            .bind(retro_id.to_hex())
+            .bind(participant.user.to_hex())
+            .execute(&mut *tx)
+            .await?;
+        let changed = DB::rows_affected(&result) > 0;
+        self.finish_update(tx, retro_id, changed).await
+    }
+
+    async fn remove_participant(&self, retro_id: &ObjectId, user_id: &ObjectId) -> Result<Option<Retro>, PersistenceError> {
+        let mut tx = self.begin_update(retro_id, None).await?;
+        let result = sqlx::query("DELETE FROM participants WHERE retro_id = $1 AND user_id = $2")
+            .bind(retro_id.to_hex())
+            .bind(user_id.to_hex())
+            .execute(&mut *tx)
+            .await?;
+        let changed = DB::rows_affected(&result) > 0;
+        self.finish_update(tx, retro_id, changed).await
+    }
+
+    async fn set_archived(&self, retro_id: &ObjectId, archived_at: Option<String>, expected_version: Option<i64>) -> Result<Option<Retro>, PersistenceError> {
+        let mut tx = self.begin_update(retro_id, expected_version).await?;
+        let result = sqlx::query("UPDATE retros SET archived_at = $1 WHERE id = $2 AND archived_at IS DISTINCT FROM $1")
+            .bind(archived_at)
+            .bind(retro_id.to_hex())
+            .execute(&mut *tx)
+            .await?;
+        let changed = DB::rows_affected(&result) > 0;
+        self.finish_update(tx, retro_id, changed).await
+    }
+
+    async fn set_guest_invite(&self, retro_id: &ObjectId, invite: Option<String>, expected_version: Option<i64>) -> Result<Option<Retro>, PersistenceError> {
+        let mut tx = self.begin_update(retro_id, expected_version).await?;
+        let result = sqlx::query("UPDATE retros SET guest_invite = $1 WHERE id = $2 AND guest_invite IS DISTINCT FROM $1")
+            .bind(invite)
+            .bind(retro_id.to_hex())
+            .execute(&mut *tx)
+            .await?;
+        let changed = DB::rows_affected(&result) > 0;
+        self.finish_update(tx, retro_id, changed).await
+    }
+
+    // Lanes, cards, votes and participants go with it through ON DELETE CASCADE
+    async fn delete_retro(&self, retro_id: &ObjectId, expected_version: Option<i64>) -> Result<(), PersistenceError> {
+        let mut connection = self.pool.acquire().await?;
+        let sql = match expected_version {
+            Some(_) => "DELETE FROM retros WHERE id = $1 AND version = $2",
+            None => "DELETE FROM retros WHERE id = $1",
+        };
+        let mut query = sqlx::query(sql).bind(retro_id.to_hex());
+        if let Some(version) = expected_version {
+            query = query.bind(version);
+        }
+        let result = query.execute(&mut *connection).await?;
+        if DB::rows_affected(&result) == 0 {
+            return Err(Self::missing_or_moved_on(&mut connection, retro_id).await);
+        }
+        Ok(())
+    }
+
+    async fn anonymize_card_authors(&self, retro_id: &ObjectId) -> Result<Option<Retro>, PersistenceError> {
+        let mut tx = self.begin_update(retro_id, None).await?;
+        let anonymous_id = ANONYMOUS_USER_ID.to_hex();
+        let result = sqlx::query("UPDATE cards SET creator_id = $1 WHERE retro_id = $2 AND creator_id <> $3")
+            .bind(&anonymous_id)
+            .bind(retro_id.to_hex())
+            .bind(&anonymous_id)
+            .execute(&mut *tx)
+            .await?;
+        let changed = DB::rows_affected(&result) > 0;
+        self.finish_update(tx, retro_id, changed).await
+    }
+
+    // Rows are upgraded by the embedded SQL migrations, which already ran when the handler opened
+    async fn migrate(&self) -> Result<u64, PersistenceError> {
+        Ok(self.upgraded_retros)
+    }
+}
+
+// Scenarios every SQL backend runs in its own tests, and memory mode where it shares the behavior
+#[cfg(test)]
+pub mod tests {
+    use std::collections::HashSet;
+
+    use mongodb::bson::oid::ObjectId;
+
+    use crate::models::{Card, Identity, Lane, RefreshToken, RefreshTokenStatus, Retro, RetroFilter, RetroOrderField, RetroPageQuery, RetroParticipant, RetroStep, SortDirection, User};
+
+    use super::super::{migrations, PersistenceError, PersistenceHandler};
+
+    fn card(retro_id: ObjectId, text: &str, subcards: Vec<Card>) -> Card {
+        let creator_id = ObjectId::new();
+        Card {
+            id: ObjectId::new(),
+            creator_id,
+            retro_id,
+            text: text.to_string(),
+            subcards,
+            votes: HashSet::from([creator_id, ObjectId::new()]),
+        }
+    }
+
+    pub fn sample_retro(retro_name: &str) -> Retro {
+        let retro_id = ObjectId::new();
+        let grouped = card(retro_id, "grouped", vec![card(retro_id, "child", vec![])]);
+        Retro {
+            _id: retro_id,
+            retro_name: retro_name.to_string(),
+            creator_id: ObjectId::new(),
+            step: RetroStep::Grouping,
+            created_at: chrono::Utc::now().to_rfc3339(),
+            participants: vec![
+                RetroParticipant { user: ObjectId::new(), retro_id },
+                RetroParticipant { user: ObjectId::new(), retro_id },
+            ],
+            lanes: vec![
+                Lane { id: ObjectId::new(), title: "Good".to_string(), cards: vec![grouped, card(retro_id, "plain", vec![])], priority: 1 },
+                Lane { id: ObjectId::new(), title: "Bad".to_string(), cards: vec![], priority: 2 },
+            ],
+            version: 0,
+            archived_at: None,
+            schema_version: migrations::CURRENT_SCHEMA_VERSION,
+            guest_invite: None,
+        }
+    }
+
+    fn participant_ids(retro: &Retro) -> Vec<ObjectId> {
+        retro.participants.iter().map(|p| p.user).collect()
+    }
+
+    pub async fn retro_round_trip(handler: &impl PersistenceHandler) {
+        let retro = sample_retro("round trip");
+        handler.create_retro(retro.clone()).await.unwrap();
+
+        let stored = handler.get_retro(&retro._id).await.unwrap();
+        assert_eq!(stored.retro_name, retro.retro_name);
+        assert_eq!(stored.creator_id, retro.creator_id);
+        assert_eq!(stored.step, retro.step);
+        assert_eq!(stored.created_at, retro.created_at);
+        assert_eq!(stored.version, 0);
+        assert_eq!(participant_ids(&stored), participant_ids(&retro));
+        assert_eq!(stored.lanes.len(), 2);
+        for (stored_lane, lane) in stored.lanes.iter().zip(&retro.lanes) {
+            assert_eq!(stored_lane.id, lane.id);
+            assert_eq!(stored_lane.title, lane.title);
+            assert_eq!(stored_lane.cards, lane.cards);
+        }
+
+        let found = handler.get_retros_by_ids(&[retro._id, ObjectId::new()]).await.unwrap();
+        assert_eq!(found.len(), 1);
+        assert!(matches!(handler.get_retro(&ObjectId::new()).await, Err(PersistenceError::NotFound(_))));
+    }
+
+    pub async fn targeted_writes(handler: &impl PersistenceHandler) {
+        let retro = sample_retro("targeted writes");
+        let retro_id = retro._id;
+        let lane_id = retro.lanes[1].id;
+        handler.create_retro(retro.clone()).await.unwrap();
+
+        let added = card(retro_id, "added", vec![]);
+        let updated = handler.push_card(&retro_id, &lane_id, added.clone(), Some(0)).await.unwrap().unwrap();
+        assert_eq!(updated.version, 1);
+        let late = card(retro_id, "late", vec![]);
+        assert!(matches!(handler.push_card(&retro_id, &lane_id, late, Some(0)).await, Err(PersistenceError::Conflict(_))));
+        assert_eq!(updated.lanes[1].cards.len(), 1);
+        assert_eq!(updated.lanes[1].cards[0].text, "added");
+        assert!(handler.push_card(&retro_id, &ObjectId::new(), added.clone(), None).await.unwrap().is_none());
+
+        let updated = handler.update_card_text(&retro_id, &added.id, "edited".to_string(), None).await.unwrap().unwrap();
+        assert_eq!(updated.find_card(&added.id).unwrap().1.text, "edited");
+        assert!(handler.update_card_text(&retro_id, &ObjectId::new(), "lost".to_string(), None).await.unwrap().is_none());
+
+        // Subcards are only reachable through the card they were grouped under
+        let subcard_id = retro.lanes[0].cards[0].subcards[0].id;
+        assert!(handler.update_card_text(&retro_id, &subcard_id, "lost".to_string(), None).await.unwrap().is_none());
+        assert!(handler.add_vote(&retro_id, &subcard_id, &ObjectId::new(), None).await.unwrap().is_none());
+        assert!(handler.remove_vote(&retro_id, &subcard_id, &retro.creator_id, None).await.unwrap().is_none());
+        let stored = handler.get_retro(&retro_id).await.unwrap();
+        assert_eq!(stored.lanes[0].cards[0].subcards, retro.lanes[0].cards[0].subcards);
+        assert_eq!(stored.version, updated.version);
+
+        let voter = ObjectId::new();
+        let updated = handler.add_vote(&retro_id, &added.id, &voter, None).await.unwrap().unwrap();
+        assert!(updated.find_card(&added.id).unwrap().1.votes.contains(&voter));
+        let updated = handler.remove_vote(&retro_id, &added.id, &voter, None).await.unwrap().unwrap();
+        assert!(!updated.find_card(&added.id).unwrap().1.votes.contains(&voter));
+
+        let updated = handler.set_step(&retro_id, RetroStep::Voting).await.unwrap().unwrap();
+        assert_eq!(updated.step, RetroStep::Voting);
+        assert!(handler.set_step(&retro_id, RetroStep::Voting).await.unwrap().is_none());
+
+        let newcomer = RetroParticipant { user: ObjectId::new(), retro_id };
+        let updated = handler.add_participant(&retro_id, newcomer.clone()).await.unwrap().unwrap();
+        assert_eq!(participant_ids(&updated).last(), Some(&newcomer.user));
+        assert!(handler.add_participant(&retro_id, newcomer.clone()).await.unwrap().is_none());
+        let updated = handler.remove_participant(&retro_id, &newcomer.user).await.unwrap().unwrap();
+        assert_eq!(participant_ids(&updated), participant_ids(&retro));
+        assert!(handler.remove_participant(&retro_id, &newcomer.user).await.unwrap().is_none());
+
+        let archived_at = Some(chrono::Utc::now().to_rfc3339());
+        let updated = handler.set_archived(&retro_id, archived_at.clone(), None).await.unwrap().unwrap();
+        assert_eq!(updated.archived_at, archived_at);
+        assert!(handler.set_archived(&retro_id, archived_at, None).await.unwrap().is_none());
+        assert!(handler.set_archived(&retro_id, None, None).await.unwrap().unwrap().archived_at.is_none());
+        assert!(handler.set_archived(&retro_id, None, None).await.unwrap().is_none());
+        let updated = handler.set_guest_invite(&retro_id, Some("invite".to_string()), None).await.unwrap().unwrap();
+        assert_eq!(updated.guest_invite.as_deref(), Some("invite"));
+        assert!(handler.set_guest_invite(&retro_id, Some("invite".to_string()), None).await.unwrap().is_none());
+        let before = updated.version;
+        assert_eq!(handler.get_retro(&retro_id).await.unwrap().version, before);
+
+        let updated = handler.anonymize_card_authors(&retro_id).await.unwrap().unwrap();
+        assert!(updated.lanes.iter().flat_map(|lane| &lane.cards).all(|card| card.creator_id == crate::models::ANONYMOUS_USER_ID));
+        assert!(handler.anonymize_card_authors(&retro_id).await.unwrap().is_none());
+
+        let stale = updated.version - 1;
+        assert!(matches!(handler.delete_retro(&retro_id, Some(stale)).await, Err(PersistenceError::Conflict(_))));
+        handler.delete_retro(&retro_id, Some(updated.version)).await.unwrap();
+        assert!(matches!(handler.get_retro(&retro_id).await, Err(PersistenceError::NotFound(_))));
+        assert!(matches!(handler.delete_retro(&retro_id, None).await, Err(PersistenceError::NotFound(_))));
+        assert!(matches!(handler.set_step(&retro_id, RetroStep::Writing).await, Err(PersistenceError::NotFound(_))));
+        assert!(matches!(handler.set_archived(&retro_id, None, Some(0)).await, Err(PersistenceError::NotFound(_))));
+    }
+
+    pub async fn retro_pages(handler: &impl PersistenceHandler) {
+        let tag = ObjectId::new().to_hex();
+        for name in ["b", "a", "c"] {
+            handler.create_retro(sample_retro(&format!("Page {} {}", tag, name))).await.unwrap();
+        }
+        let mut query = RetroPageQuery {
+            filter: RetroFilter { name_contains: Some(tag.to_uppercase()), ..Default::default() },
+            order_by: RetroOrderField::Name,
+            direction: SortDirection::Asc,
+            first: 2,
+            ..Default::default()
+        };
+
+        let page = handler.get_retro_page(&query).await.unwrap();
+        let names: Vec<&str> = page.retros.iter().map(|r| r.retro_name.as_str()).collect();
+        assert_eq!(names, [format!("Page {} a", tag), format!("Page {} b", tag)]);
+        assert!(page.has_next_page);
+
+        query.after = Some(crate::models::RetroCursor::for_retro(&page.retros[1], RetroOrderField::Name));
+        let page = handler.get_retro_page(&query).await.unwrap();
+        assert_eq!(page.retros.len(), 1);
+        assert_eq!(page.retros[0].retro_name, format!("Page {} c", tag));
+        assert!(!page.has_next_page);
+    }
+
+    pub async fn users_and_credentials(handler: &impl PersistenceHandler) {
+        let username = format!("user-{}", ObjectId::new());
+        let user = User { _id: ObjectId::new(), username: username.clone(), admin: false, guest: false, email: None, display_name: None };
+
+        // Guests and users from an identity provider may share a username without getting in the
+        // way of registering or signing in
+        let guest = User { _id: ObjectId::new(), guest: true, ..user.clone() };
+        handler.create_guest(guest.clone()).await.unwrap();
+        let linked = User { _id: ObjectId::new(), ..user.clone() };
+        let identity = Identity { issuer: "https://idp.example.com".to_string(), subject: ObjectId::new().to_hex(), user_id: linked._id };
+        handler.create_user_with_identity(linked, identity).await.unwrap();
+
+        handler.create_user(user.clone(), "hash".to_string()).await.unwrap();
+        let taken = User { _id: ObjectId::new(), ..user.clone() };
+        assert!(matches!(handler.create_user(taken, "other".to_string()).await, Err(PersistenceError::Conflict(_))));
+        let (found, password_hash) = handler.get_credentials(&username).await.unwrap();
+        assert_eq!(found, user);
+        assert_eq!(password_hash, "hash");
+        assert!(matches!(handler.get_credentials("nobody at all").await, Err(PersistenceError::NotFound(_))));
+
+        let profile = User { email: Some("user@example.com".to_string()), display_name: Some("User".to_string()), ..user.clone() };
+        assert_eq!(handler.upsert_user(profile.clone()).await.unwrap(), profile);
+        let found = handler.get_users_by_ids(&[user._id, guest._id, ObjectId::new()]).await.unwrap();
+        assert_eq!(found.len(), 2);
+    }
+
+    pub async fn identity_users(handler: &impl PersistenceHandler) {
+        let issuer = "https://idp.example.com";
+        let subject = ObjectId::new().to_hex();
+        let user = User {
+            _id: ObjectId::new(),
+            username: "ada".to_string(),
+            admin: false,
+            guest: false,
+            email: Some("ada@example.com".to_string()),
+            display_name: Some("Ada Lovelace".to_string()),
+        };
+        let identity = Identity { issuer: issuer.to_string(), subject: subject.clone(), user_id: user._id };
+        handler.create_user_with_identity(user.clone(), identity.clone()).await.unwrap();
+        assert_eq!(handler.get_user_by_identity(issuer, &subject).await.unwrap(), user);
+
+        // A second first login for the same identity loses
+        let twin = User { _id: ObjectId::new(), ..user.clone() };
+        let identity = Identity { user_id: twin._id, ..identity };
+        assert!(matches!(handler.create_user_with_identity(twin, identity).await, Err(PersistenceError::Conflict(_))));
+        assert!(matches!(handler.get_user_by_identity(issuer, "someone else").await, Err(PersistenceError::NotFound(_))));
+    }
+
+    pub async fn refresh_token_rotation(handler: &impl PersistenceHandler) {
+        let family_id = ObjectId::new();
+        let expires_at = chrono::Utc::now().timestamp() + 3600;
+        let token = RefreshToken { _id: ObjectId::new(), family_id, user_id: ObjectId::new(), expires_at, status: RefreshTokenStatus::Active };
+        handler.create_refresh_token(token.clone()).await.unwrap();
+
+        let next = handler.rotate_refresh_token(&token._id, ObjectId::new(), expires_at).await.unwrap();
+        assert_eq!(next.family_id, family_id);
+        assert!(matches!(handler.rotate_refresh_token(&token._id, ObjectId::new(), expires_at).await, Err(PersistenceError::Conflict(_))));
+        assert!(matches!(handler.rotate_refresh_token(&ObjectId::new(), ObjectId::new(), expires_at).await, Err(PersistenceError::NotFound(_))));
+        assert_eq!(handler.revoke_refresh_tokens(&family_id).await.unwrap(), 1);
+    }
+}
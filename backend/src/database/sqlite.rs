@@ -0,0 +1,109 @@
+use sqlx::{sqlite::{SqliteConnectOptions, SqlitePoolOptions, SqliteQueryResult}, Sqlite};
+
+use super::{sql::{Dialect, SqlHandler}, PersistenceError};
+
+pub type SqliteHandler = SqlHandler<Sqlite>;
+
+impl Dialect for Sqlite {
+    const FIND_FUNCTION: &'static str = "instr";
+    // A deferred transaction reads from one snapshot already
+    const READ_SNAPSHOT: Option<&'static str> = None;
+
+    fn rows_affected(result: &SqliteQueryResult) -> u64 {
+        result.rows_affected()
+    }
+}
+
+pub async fn connect(path: &str) -> Result<SqliteHandler, PersistenceError> {
+    let options = SqliteConnectOptions::new()
+        .filename(path)
+        .create_if_missing(true)
+        .foreign_keys(true);
+    let pool = SqlitePoolOptions::new().connect_with(options).await?;
+    SqlHandler::open(pool, &sqlx::migrate!("./migrations/sqlite")).await
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::oid::ObjectId;
+
+    use super::super::{sql::tests, PersistenceHandler};
+    use super::*;
+
+    // A fresh database file, removed again when the test ends
+    struct TempDatabase(std::path::PathBuf);
+
+    impl Drop for TempDatabase {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    async fn handler() -> (TempDatabase, SqliteHandler) {
+        let path = std::env::temp_dir().join(format!("retro-test-{}.db", ObjectId::new()));
+        let handler = connect(path.to_str().unwrap()).await.unwrap();
+        (TempDatabase(path), handler)
+    }
+
+    #[tokio::test]
+    async fn retro_round_trip() {
+        let (_database, handler) = handler().await;
+        tests::retro_round_trip(&handler).await;
+    }
+
+    #[tokio::test]
+    async fn targeted_writes() {
+        let (_database, handler) = handler().await;
+        tests::targeted_writes(&handler).await;
+    }
+
+    #[tokio::test]
+    async fn retro_pages() {
+        let (_database, handler) = handler().await;
+        tests::retro_pages(&handler).await;
+    }
+
+    #[tokio::test]
+    async fn users_and_credentials() {
+        let (_database, handler) = handler().await;
+        tests::users_and_credentials(&handler).await;
+    }
+
+    #[tokio::test]
+    async fn migrate_counts_retros_stored_before_pending_migrations() {
+        let path = std::env::temp_dir().join(format!("retro-test-{}.db", ObjectId::new()));
+        let _database = TempDatabase(path.clone());
+        let options = SqliteConnectOptions::new().filename(&path).create_if_missing(true);
+
+        // A database left behind by a build that only knew the first migration
+        let mut first = sqlx::migrate!("./migrations/sqlite");
+        first.migrations = first.migrations[..1].to_vec().into();
+        let pool = SqlitePoolOptions::new().connect_with(options.clone()).await.unwrap();
+        first.run(&pool).await.unwrap();
+        sqlx::query("INSERT INTO retros (id, retro_name, creator_id, step, created_at) VALUES ($1, 'Old', $2, 'Writing', '2024-01-01T00:00:00+00:00')")
+            .bind(ObjectId::new().to_hex())
+            .bind(ObjectId::new().to_hex())
+            .execute(&pool).await.unwrap();
+        pool.close().await;
+
+        let handler = connect(path.to_str().unwrap()).await.unwrap();
+        assert_eq!(handler.migrate().await.unwrap(), 1);
+        drop(handler);
+
+        // Nothing is pending anymore the next time around
+        let handler = connect(path.to_str().unwrap()).await.unwrap();
+        assert_eq!(handler.migrate().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn identity_users() {
+        let (_database, handler) = handler().await;
+        tests::identity_users(&handler).await;
+    }
+
+    #[tokio::test]
+    async fn refresh_token_rotation() {
+        let (_database, handler) = handler().await;
+        tests::refresh_token_rotation(&handler).await;
+    }
+}
@@ -0,0 +1,50 @@
+use std::str::FromStr;
+
+use derive_more::derive::{Display, Error};
+use juniper::{graphql_value, FieldError, IntoFieldError, ScalarValue};
+use mongodb::bson::oid::ObjectId;
+
+use crate::database::PersistenceError;
+
+// Errors returned from resolvers; each maps onto a stable `extensions.code` clients can match on:
+// INVALID_ID for malformed ids, INVALID_ARGUMENT for any other input that fails validation,
+// NOT_FOUND and FORBIDDEN. Persistence failures keep their own codes, which is where CONFLICT
+// comes from.
+#[derive(Debug, Display, Error)]
+pub enum ApiError {
+    #[display("{_0:?} is not a valid id")]
+    InvalidId(#[error(not(source))] String),
+    #[display("{_0}")]
+    InvalidArgument(#[error(not(source))] String),
+    #[display("{_0} not found")]
+    NotFound(#[error(not(source))] &'static str),
+    #[display("{_0}")]
+    Forbidden(#[error(not(source))] String),
+    #[display("{_0}")]
+    Persistence(PersistenceError),
+}
+
+pub type ApiResult<T> = Result<T, ApiError>;
+
+impl From<PersistenceError> for ApiError {
+    fn from(error: PersistenceError) -> Self {
+        ApiError::Persistence(error)
+    }
+}
+
+impl<S: ScalarValue> IntoFieldError<S> for ApiError {
+    fn into_field_error(self) -> FieldError<S> {
+        let code = match self {
+            ApiError::InvalidId(_) => "INVALID_ID",
+            ApiError::InvalidArgument(_) => "INVALID_ARGUMENT",
+            ApiError::NotFound(_) => "NOT_FOUND",
+            ApiError::Forbidden(_) => "FORBIDDEN",
+            ApiError::Persistence(e) => return e.into_field_error(),
+        };
+        FieldError::new(self, graphql_value!({ "code": code }))
+    }
+}
+
+pub fn parse_id(id: &str) -> ApiResult<ObjectId> {
+    ObjectId::from_str(id).map_err(|_| ApiError::InvalidId(id.to_string()))
+}
@@ -0,0 +1,274 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::stream::StreamExt;
+use redis::AsyncCommands;
+use tokio::sync::broadcast;
+
+use crate::models::{RedisConfig, SubscriptionUpdate};
+
+const CHANNEL_CAPACITY: usize = 100;
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Topic {
+    CardAdded,
+    UserListUpdated,
+    StepUpdated,
+    RetroArchived,
+    RetroDeleted,
+}
+
+impl SubscriptionUpdate {
+    pub fn topic(&self) -> Topic {
+        match self {
+            SubscriptionUpdate::CardAdded(_) => Topic::CardAdded,
+            SubscriptionUpdate::UserListUpdated(_) => Topic::UserListUpdated,
+            SubscriptionUpdate::StepUpdated(_) => Topic::StepUpdated,
+            SubscriptionUpdate::RetroArchived(_) => Topic::RetroArchived,
+            SubscriptionUpdate::RetroDeleted(_) => Topic::RetroDeleted,
+        }
+    }
+}
+
+// Process-local fan-out that every bus delivers into; subscribers always read from here
+#[derive(Clone)]
+struct LocalChannels {
+    card_addition_sender: broadcast::Sender<SubscriptionUpdate>,
+    user_update_sender: broadcast::Sender<SubscriptionUpdate>,
+    step_update_sender: broadcast::Sender<SubscriptionUpdate>,
+    archive_sender: broadcast::Sender<SubscriptionUpdate>,
+    deletion_sender: broadcast::Sender<SubscriptionUpdate>,
+}
+
+impl LocalChannels {
+    fn new() -> Self {
+        let (card_addition_sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        let (user_update_sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        let (step_update_sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        let (archive_sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        let (deletion_sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+
+        LocalChannels {
+            card_addition_sender,
+            user_update_sender,
+            step_update_sender,
+            archive_sender,
+            deletion_sender,
+        }
+    }
+
+    fn sender(&self, topic: Topic) -> &broadcast::Sender<SubscriptionUpdate> {
+        match topic {
+            Topic::CardAdded => &self.card_addition_sender,
+            Topic::UserListUpdated => &self.user_update_sender,
+            Topic::StepUpdated => &self.step_update_sender,
+            Topic::RetroArchived => &self.archive_sender,
+            Topic::RetroDeleted => &self.deletion_sender,
+        }
+    }
+
+    fn deliver(&self, update: SubscriptionUpdate) {
+        // No receivers just means nobody is subscribed on this replica
+        let _ = self.sender(update.topic()).send(update);
+    }
+}
+
+#[async_trait]
+trait EventBusHandler: Clone {
+    async fn publish(&self, update: SubscriptionUpdate) -> Result<(), String>;
+    fn subscribe(&self, topic: Topic) -> broadcast::Receiver<SubscriptionUpdate>;
+}
+
+#[derive(Clone)]
+pub struct MemoryBus {
+    channels: LocalChannels,
+}
+
+impl Default for MemoryBus {
+    fn default() -> Self {
+        MemoryBus {
+            channels: LocalChannels::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl EventBusHandler for MemoryBus {
+    async fn publish(&self, update: SubscriptionUpdate) -> Result<(), String> {
+        self.channels.deliver(update);
+        Ok(())
+    }
+
+    fn subscribe(&self, topic: Topic) -> broadcast::Receiver<SubscriptionUpdate> {
+        self.channels.sender(topic).subscribe()
+    }
+}
+
+#[derive(Clone)]
+pub struct RedisBus {
+    channels: LocalChannels,
+    connection: redis::aio::MultiplexedConnection,
+    channel: String,
+}
+
+impl RedisBus {
+    pub async fn new(redis_config: &RedisConfig) -> Result<RedisBus, String> {
+        let client = redis::Client::open(redis_config.url.as_str())
+            .map_err(|e| format!("Invalid redis url {:?}: {}", redis_config.url, e))?;
+        let connection = client
+            .get_multiplexed_async_connection()
+            .await
+            .map_err(|e| format!("Failed to connect to redis: {}", e))?;
+        let channels = LocalChannels::new();
+
+        tokio::spawn(Self::listen(client, redis_config.channel.clone(), channels.clone()));
+
+        Ok(RedisBus {
+            channels,
+            connection,
+            channel: redis_config.channel.clone(),
+        })
+    }
+
+    // Forwards every update published by any replica into this replica's local channels
+    async fn listen(client: redis::Client, channel: String, channels: LocalChannels) {
+        loop {
+            if let Err(e) = Self::forward_messages(&client, &channel, &channels).await {
+                log::warn!("Redis event listener disconnected: {}", e);
+            }
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    }
+
+    async fn forward_messages(client: &redis::Client, channel: &str, channels: &LocalChannels) -> redis::RedisResult<()> {
+        let mut pubsub = client.get_async_pubsub().await?;
+        pubsub.subscribe(channel).await?;
+
+        let mut messages = pubsub.on_message();
+        while let Some(message) = messages.next().await {
+            let payload: String = message.get_payload()?;
+            match serde_json::from_str::<SubscriptionUpdate>(&payload) {
+                Ok(update) => channels.deliver(update),
+                Err(e) => log::warn!("Dropping malformed event from redis: {}", e),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl EventBusHandler for RedisBus {
+    async fn publish(&self, update: SubscriptionUpdate) -> Result<(), String> {
+        let payload = serde_json::to_string(&update).map_err(|e| e.to_string())?;
+        let mut connection = self.connection.clone();
+        connection
+            .publish::<_, _, ()>(&self.channel, payload)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    fn subscribe(&self, topic: Topic) -> broadcast::Receiver<SubscriptionUpdate> {
+        self.channels.sender(topic).subscribe()
+    }
+}
+
+#[derive(Clone)]
+pub enum EventBus {
+    Memory(MemoryBus),
+    Redis(RedisBus),
+}
+
+impl EventBus {
+    pub fn new_memory() -> EventBus {
+        EventBus::Memory(MemoryBus::default())
+    }
+
+    pub async fn new_redis(redis_config: &RedisConfig) -> Result<EventBus, String> {
+        let bus = RedisBus::new(redis_config).await?;
+        Ok(EventBus::Redis(bus))
+    }
+
+    // A failed publish only costs subscribers a live update, so it's logged rather than failing
+    // the mutation that already went through
+    pub async fn publish(&self, update: SubscriptionUpdate) {
+        let topic = update.topic();
+        let published = match self {
+            EventBus::Memory(bus) => bus.publish(update).await,
+            EventBus::Redis(bus) => bus.publish(update).await,
+        };
+        if let Err(e) = published {
+            log::warn!("Failed to publish a {:?} event: {}", topic, e);
+        }
+    }
+
+    pub fn subscribe(&self, topic: Topic) -> broadcast::Receiver<SubscriptionUpdate> {
+        match self {
+            EventBus::Memory(bus) => bus.subscribe(topic),
+            EventBus::Redis(bus) => bus.subscribe(topic),
+        }
+    }
+}
+
+// Run against the compose redis with `docker compose --profile redis up -d redis` and
+// `cargo test -- --ignored`; REDIS_URL points them elsewhere
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::oid::ObjectId;
+
+    use super::*;
+    use crate::models::RetroStep;
+
+    const RECEIVE_TIMEOUT: Duration = Duration::from_secs(20);
+
+    fn redis_config() -> RedisConfig {
+        RedisConfig {
+            url: std::env::var("REDIS_URL").unwrap_or("redis://127.0.0.1:6379".to_string()),
+            channel: format!("retro-test-{}", ObjectId::new()),
+        }
+    }
+
+    // Keeps publishing until the update comes through, since the listener subscribes in the
+    // background and anything published before that is lost
+    async fn round_trip(publisher: &EventBus, subscriber: &EventBus) -> SubscriptionUpdate {
+        let mut receiver = subscriber.subscribe(Topic::StepUpdated);
+        let update = SubscriptionUpdate::create_step_update(ObjectId::new(), RetroStep::Voting);
+        let received = tokio::time::timeout(RECEIVE_TIMEOUT, async {
+            loop {
+                publisher.publish(update.clone()).await;
+                if let Ok(Ok(received)) = tokio::time::timeout(Duration::from_millis(200), receiver.recv()).await {
+                    return received;
+                }
+            }
+        });
+        received.await.expect("the update never arrived")
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a redis server"]
+    async fn updates_reach_other_replicas() {
+        let config = redis_config();
+        let first = EventBus::new_redis(&config).await.unwrap();
+        let second = EventBus::new_redis(&config).await.unwrap();
+
+        let received = round_trip(&first, &second).await;
+        assert_eq!(received.topic(), Topic::StepUpdated);
+        assert_eq!(round_trip(&second, &first).await.topic(), Topic::StepUpdated);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a redis server"]
+    async fn listener_reconnects_after_losing_redis() {
+        let config = redis_config();
+        let bus = EventBus::new_redis(&config).await.unwrap();
+        round_trip(&bus, &bus).await;
+
+        // Drops every subscriber connection, ours included
+        let client = redis::Client::open(config.url.as_str()).unwrap();
+        let mut connection = client.get_multiplexed_async_connection().await.unwrap();
+        redis::cmd("CLIENT").arg("KILL").arg("TYPE").arg("pubsub")
+            .query_async::<()>(&mut connection).await.unwrap();
+
+        round_trip(&bus, &bus).await;
+    }
+}
@@ -0,0 +1,103 @@
+use std::sync::Arc;
+
+use actix_web::{error, http::StatusCode, web::{self, Data, Json}, HttpResponse};
+use derive_more::derive::{Display, Error};
+use mongodb::bson::oid::ObjectId;
+use sha2::{Digest, Sha256};
+
+use crate::auth::{AuthError, TokenIssuer, MAX_USERNAME_LENGTH};
+use crate::database::PersistenceError;
+use crate::models::{GuestConfig, GuestJoinRequest, LoginResponse, User};
+
+#[derive(Debug, Display, Error)]
+pub enum GuestError {
+    // The same answer for unknown retros, closed retros and wrong invites, so invites can't be probed
+    #[display("That retro is not available to guests.")]
+    NotAvailable,
+    #[display("{_0}")]
+    InvalidRequest(#[error(not(source))] String),
+    #[display("{_0}")]
+    Session(AuthError),
+}
+
+impl error::ResponseError for GuestError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            GuestError::NotAvailable => StatusCode::NOT_FOUND,
+            GuestError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+            GuestError::Session(e) => e.status_code(),
+        }
+    }
+}
+
+impl From<AuthError> for GuestError {
+    fn from(error: AuthError) -> Self {
+        GuestError::Session(error)
+    }
+}
+
+impl From<PersistenceError> for GuestError {
+    fn from(error: PersistenceError) -> Self {
+        match error {
+            PersistenceError::NotFound(_) => GuestError::NotAvailable,
+            e => {
+                log::error!("Guest join failed to reach persistence: {}", e);
+                GuestError::Session(AuthError::Unavailable)
+            }
+        }
+    }
+}
+
+// Lets people without an account join a single retro through its invite, shared as app data when guests are on
+pub struct Guests {
+    pub config: GuestConfig,
+    pub tokens: Arc<TokenIssuer>,
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/api/v1/auth/guest").route(web::post().to(join)));
+}
+
+fn validate(display_name: &str) -> Result<String, GuestError> {
+    let display_name = display_name.trim();
+    if display_name.is_empty() || display_name.len() > MAX_USERNAME_LENGTH {
+        return Err(GuestError::InvalidRequest(format!("Display names must be 1 to {} characters.", MAX_USERNAME_LENGTH)));
+    }
+    if display_name.chars().any(char::is_control) {
+        return Err(GuestError::InvalidRequest("Display names cannot contain control characters.".to_string()));
+    }
+    Ok(display_name.to_string())
+}
+
+// Compares digests so the time taken says nothing about how much of the invite matched
+fn invite_matches(expected: &str, given: &str) -> bool {
+    Sha256::digest(expected.as_bytes()) == Sha256::digest(given.as_bytes())
+}
+
+async fn join(request: Json<GuestJoinRequest>, guests: Data<Guests>) -> Result<HttpResponse, GuestError> {
+    let request = request.into_inner();
+    let display_name = validate(&request.display_name)?;
+    let retro_id = ObjectId::parse_str(&request.retro_id).map_err(|_| GuestError::NotAvailable)?;
+
+    let persistence_manager = &guests.tokens.persistence_manager;
+    let retro = persistence_manager.get_retro(&retro_id).await?;
+    let open = retro.archived_at.is_none()
+        && retro.guest_invite.as_deref().is_some_and(|invite| invite_matches(invite, &request.invite));
+    if !open {
+        return Err(GuestError::NotAvailable);
+    }
+
+    let user = User {
+        _id: ObjectId::new(),
+        username: display_name,
+        admin: false,
+        guest: true,
+        email: None,
+        display_name: None,
+    };
+    let user = persistence_manager.create_guest(user).await?;
+    log::info!("Guest {} ({}) joined retro {}", user.username, user._id, retro_id);
+
+    let token = guests.tokens.guest_token(user._id, retro_id, guests.config.token_ttl_secs)?;
+    Ok(HttpResponse::Created().json(LoginResponse { token, refresh_token: None }))
+}
@@ -0,0 +1,417 @@
+use std::{fs, str::FromStr, sync::{Arc, RwLock}, time::Duration};
+
+use actix_web::{error, http::StatusCode};
+use chrono::{DateTime, Utc};
+use derive_more::derive::{Display, Error};
+use ed25519_dalek::pkcs8::DecodePublicKey;
+use jwt_compact::{
+    alg::{Ed25519, Es256, Hs512, Hs512Key, Rsa, RsaPublicKey},
+    jwk::JsonWebKey,
+    AlgorithmExt, TimeOptions, Token, UntrustedToken, ValidationError,
+};
+use rsa::pkcs1::DecodeRsaPublicKey;
+use serde::{de::DeserializeOwned, Deserialize};
+
+use crate::models::{JwtAlgorithm, JwtConfig, JwtKeyConfig};
+
+// Clock skew tolerated between token issuers and this service
+const LEEWAY_MINUTES: i64 = 15;
+
+#[derive(Debug, Display, Error)]
+pub enum KeyError {
+    #[display("JWT_SECRET must be set while jwt.shared_secret is on")]
+    MissingSharedSecret,
+    #[display("Failed to read {path}: {reason}")]
+    Unreadable {
+        path: String,
+        reason: String,
+    },
+    #[display("Invalid key in {path}: {reason}")]
+    Invalid {
+        path: String,
+        reason: String,
+    },
+    #[display("No keys are configured for verifying access tokens")]
+    NoKeys,
+}
+
+#[derive(Debug, Display, Error)]
+pub enum TokenError {
+    #[display("An access token is required.")]
+    Missing,
+    #[display("The access token is invalid or has expired.")]
+    Invalid,
+}
+
+impl error::ResponseError for TokenError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::UNAUTHORIZED
+    }
+}
+
+#[derive(Clone)]
+enum VerifyingKey {
+    Hs512(Hs512Key),
+    Rsa(Rsa, RsaPublicKey),
+    Es256(p256::ecdsa::VerifyingKey),
+    EdDsa(ed25519_dalek::VerifyingKey),
+}
+
+#[derive(Clone)]
+struct Key {
+    kid: Option<String>,
+    algorithm: String,
+    key: VerifyingKey,
+}
+
+impl Key {
+    fn new(kid: Option<String>, algorithm: JwtAlgorithm, key: VerifyingKey) -> Key {
+        Key { kid, algorithm: algorithm.as_str().to_string(), key }
+    }
+
+    // Keys without a kid stand in for any kid, so tokens from issuers that don't set one still verify
+    fn matches(&self, token: &UntrustedToken<'_>) -> bool {
+        if self.algorithm != token.algorithm() {
+            return false;
+        }
+        match (&self.kid, &token.header().key_id) {
+            (Some(kid), Some(token_kid)) => kid == token_kid,
+            _ => true,
+        }
+    }
+
+    fn validate<T: DeserializeOwned + Clone>(&self, token: &UntrustedToken<'_>) -> Result<Token<T>, ValidationError> {
+        match &self.key {
+            VerifyingKey::Hs512(key) => Hs512.validator(key).validate(token),
+            VerifyingKey::Rsa(rsa, key) => rsa.validator(key).validate(token),
+            VerifyingKey::Es256(key) => Es256.validator(key).validate(token),
+            VerifyingKey::EdDsa(key) => Ed25519.validator(key).validate(token),
+        }
+    }
+}
+
+fn read(path: &str) -> Result<String, KeyError> {
+    fs::read_to_string(path).map_err(|e| KeyError::Unreadable { path: path.to_string(), reason: e.to_string() })
+}
+
+fn invalid(path: &str, reason: impl ToString) -> KeyError {
+    KeyError::Invalid { path: path.to_string(), reason: reason.to_string() }
+}
+
+fn key_from_pem(config: &JwtKeyConfig) -> Result<Key, KeyError> {
+    let path = config.pem_file.as_str();
+    let pem = read(path)?;
+    let key = match config.algorithm {
+        JwtAlgorithm::Es256 => VerifyingKey::Es256(p256::ecdsa::VerifyingKey::from_public_key_pem(&pem).map_err(|e| invalid(path, e))?),
+        JwtAlgorithm::EdDSA => VerifyingKey::EdDsa(ed25519_dalek::VerifyingKey::from_public_key_pem(&pem).map_err(|e| invalid(path, e))?),
+        algorithm => {
+            let key = RsaPublicKey::from_public_key_pem(&pem)
+                .or_else(|_| RsaPublicKey::from_pkcs1_pem(&pem))
+                .map_err(|e| invalid(path, e))?;
+            VerifyingKey::Rsa(Rsa::with_name(algorithm.as_str()), key)
+        }
+    };
+    Ok(Key::new(config.kid.clone(), config.algorithm, key))
+}
+
+#[derive(Deserialize)]
+struct JwkSet {
+    keys: Vec<serde_json::Value>,
+}
+
+// Keys meant for encryption, or of a type we can't verify with, are skipped rather than failing the set
+fn keys_from_jwks(json: &str, path: &str) -> Result<Vec<Key>, KeyError> {
+    let set: JwkSet = serde_json::from_str(json).map_err(|e| invalid(path, e))?;
+    let mut keys = vec![];
+    for value in set.keys {
+        let field = |name: &str| value.get(name).and_then(serde_json::Value::as_str).map(str::to_string);
+        let kid = field("kid");
+        if field("use").is_some_and(|usage| usage != "sig") {
+            continue;
+        }
+        let json = value.to_string();
+        let jwk: JsonWebKey<'_> = serde_json::from_str(&json).map_err(|e| invalid(path, e))?;
+
+        let algorithm = match (field("alg"), &jwk) {
+            (Some(alg), _) => JwtAlgorithm::from_str(&alg).ok(),
+            (None, JsonWebKey::Rsa { .. }) => Some(JwtAlgorithm::Rs256),
+            (None, JsonWebKey::EllipticCurve { curve, .. }) if curve == "P-256" => Some(JwtAlgorithm::Es256),
+            (None, JsonWebKey::KeyPair { curve, .. }) if curve == "Ed25519" => Some(JwtAlgorithm::EdDSA),
+            _ => None,
+        };
+        let Some(algorithm) = algorithm else {
+            log::warn!("Skipping JWKS key {:?} in {}: unsupported key type or algorithm", kid, path);
+            continue;
+        };
+
+        let key = match algorithm {
+            JwtAlgorithm::Es256 => p256::ecdsa::VerifyingKey::try_from(&jwk).map(VerifyingKey::Es256).map_err(|e| invalid(path, e))?,
+            JwtAlgorithm::EdDSA => ed25519_dalek::VerifyingKey::try_from(&jwk).map(VerifyingKey::EdDsa).map_err(|e| invalid(path, e))?,
+            algorithm => {
+                let key = RsaPublicKey::try_from(&jwk).map_err(|e| invalid(path, e))?;
+                VerifyingKey::Rsa(Rsa::with_name(algorithm.as_str()), key)
+            }
+        };
+        keys.push(Key::new(kid, algorithm, key));
+    }
+    Ok(keys)
+}
+
+fn load_keys(config: &JwtConfig, shared_secret: Option<&str>) -> Result<Vec<Key>, KeyError> {
+    let mut keys = vec![];
+    if config.shared_secret {
+        let secret = shared_secret.ok_or(KeyError::MissingSharedSecret)?;
+        keys.push(Key {
+            kid: None,
+            algorithm: "HS512".to_string(),
+            key: VerifyingKey::Hs512(Hs512Key::new(secret.as_bytes())),
+        });
+    }
+    for key_config in &config.keys {
+        keys.push(key_from_pem(key_config)?);
+    }
+    if let Some(path) = &config.jwks_file {
+        keys.extend(keys_from_jwks(&read(path)?, path)?);
+    }
+    if keys.is_empty() {
+        return Err(KeyError::NoKeys);
+    }
+    Ok(keys)
+}
+
+// Every key access tokens may be signed with. Several can be live at once, picked by the token's
+// `kid` and `alg`, so a new key can be published before tokens signed with it show up.
+#[derive(Clone)]
+pub struct KeySet {
+    keys: Arc<RwLock<Vec<Key>>>,
+    time_options: TimeOptions,
+}
+
+impl KeySet {
+    fn new(keys: Vec<Key>) -> KeySet {
+        KeySet {
+            keys: Arc::new(RwLock::new(keys)),
+            time_options: TimeOptions::from_leeway(chrono::Duration::minutes(LEEWAY_MINUTES)),
+        }
+    }
+
+    pub fn load(config: &JwtConfig, shared_secret: Option<&str>) -> Result<KeySet, KeyError> {
+        let keys = load_keys(config, shared_secret)?;
+        log::info!("Loaded {} access token keys", keys.len());
+        Ok(KeySet::new(keys))
+    }
+
+    // A set published by someone else, such as an OIDC provider; `source` names it in errors
+    pub fn from_jwks(json: &str, source: &str) -> Result<KeySet, KeyError> {
+        let keys = keys_from_jwks(json, source)?;
+        if keys.is_empty() {
+            return Err(KeyError::NoKeys);
+        }
+        Ok(KeySet::new(keys))
+    }
+
+    // A reload that fails keeps the keys that were already loaded
+    pub fn spawn_reload(&self, config: JwtConfig, shared_secret: Option<String>) {
+        if config.reload_interval_secs == 0 || (config.keys.is_empty() && config.jwks_file.is_none()) {
+            return;
+        }
+        let key_set = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(Duration::from_secs(config.reload_interval_secs));
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                key_set.reload(&config, shared_secret.as_deref());
+            }
+        });
+    }
+
+    fn reload(&self, config: &JwtConfig, shared_secret: Option<&str>) {
+        match load_keys(config, shared_secret) {
+            Ok(keys) => *self.keys.write().unwrap() = keys,
+            Err(e) => log::error!("Failed to reload access token keys: {}", e),
+        }
+    }
+
+    pub fn verify<T: DeserializeOwned + Clone>(&self, token_string: &str) -> Result<T, TokenError> {
+        self.verify_with_expiry(token_string).map(|(custom, _)| custom)
+    }
+
+    // Also returns when the token stops verifying, leeway included, for callers that hold on to
+    // it past this request
+    pub fn verify_with_expiry<T: DeserializeOwned + Clone>(&self, token_string: &str) -> Result<(T, Option<DateTime<Utc>>), TokenError> {
+        let token = UntrustedToken::new(token_string).map_err(|_| TokenError::Invalid)?;
+        let keys = self.keys.read().unwrap();
+        let token: Token<T> = keys.iter()
+            .filter(|key| key.matches(&token))
+            .find_map(|key| key.validate(&token).ok())
+            .ok_or(TokenError::Invalid)?;
+        token.claims().validate_expiration(&self.time_options).map_err(|_| TokenError::Invalid)?;
+        let valid_until = token.claims().expiration.map(|expiration| expiration + self.time_options.leeway);
+        Ok((token.claims().custom.clone(), valid_until))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use ed25519_dalek::pkcs8::EncodePublicKey;
+    use jwt_compact::{jwk::JsonWebKey, Claims, Header};
+    use mongodb::bson::oid::ObjectId;
+    use rsa::{pkcs8::DecodePrivateKey, RsaPrivateKey};
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    const RSA_KEY: &str = include_str!("../fixtures/rs256-test-key.pem");
+
+    struct TempDir(PathBuf);
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn temp_dir() -> TempDir {
+        let dir = std::env::temp_dir().join(format!("retro-keys-test-{}", ObjectId::new()));
+        fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+
+    fn ed25519(seed: u8) -> ed25519_dalek::SigningKey {
+        ed25519_dalek::SigningKey::from_bytes(&[seed; 32])
+    }
+
+    fn ed25519_key(kid: Option<&str>, signing_key: &ed25519_dalek::SigningKey) -> Key {
+        Key::new(kid.map(str::to_string), JwtAlgorithm::EdDSA, VerifyingKey::EdDsa(signing_key.verifying_key()))
+    }
+
+    fn claims(expires_in: chrono::Duration) -> Claims<Value> {
+        let mut claims = Claims::new(json!({ "sub": "someone" }));
+        claims.expiration = Some(Utc::now() + expires_in);
+        claims
+    }
+
+    fn header(kid: Option<&str>) -> Header {
+        match kid {
+            Some(kid) => Header::empty().with_key_id(kid),
+            None => Header::empty(),
+        }
+    }
+
+    fn verifies(key_set: &KeySet, token: &str) -> bool {
+        key_set.verify::<Value>(token).is_ok()
+    }
+
+    #[test]
+    fn tokens_are_matched_to_keys_by_kid() {
+        let (current, next) = (ed25519(1), ed25519(2));
+        let key_set = KeySet::new(vec![ed25519_key(Some("current"), &current), ed25519_key(Some("next"), &next)]);
+        let sign = |kid: Option<&str>, signing_key: &ed25519_dalek::SigningKey| {
+            Ed25519.token(&header(kid), &claims(chrono::Duration::minutes(5)), signing_key).unwrap()
+        };
+
+        assert!(verifies(&key_set, &sign(Some("current"), &current)));
+        assert!(verifies(&key_set, &sign(Some("next"), &next)));
+        assert!(verifies(&key_set, &sign(None, &next)));
+        assert!(!verifies(&key_set, &sign(Some("retired"), &current)));
+        assert!(!verifies(&key_set, &sign(Some("next"), &current)));
+        assert!(!verifies(&key_set, &sign(None, &ed25519(3))));
+        assert!(matches!(key_set.verify::<Value>("not a token"), Err(TokenError::Invalid)));
+    }
+
+    // Someone holding the public key mustn't be able to use it as an HMAC secret
+    #[test]
+    fn public_keys_only_verify_their_own_algorithm() {
+        let private_key = RsaPrivateKey::from_pkcs8_pem(RSA_KEY).unwrap();
+        let public_key = private_key.to_public_key();
+        let public_pem = public_key.to_public_key_pem(Default::default()).unwrap();
+        let rsa_key = Key::new(Some("rsa".to_string()), JwtAlgorithm::Rs256, VerifyingKey::Rsa(Rsa::rs256(), public_key));
+        let shared_secret = Key { kid: None, algorithm: "HS512".to_string(), key: VerifyingKey::Hs512(Hs512Key::new(b"JWT_SECRET")) };
+
+        let signed = Rsa::rs256().token(&header(Some("rsa")), &claims(chrono::Duration::minutes(5)), &private_key).unwrap();
+        let forged = Hs512.token(&header(Some("rsa")), &claims(chrono::Duration::minutes(5)), &Hs512Key::new(public_pem.as_bytes())).unwrap();
+        for key_set in [KeySet::new(vec![rsa_key.clone()]), KeySet::new(vec![rsa_key, shared_secret])] {
+            assert!(verifies(&key_set, &signed));
+            assert!(!verifies(&key_set, &forged));
+        }
+    }
+
+    #[test]
+    fn expiry_allows_for_the_leeway() {
+        let signing_key = ed25519(1);
+        let key_set = KeySet::new(vec![ed25519_key(None, &signing_key)]);
+        let leeway = chrono::Duration::minutes(LEEWAY_MINUTES);
+
+        let lately_expired = claims(chrono::Duration::minutes(-5));
+        let token = Ed25519.token(&Header::empty(), &lately_expired, &signing_key).unwrap();
+        let (_, valid_until) = key_set.verify_with_expiry::<Value>(&token).unwrap();
+        // Tokens carry whole seconds
+        let expected = lately_expired.expiration.unwrap() + leeway;
+        assert_eq!(valid_until.map(|valid_until| valid_until.timestamp()), Some(expected.timestamp()));
+
+        let token = Ed25519.token(&Header::empty(), &claims(-leeway - chrono::Duration::minutes(1)), &signing_key).unwrap();
+        assert!(matches!(key_set.verify::<Value>(&token), Err(TokenError::Invalid)));
+    }
+
+    #[test]
+    fn jwks_keep_the_keys_they_can_verify_with() {
+        let signing_key = ed25519(1);
+        let private_key = RsaPrivateKey::from_pkcs8_pem(RSA_KEY).unwrap();
+        let mut ed25519_jwk = serde_json::to_value(JsonWebKey::from(&signing_key.verifying_key())).unwrap();
+        ed25519_jwk["kid"] = json!("ed");
+        let mut encryption_jwk = ed25519_jwk.clone();
+        encryption_jwk["use"] = json!("enc");
+        encryption_jwk["kid"] = json!("enc");
+        let mut rsa_jwk = serde_json::to_value(JsonWebKey::from(&private_key.to_public_key())).unwrap();
+        rsa_jwk["kid"] = json!("rsa");
+        let symmetric_jwk = json!({ "kty": "oct", "k": "c2VjcmV0", "kid": "oct" });
+
+        let jwks = json!({ "keys": [ed25519_jwk, encryption_jwk, rsa_jwk, symmetric_jwk] }).to_string();
+        let key_set = KeySet::from_jwks(&jwks, "test").unwrap();
+        let kids: Vec<_> = key_set.keys.read().unwrap().iter().map(|key| (key.kid.clone().unwrap(), key.algorithm.clone())).collect();
+        assert_eq!(kids, [("ed".to_string(), "EdDSA".to_string()), ("rsa".to_string(), "RS256".to_string())]);
+
+        let token = Rsa::rs256().token(&header(Some("rsa")), &claims(chrono::Duration::minutes(5)), &private_key).unwrap();
+        assert!(verifies(&key_set, &token));
+        let token = Ed25519.token(&header(Some("enc")), &claims(chrono::Duration::minutes(5)), &signing_key).unwrap();
+        assert!(!verifies(&key_set, &token));
+
+        let only_unusable = json!({ "keys": [symmetric_jwk] }).to_string();
+        assert!(matches!(KeySet::from_jwks(&only_unusable, "test"), Err(KeyError::NoKeys)));
+        assert!(matches!(KeySet::from_jwks("{}", "test"), Err(KeyError::Invalid { .. })));
+    }
+
+    #[test]
+    fn reloads_pick_up_rotated_keys() {
+        let dir = temp_dir();
+        let path = dir.0.join("key.pem");
+        let write_key = |signing_key: &ed25519_dalek::SigningKey| {
+            fs::write(&path, signing_key.verifying_key().to_public_key_pem(Default::default()).unwrap()).unwrap();
+        };
+        let config = JwtConfig {
+            shared_secret: false,
+            keys: vec![JwtKeyConfig { kid: None, algorithm: JwtAlgorithm::EdDSA, pem_file: path.to_str().unwrap().to_string() }],
+            ..JwtConfig::default()
+        };
+        let sign = |signing_key: &ed25519_dalek::SigningKey| Ed25519.token(&Header::empty(), &claims(chrono::Duration::minutes(5)), signing_key).unwrap();
+        let (old, new) = (ed25519(1), ed25519(2));
+
+        write_key(&old);
+        let key_set = KeySet::load(&config, None).unwrap();
+        assert!(verifies(&key_set, &sign(&old)));
+
+        write_key(&new);
+        key_set.reload(&config, None);
+        assert!(verifies(&key_set, &sign(&new)));
+        assert!(!verifies(&key_set, &sign(&old)));
+
+        // A broken file leaves the keys as they were
+        fs::write(&path, "not a key").unwrap();
+        key_set.reload(&config, None);
+        assert!(verifies(&key_set, &sign(&new)));
+        assert!(matches!(KeySet::load(&config, None), Err(KeyError::Invalid { .. })));
+    }
+}
@@ -0,0 +1,278 @@
+use std::{collections::HashMap, sync::{Arc, Mutex}};
+
+use async_trait::async_trait;
+use futures::{future::{BoxFuture, Shared}, FutureExt};
+use mongodb::bson::oid::ObjectId;
+
+use crate::database::{PersistenceError, PersistenceManager};
+use crate::models::{Retro, User};
+
+// Something a Loader can fetch many of in one round trip
+#[async_trait]
+pub trait Loadable: Clone + Send + Sync + 'static {
+    const NAME: &'static str;
+
+    fn id(&self) -> ObjectId;
+    async fn fetch(persistence_manager: &PersistenceManager, ids: &[ObjectId]) -> Result<Vec<Self>, PersistenceError>;
+}
+
+#[async_trait]
+impl Loadable for User {
+    const NAME: &'static str = "User";
+
+    fn id(&self) -> ObjectId {
+        self._id
+    }
+
+    async fn fetch(persistence_manager: &PersistenceManager, ids: &[ObjectId]) -> Result<Vec<Self>, PersistenceError> {
+        persistence_manager.get_users_by_ids(ids).await
+    }
+}
+
+#[async_trait]
+impl Loadable for Retro {
+    const NAME: &'static str = "Retro";
+
+    fn id(&self) -> ObjectId {
+        self._id
+    }
+
+    async fn fetch(persistence_manager: &PersistenceManager, ids: &[ObjectId]) -> Result<Vec<Self>, PersistenceError> {
+        persistence_manager.get_retros_by_ids(ids).await
+    }
+}
+
+type BatchResult<V> = Result<Arc<HashMap<ObjectId, V>>, PersistenceError>;
+type BatchFuture<V> = Shared<BoxFuture<'static, BatchResult<V>>>;
+
+// A batch collects ids until the first load waiting on it gets polled again, then fetches them all
+struct Batch<V: Loadable> {
+    // None once the batch has been sent
+    ids: Arc<Mutex<Option<Vec<ObjectId>>>>,
+    result: BatchFuture<V>,
+}
+
+struct LoaderState<V: Loadable> {
+    cache: HashMap<ObjectId, V>,
+    // The batch each id that is being loaded right now belongs to, so nothing is fetched twice
+    loading: HashMap<ObjectId, BatchFuture<V>>,
+    open_batch: Option<Batch<V>>,
+}
+
+// Coalesces the lookups sibling resolvers make concurrently into one persistence call. With
+// caching on, everything loaded is remembered for the lifetime of the context; subscription
+// contexts live as long as the connection, so they only batch.
+pub struct Loader<V: Loadable> {
+    persistence_manager: PersistenceManager,
+    cache_enabled: bool,
+    state: Mutex<LoaderState<V>>,
+}
+
+impl<V: Loadable> Loader<V> {
+    pub fn new(persistence_manager: PersistenceManager, cache_enabled: bool) -> Loader<V> {
+        Loader {
+            persistence_manager,
+            cache_enabled,
+            state: Mutex::new(LoaderState {
+                cache: HashMap::new(),
+                loading: HashMap::new(),
+                open_batch: None,
+            }),
+        }
+    }
+
+    pub async fn load(&self, id: ObjectId) -> Result<V, PersistenceError> {
+        let result = {
+            let mut state = self.state.lock().unwrap();
+            if let Some(value) = state.cache.get(&id) {
+                return Ok(value.clone());
+            }
+            match state.loading.get(&id) {
+                Some(batch) => batch.clone(),
+                None => {
+                    let batch = self.enqueue(&mut state, id);
+                    state.loading.insert(id, batch.clone());
+                    batch
+                }
+            }
+        };
+
+        // Let the other resolvers polled alongside this one add their ids first. Each load yields
+        // on its own: the batch is shared, so every sibling polling it would move it along
+        tokio::task::yield_now().await;
+        let values = result.await;
+        let mut state = self.state.lock().unwrap();
+        state.loading.remove(&id);
+        let value = values?.get(&id).cloned().ok_or(PersistenceError::NotFound(V::NAME))?;
+        if self.cache_enabled {
+            state.cache.insert(id, value.clone());
+        }
+        Ok(value)
+    }
+
+    // Writes go straight to persistence, so mutations refresh the cache with what they stored
+    pub fn prime(&self, value: &V) {
+        if self.cache_enabled {
+            self.state.lock().unwrap().cache.insert(value.id(), value.clone());
+        }
+    }
+
+    pub fn forget(&self, id: &ObjectId) {
+        self.state.lock().unwrap().cache.remove(id);
+    }
+
+    fn enqueue(&self, state: &mut LoaderState<V>, id: ObjectId) -> BatchFuture<V> {
+        if let Some(batch) = &state.open_batch {
+            if let Some(ids) = batch.ids.lock().unwrap().as_mut() {
+                ids.push(id);
+                return batch.result.clone();
+            }
+        }
+
+        let ids = Arc::new(Mutex::new(Some(vec![id])));
+        let pending = ids.clone();
+        let persistence_manager = self.persistence_manager.clone();
+        let result = async move {
+            let mut ids = pending.lock().unwrap().take().unwrap_or_default();
+            ids.sort();
+            ids.dedup();
+            let values = V::fetch(&persistence_manager, &ids).await?;
+            Ok(Arc::new(values.into_iter().map(|v| (v.id(), v)).collect()))
+        }.boxed().shared();
+
+        state.open_batch = Some(Batch { ids, result: result.clone() });
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::RwLock;
+
+    use super::*;
+    use crate::context::ContextBuilder;
+    use crate::events::EventBus;
+    use crate::models::SharedUsers;
+
+    // Every get_users_by_ids call a loader makes, across all tests; each test only looks at the
+    // calls for its own ids
+    static FETCHES: Mutex<Vec<Vec<ObjectId>>> = Mutex::new(Vec::new());
+
+    // A user whose persistence calls are recorded on the way through
+    #[derive(Clone, Debug)]
+    struct CountedUser(User);
+
+    #[async_trait]
+    impl Loadable for CountedUser {
+        const NAME: &'static str = "User";
+
+        fn id(&self) -> ObjectId {
+            self.0._id
+        }
+
+        async fn fetch(persistence_manager: &PersistenceManager, ids: &[ObjectId]) -> Result<Vec<Self>, PersistenceError> {
+            FETCHES.lock().unwrap().push(ids.to_vec());
+            Ok(persistence_manager.get_users_by_ids(ids).await?.into_iter().map(CountedUser).collect())
+        }
+    }
+
+    fn fetches_of(ids: &[ObjectId]) -> Vec<Vec<ObjectId>> {
+        FETCHES.lock().unwrap().iter().filter(|fetch| fetch.iter().any(|id| ids.contains(id))).cloned().collect()
+    }
+
+    fn user(name: &str) -> User {
+        User { _id: ObjectId::new(), username: name.to_string(), admin: false, guest: false, email: None, display_name: None }
+    }
+
+    fn memory(users: Vec<User>) -> (PersistenceManager, SharedUsers) {
+        let stored = Arc::new(RwLock::new(users.into_iter().map(|u| (u._id, u)).collect()));
+        (PersistenceManager::new_memory(Arc::new(RwLock::new(HashMap::new())), stored.clone()), stored)
+    }
+
+    #[tokio::test]
+    async fn sibling_loads_share_one_fetch() {
+        let (alice, bob, carol) = (user("alice"), user("bob"), user("carol"));
+        let missing = ObjectId::new();
+        let (persistence_manager, _) = memory(vec![alice.clone(), bob.clone(), carol.clone()]);
+        let loader = Loader::<CountedUser>::new(persistence_manager, true);
+
+        let (a, b, again, c, gone) = futures::join!(
+            loader.load(alice._id), loader.load(bob._id), loader.load(alice._id), loader.load(carol._id), loader.load(missing),
+        );
+        assert_eq!([a.unwrap().0.username, b.unwrap().0.username, again.unwrap().0.username, c.unwrap().0.username], ["alice", "bob", "alice", "carol"]);
+        assert!(matches!(gone, Err(PersistenceError::NotFound("User"))));
+
+        let mut expected = vec![alice._id, bob._id, carol._id, missing];
+        expected.sort();
+        assert_eq!(fetches_of(&expected), [expected.clone()]);
+
+        // A load that comes in once the batch is on its way gets a batch of its own
+        let (persistence_manager, _) = memory(vec![alice.clone(), bob.clone()]);
+        let loader = Loader::<CountedUser>::new(persistence_manager, true);
+        let first = loader.load(alice._id);
+        let late = async {
+            tokio::task::yield_now().await;
+            tokio::task::yield_now().await;
+            loader.load(bob._id).await
+        };
+        let (first, late) = futures::join!(first, late);
+        assert_eq!((first.unwrap().0.username, late.unwrap().0.username), ("alice".to_string(), "bob".to_string()));
+        // The batch from before, then one for each of these
+        assert_eq!(fetches_of(&[alice._id, bob._id]).len(), 3);
+    }
+
+    #[tokio::test]
+    async fn cached_loads_are_primed_and_forgotten() {
+        let alice = user("alice");
+        let (persistence_manager, stored) = memory(vec![alice.clone()]);
+        let loader = Loader::<CountedUser>::new(persistence_manager, true);
+
+        loader.load(alice._id).await.unwrap();
+        loader.load(alice._id).await.unwrap();
+        assert_eq!(fetches_of(&[alice._id]).len(), 1);
+
+        let renamed = User { username: "alicia".to_string(), ..alice.clone() };
+        stored.write().unwrap().insert(alice._id, renamed.clone());
+        loader.prime(&CountedUser(User { username: "primed".to_string(), ..alice.clone() }));
+        assert_eq!(loader.load(alice._id).await.unwrap().0.username, "primed");
+        assert_eq!(fetches_of(&[alice._id]).len(), 1);
+
+        loader.forget(&alice._id);
+        assert_eq!(loader.load(alice._id).await.unwrap().0.username, "alicia");
+        assert_eq!(fetches_of(&[alice._id]).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn uncached_loads_always_fetch() {
+        let alice = user("alice");
+        let (persistence_manager, _) = memory(vec![alice.clone()]);
+        let loader = Loader::<CountedUser>::new(persistence_manager, false);
+
+        loader.load(alice._id).await.unwrap();
+        loader.prime(&CountedUser(User { username: "primed".to_string(), ..alice.clone() }));
+        assert_eq!(loader.load(alice._id).await.unwrap().0.username, "alice");
+        assert_eq!(fetches_of(&[alice._id]).len(), 2);
+
+        // Still batched, just not remembered
+        let (first, second) = futures::join!(loader.load(alice._id), loader.load(alice._id));
+        assert!(first.is_ok() && second.is_ok());
+        assert_eq!(fetches_of(&[alice._id]).len(), 3);
+    }
+
+    // Subscription contexts see the changes made after they were built
+    #[tokio::test]
+    async fn subscription_contexts_do_not_cache() {
+        let alice = user("alice");
+        let (persistence_manager, stored) = memory(vec![alice.clone()]);
+        let builder = ContextBuilder::new(persistence_manager, EventBus::new_memory()).with_active_user(alice.clone());
+        let request = builder.clone().build();
+        let subscription = builder.without_load_cache().build();
+
+        for context in [&request, &subscription] {
+            assert_eq!(context.users.load(alice._id).await.unwrap().username, "alice");
+        }
+        stored.write().unwrap().insert(alice._id, User { username: "alicia".to_string(), ..alice.clone() });
+        assert_eq!(request.users.load(alice._id).await.unwrap().username, "alice");
+        assert_eq!(subscription.users.load(alice._id).await.unwrap().username, "alicia");
+    }
+}
@@ -3,36 +3,48 @@ mod schema;
 mod context;
 mod database;
 mod auth;
+mod events;
+mod errors;
+mod loaders;
+mod keys;
+mod oidc;
+mod guest;
+mod retention;
+mod ws;
 
 use std::{collections::HashMap, env, sync::{Arc, RwLock}, time::Duration};
 
 use actix_cors::Cors;
-use actix_jwt_auth_middleware::{use_jwt::UseJWTOnApp, Authority};
 use actix_web::{
-    error, middleware, web::{self, Data}, App, Error, HttpRequest, HttpResponse, HttpServer, Responder,
+    error, http::StatusCode, middleware, web::{self, Data}, App, Error, HttpRequest, HttpResponse, HttpServer, Responder,
 };
-use auth::Claims;
+use actix_ws::{CloseCode, CloseReason};
+use auth::{Claims, Credentials};
+use chrono::{DateTime, TimeDelta, Utc};
 use config::{Config, Environment, File, FileFormat};
 use dotenvy::dotenv;
 use juniper::InputValue;
-use jwt_compact::{prelude::*, alg::{Hs512, Hs512Key}};
+use jwt_compact::alg::Hs512Key;
 
 use context::ContextBuilder;
-use database::PersistenceManager;
-use juniper_actix::{graphiql_handler, graphql_handler, playground_handler, subscriptions};
+use database::{PersistenceError, PersistenceManager};
+use events::EventBus;
+use keys::KeySet;
+use juniper_actix::{graphiql_handler, graphql_handler, playground_handler};
 use juniper_graphql_ws::ConnectionConfig;
 
 use derive_more::derive::{Display, Error};
 
-use models::{ServiceMode, SharedRetros, SharedUsers, User};
+use models::{EventBusMode, RetroConfig, ServiceMode, SharedRetros, SharedUsers, User};
 use mongodb::bson::oid::ObjectId;
 use schema::{create_schema, Schema};
+use tokio::sync::oneshot;
 
-async fn playground() -> Result<HttpResponse, Error> {
+async fn playground(_claims: auth::Claims) -> Result<HttpResponse, Error> {
     playground_handler("/graphql", Some("/subscriptions")).await
 }
 
-async fn graphiql() -> Result<HttpResponse, Error> {
+async fn graphiql(_claims: auth::Claims) -> Result<HttpResponse, Error> {
     graphiql_handler("/graphql", Some("/subscriptions")).await
 }
 
@@ -41,12 +53,10 @@ async fn graphql(
     payload: web::Payload,
     schema: Data<Schema>,
     context: Data<ContextBuilder>,
-    claims: auth::Claims,
+    credentials: Credentials,
 ) -> Result<HttpResponse, Error> {
-    let context_builder = ContextBuilder::from_self(&context);
-    
-    let active_user = context_builder.persistence_manager.get_user(&claims.subject_id).await.unwrap();
-    let context = context_builder.with_active_user(active_user).build();
+    let (context, _) = authorize(context.get_ref().clone(), &credentials).await?;
+    let context = context.build();
     
     graphql_handler(&schema, &context, req, payload).await
 }
@@ -64,16 +74,199 @@ async fn homepage() -> impl Responder {
 
 #[derive(Debug, Display, Error)]
 enum ServiceError {
-    #[display("An internal error occurred. Please try again later.")]
+    #[display("The access token is missing, invalid or expired.")]
     AuthError,
+    #[display("The authenticated user does not exist.")]
+    UnknownUser,
+    #[display("The service is temporarily unavailable. Please try again later.")]
+    Unavailable,
+    #[display("Guest access to this retro has been turned off.")]
+    GuestAccessRevoked,
+    #[display("The API token is invalid, expired or revoked.")]
+    InvalidApiToken,
+}
+
+impl error::ResponseError for ServiceError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ServiceError::AuthError => StatusCode::UNAUTHORIZED,
+            ServiceError::UnknownUser => StatusCode::UNAUTHORIZED,
+            ServiceError::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ServiceError::GuestAccessRevoked => StatusCode::FORBIDDEN,
+            ServiceError::InvalidApiToken => StatusCode::UNAUTHORIZED,
+        }
+    }
+}
+
+// Creates the user on their first request and refreshes their profile when the claims change.
+// Without provisioning only users that are already stored get in, as they are stored.
+async fn load_active_user(context_builder: &ContextBuilder, claims: &Claims) -> Result<User, ServiceError> {
+    let persistence_manager = &context_builder.persistence_manager;
+    let user_id = &claims.subject_id;
+    let stored = match persistence_manager.get_user(user_id).await {
+        Ok(user) => Some(user),
+        // Guests only come into being by joining a retro
+        Err(PersistenceError::NotFound(_)) if claims.retro_id.is_some() => return Err(ServiceError::UnknownUser),
+        Err(PersistenceError::NotFound(_)) => None,
+        Err(e) => {
+            log::error!("Failed to load user {}: {}", user_id, e);
+            return Err(ServiceError::Unavailable);
+        }
+    };
+
+    if !context_builder.provision_users {
+        return stored.ok_or(ServiceError::UnknownUser);
+    }
+    let Some(profile) = claims.profile_update(stored.as_ref()) else {
+        // Already up to date, so there is nothing to write
+        return stored.ok_or(ServiceError::UnknownUser);
+    };
+    match persistence_manager.upsert_user(profile).await {
+        Ok(user) => {
+            if stored.is_none() {
+                log::info!("Created user {} ({}) from token claims", user.username, user._id);
+            }
+            Ok(user)
+        }
+        // A concurrent first request created them already
+        Err(PersistenceError::Conflict(_)) => persistence_manager.get_user(user_id).await.map_err(|e| {
+            log::error!("Failed to load user {}: {}", user_id, e);
+            ServiceError::Unavailable
+        }),
+        Err(e) => {
+            log::error!("Failed to save the profile of user {}: {}", user_id, e);
+            Err(ServiceError::Unavailable)
+        }
+    }
+}
+
+// Along with when the credentials stop being good, for connections that outlive a request
+async fn authorize(context_builder: ContextBuilder, credentials: &Credentials) -> Result<(ContextBuilder, Option<DateTime<Utc>>), ServiceError> {
+    match credentials {
+        Credentials::Jwt(claims, valid_until) => Ok((authorize_claims(context_builder, claims).await?, *valid_until)),
+        Credentials::ApiToken(token) => authorize_api_token(context_builder, token).await,
+    }
 }
 
-impl error::ResponseError for ServiceError {}
+// API tokens act as the user who created them, within the token's scopes
+async fn authorize_api_token(context_builder: ContextBuilder, token: &str) -> Result<(ContextBuilder, Option<DateTime<Utc>>), ServiceError> {
+    let persistence_manager = &context_builder.persistence_manager;
+    let api_token = match persistence_manager.get_api_token(&auth::hash_api_token(token)).await {
+        Ok(api_token) if !api_token.is_expired() => api_token,
+        Ok(_) | Err(PersistenceError::NotFound(_)) => return Err(ServiceError::InvalidApiToken),
+        Err(e) => {
+            log::error!("Failed to look up an API token: {}", e);
+            return Err(ServiceError::Unavailable);
+        }
+    };
+    let user = match persistence_manager.get_user(&api_token.user_id).await {
+        Ok(user) => user,
+        Err(PersistenceError::NotFound(_)) => return Err(ServiceError::UnknownUser),
+        Err(e) => {
+            log::error!("Failed to load user {}: {}", api_token.user_id, e);
+            return Err(ServiceError::Unavailable);
+        }
+    };
+    let expires_at = api_token.expires_at.as_deref()
+        .and_then(|expires_at| DateTime::parse_from_rfc3339(expires_at).ok())
+        .map(|expires_at| expires_at.with_timezone(&Utc));
+    Ok((context_builder.with_active_user(user).with_api_scopes(api_token.scopes), expires_at))
+}
 
-fn validate_access_token(token_string: &str, public_key: &Hs512Key) -> Result<Claims, ServiceError> {
-    let token = UntrustedToken::new(token_string).map_err(|_| ServiceError::AuthError)?;
-    let token: Token<auth::Claims> = Hs512.validator(public_key).validate(&token).map_err(|_| ServiceError::AuthError)?;
-    Ok(token.claims().custom.clone())
+// Guests stay scoped to their retro, and lose access as soon as its facilitator turns guests off
+async fn authorize_claims(context_builder: ContextBuilder, claims: &Claims) -> Result<ContextBuilder, ServiceError> {
+    let user = load_active_user(&context_builder, claims).await?;
+    if user.guest && claims.retro_id.is_none() {
+        return Err(ServiceError::UnknownUser);
+    }
+    if let Some(retro_id) = &claims.retro_id {
+        let retro = match context_builder.persistence_manager.get_retro(retro_id).await {
+            Ok(retro) => retro,
+            Err(PersistenceError::NotFound(_)) => return Err(ServiceError::GuestAccessRevoked),
+            Err(e) => {
+                log::error!("Failed to load retro {} for a guest: {}", retro_id, e);
+                return Err(ServiceError::Unavailable);
+            }
+        };
+        if retro.guest_invite.is_none() {
+            return Err(ServiceError::GuestAccessRevoked);
+        }
+    }
+    Ok(context_builder.with_active_user(user).with_retro_scope(claims.retro_id))
+}
+
+fn validate_access_token(token_string: &str, key_set: &KeySet) -> Result<Credentials, ServiceError> {
+    Credentials::parse(token_string, key_set).map_err(|_| ServiceError::AuthError)
+}
+
+// The close codes graphql-transport-ws uses for failed auth, so clients know to sign in again
+fn session_lapsed(error: &ServiceError) -> Option<CloseReason> {
+    match error {
+        ServiceError::AuthError | ServiceError::UnknownUser | ServiceError::InvalidApiToken => {
+            Some(CloseReason { code: CloseCode::Other(4401), description: Some("Unauthorized".to_string()) })
+        }
+        ServiceError::GuestAccessRevoked => {
+            Some(CloseReason { code: CloseCode::Other(4403), description: Some("Forbidden".to_string()) })
+        }
+        ServiceError::Unavailable => None,
+    }
+}
+
+// How long a connection waits before checking its credentials again: until they expire, or the
+// recheck interval if that comes first, and never less than a second
+fn recheck_delay(expires_at: Option<DateTime<Utc>>, recheck: TimeDelta, now: DateTime<Utc>) -> Duration {
+    let wait = expires_at.map_or(recheck, |expires_at| (expires_at - now).min(recheck));
+    wait.max(TimeDelta::seconds(1)).to_std().unwrap_or_default()
+}
+
+// Checks a connection's credentials the way its first request was, returning when they next
+// need checking, or the reason to close it once they've lapsed. Checks that can't be made right
+// now keep the connection open and the previous expiry.
+async fn recheck_session(
+    token_string: &str,
+    expires_at: Option<DateTime<Utc>>,
+    context_builder: &ContextBuilder,
+    key_set: &KeySet,
+) -> Result<Option<DateTime<Utc>>, CloseReason> {
+    let checked = match validate_access_token(token_string, key_set) {
+        Ok(credentials) => authorize(context_builder.clone(), &credentials).await.map(|(_, expires_at)| expires_at),
+        Err(e) => Err(e),
+    };
+    match checked {
+        Ok(next_expiry) => Ok(next_expiry),
+        Err(e) => match session_lapsed(&e) {
+            Some(reason) => {
+                log::info!("Closing a subscription connection whose credentials lapsed: {:?}", e);
+                Err(reason)
+            }
+            None => {
+                log::warn!("Couldn't recheck a subscription connection, keeping it open: {:?}", e);
+                Ok(expires_at)
+            }
+        },
+    }
+}
+
+// Resolves once the credentials a connection was opened with stop being good: right as they
+// expire, or at the first recheck after the token is revoked or the user removed
+async fn watch_session(
+    authorized: oneshot::Receiver<(String, Option<DateTime<Utc>>)>,
+    context_builder: ContextBuilder,
+    key_set: Data<KeySet>,
+    recheck: Duration,
+) -> CloseReason {
+    let Ok((token_string, mut expires_at)) = authorized.await else {
+        // The connection never got past init, so there's nothing to watch
+        return std::future::pending().await;
+    };
+    let recheck = TimeDelta::from_std(recheck).unwrap_or(TimeDelta::MAX);
+    loop {
+        actix_web::rt::time::sleep(recheck_delay(expires_at, recheck, Utc::now())).await;
+        match recheck_session(&token_string, expires_at, &context_builder, &key_set).await {
+            Ok(next_expiry) => expires_at = next_expiry,
+            Err(reason) => return reason,
+        }
+    }
 }
 
 async fn subscriptions(
@@ -81,23 +274,32 @@ async fn subscriptions(
     stream: web::Payload,
     schema: Data<Schema>,
     context: Data<ContextBuilder>,
-    public_key: Data<Hs512Key>
+    key_set: Data<KeySet>,
+    retro_config: Data<RetroConfig>,
 ) -> Result<HttpResponse, Error> {
     let schema = schema.into_inner();
+    // Clients that can't put the token in the init payload send it with the upgrade, like on the HTTP routes
+    let upgrade_token = auth::upgrade_access_token(&req);
+    let (authorized_tx, authorized_rx) = oneshot::channel();
+    let lapsed = watch_session(
+        authorized_rx,
+        context.get_ref().clone(),
+        key_set.clone(),
+        Duration::from_secs(retro_config.subscription_recheck_secs),
+    );
 
-    subscriptions::ws_handler(req, stream, schema, async move |payload: HashMap<String, InputValue>| {
-        // handle_connection_init(payload).await
-        let context_builder = ContextBuilder::from_self(&context);
-        if let Some(access_token) = payload.get("access_token") {
-            let token_string = access_token.as_string_value().ok_or(ServiceError::AuthError)?;
-            let claims = validate_access_token(token_string, &public_key.into_inner())?;
-            let user = context_builder.persistence_manager.get_user(&claims.subject_id).await.unwrap();
-            let new_context = context_builder.with_active_user(user).build();
-            Ok(ConnectionConfig::new(new_context).with_keep_alive_interval(Duration::from_secs(15)))
-        } else {
-            Err(ServiceError::AuthError)
-        }
-    }).await
+    ws::handler(req, stream, schema, async move |payload: HashMap<String, InputValue>| {
+        let context_builder = context.get_ref().clone();
+        let token_string = match payload.get(auth::ACCESS_TOKEN_NAME) {
+            Some(access_token) => access_token.as_string_value().ok_or(ServiceError::AuthError)?.to_string(),
+            None => upgrade_token.ok_or(ServiceError::AuthError)?,
+        };
+        let credentials = validate_access_token(&token_string, &key_set)?;
+        let (context_builder, expires_at) = authorize(context_builder, &credentials).await?;
+        // Only fails once the connection is already gone
+        let _ = authorized_tx.send((token_string, expires_at));
+        Ok::<_, ServiceError>(ConnectionConfig::new(context_builder.without_load_cache().build()).with_keep_alive_interval(Duration::from_secs(15)))
+    }, lapsed).await
 }
 
 #[actix_web::main]
@@ -115,69 +317,413 @@ async fn main() -> std::io::Result<()> {
     let service_config: models::ServiceConfig = conf.try_deserialize().expect("Failed to deserialize config");
     let retro_config = service_config.retro.clone().unwrap_or_default();
 
-    let jwt_secret = env::var("JWT_SECRET").expect("JWT_SECRET must be set");
-    let secret_key = Hs512Key::new(jwt_secret.as_bytes());
+    // Only needed while HS512 tokens are accepted or local auth signs them
+    let jwt_secret = env::var("JWT_SECRET").ok();
+    let key_set = KeySet::load(&retro_config.jwt, jwt_secret.as_deref()).expect("Failed to load access token keys");
+    key_set.spawn_reload(retro_config.jwt.clone(), jwt_secret.clone());
 
     let retros: SharedRetros = Arc::new(RwLock::new(HashMap::new()));
-    let default_users = HashMap::from([(ObjectId::new(), User {
+    let admin = User {
         _id: ObjectId::new(),
         username: "admin".to_string(),
-    })]);
+        admin: true,
+        guest: false,
+        email: None,
+        display_name: None,
+    };
+    // With local auth, ADMIN_PASSWORD registers the admin so it can log in like anyone else
+    let admin_password = retro_config.local_auth.as_ref().and_then(|_| env::var("ADMIN_PASSWORD").ok());
+    let default_users = match admin_password {
+        Some(_) => HashMap::new(),
+        None => HashMap::from([(admin._id, admin.clone())]),
+    };
     let users: SharedUsers = Arc::new(RwLock::new(default_users));
 
     println!("Starting server in mode: {:?}", retro_config.mode);
 
     let persistence_manager: PersistenceManager  = match retro_config.mode {
-        ServiceMode::Memory => {
-            database::PersistenceManager::new_memory(retros.clone(), users.clone())
-        }
+        ServiceMode::Memory => match &retro_config.snapshot_dir {
+            Some(dir) => {
+                let interval = Duration::from_secs(retro_config.snapshot_interval_secs);
+                database::PersistenceManager::new_snapshotted_memory(retros.clone(), users.clone(), dir, interval).expect("Failed to load memory snapshots")
+            }
+            None => database::PersistenceManager::new_memory(retros.clone(), users.clone()),
+        },
         ServiceMode::Mongo => {
-            database::PersistenceManager::new_mongo(&service_config).await
+            database::PersistenceManager::new_mongo(&service_config).await.expect("Failed to connect to MongoDB")
+        }
+        ServiceMode::Sqlite => {
+            database::PersistenceManager::new_sqlite(&retro_config.sqlite_path).await.expect("Failed to open SQLite database")
+        }
+        ServiceMode::Postgres => {
+            database::PersistenceManager::new_postgres(&service_config).await.expect("Failed to connect to Postgres")
+        }
+    };
+
+    let persistence_manager = match &retro_config.cache {
+        Some(cache_config) => persistence_manager.with_cache(cache_config).expect("Failed to set up the retro cache"),
+        None => persistence_manager,
+    };
+
+    // `backend migrate` upgrades every stored retro to the current schema and exits
+    if env::args().nth(1).as_deref() == Some("migrate") {
+        let migrated = persistence_manager.migrate().await.expect("Failed to migrate stored retros");
+        println!("Migrated {} retros to schema version {}", migrated, database::migrations::CURRENT_SCHEMA_VERSION);
+        return Ok(());
+    }
+
+    if let (ServiceMode::Memory, Some(password)) = (&retro_config.mode, admin_password) {
+        let password_hash = auth::hash_password(&password).expect("Failed to hash ADMIN_PASSWORD");
+        match persistence_manager.create_user(admin, password_hash).await {
+            Ok(admin) => println!("Registered local user {:?}", admin.username),
+            // Restored from a snapshot, along with whatever password it had then
+            Err(PersistenceError::Conflict(_)) => println!("Keeping the existing admin user"),
+            Err(e) => panic!("Failed to register the admin user: {}", e),
+        }
+    }
+
+    println!("Starting event bus in mode: {:?}", retro_config.event_bus);
+
+    let event_bus = match retro_config.event_bus {
+        EventBusMode::Memory => EventBus::new_memory(),
+        EventBusMode::Redis => {
+            let redis_config = service_config.redis.clone().expect("redis config must be set for the Redis event bus");
+            EventBus::new_redis(&redis_config).await.expect("Failed to start Redis event bus")
         }
     };
 
+    if let Some(retention_config) = retro_config.retention.clone() {
+        retention::spawn(persistence_manager.clone(), event_bus.clone(), retention_config).expect("Invalid retention config");
+    }
+
     let schema = Arc::new(create_schema());
 
-    let context = Arc::new(ContextBuilder::new(persistence_manager));
+    // Local auth, OIDC and guest joins all end with this service signing the tokens
+    let token_issuer = (retro_config.local_auth.is_some() || retro_config.oidc.is_some() || retro_config.guests.is_some()).then(|| {
+        assert!(retro_config.jwt.shared_secret, "logins are signed with JWT_SECRET, so jwt.shared_secret must stay on");
+        let jwt_secret = jwt_secret.clone().expect("JWT_SECRET must be set for local auth, OIDC and guests");
+        // Refresh tokens get their own secret so a leaked access token key can't mint them
+        let refresh_key = retro_config.tokens.refresh.as_ref().map(|_| {
+            let refresh_secret = env::var("JWT_REFRESH_SECRET").expect("JWT_REFRESH_SECRET must be set for refresh tokens");
+            assert!(refresh_secret != jwt_secret, "JWT_REFRESH_SECRET must differ from JWT_SECRET");
+            Hs512Key::new(refresh_secret.as_bytes())
+        });
+        Arc::new(auth::TokenIssuer {
+            config: retro_config.tokens.clone(),
+            signing_key: Hs512Key::new(jwt_secret.as_bytes()),
+            login_key: auth::derived_key(&jwt_secret, "oidc-login"),
+            refresh_key,
+            persistence_manager: persistence_manager.clone(),
+        })
+    });
+
+    let local_auth = retro_config.local_auth.clone().zip(token_issuer.clone()).map(|(config, tokens)| {
+        println!("Serving local register and login endpoints");
+        Arc::new(auth::LocalAuth { config, tokens })
+    });
+
+    let oidc = retro_config.oidc.clone().zip(token_issuer.clone()).map(|(config, tokens)| {
+        println!("Serving OIDC login through {}", config.issuer);
+        let client_secret = env::var("OIDC_CLIENT_SECRET").ok();
+        Arc::new(oidc::Oidc::new(config, client_secret, tokens).expect("Failed to set up the OIDC client"))
+    });
+
+    let guests = retro_config.guests.clone().zip(token_issuer.clone()).map(|(config, tokens)| {
+        println!("Serving guest joins");
+        Arc::new(guest::Guests { config, tokens })
+    });
+
+    // A users collection of another service's is only ever read from
+    let provision_users = match retro_config.mode {
+        ServiceMode::Mongo => service_config.db.as_ref().is_none_or(models::DbConfig::provisions_users),
+        _ => true,
+    };
+    if !provision_users {
+        println!("Not provisioning users from token claims");
+    }
+    let context = Arc::new(ContextBuilder::new(persistence_manager, event_bus).with_user_provisioning(provision_users));
     
     let address = format!("0.0.0.0:{}", retro_config.port);
 
     HttpServer::new(move || {
-        let authority = Authority::<auth::Claims, Hs512, _, _>::new()
-            .refresh_authorizer(|| async move { Err(ServiceError::AuthError.into()) })
-            .algorithm(Hs512)
-            .time_options(TimeOptions::from_leeway(chrono::Duration::minutes(15)))
-            .enable_header_tokens(true)
-            .enable_cookie_tokens(false)
-            .access_token_name("access_token")
-            .verifying_key(secret_key.clone())
-            .build()
-            .expect("");
-
         App::new()
             .app_data(Data::from(retros.clone()))
             .app_data(Data::from(schema.clone()))
             .app_data(Data::from(context.clone()))
-            .app_data(Data::new(secret_key.clone()))
+            .app_data(Data::new(key_set.clone()))
+            .app_data(Data::new(retro_config.clone()))
             .wrap(
                 Cors::permissive()
             )
             .wrap(middleware::Compress::default())
             .wrap(middleware::Logger::default())
+            .configure(|cfg| {
+                if let Some(token_issuer) = &token_issuer {
+                    cfg.app_data(Data::from(token_issuer.clone()));
+                    if token_issuer.refresh_key.is_some() {
+                        auth::configure_refresh(cfg);
+                    }
+                }
+                if let Some(local_auth) = &local_auth {
+                    cfg.app_data(Data::from(local_auth.clone()));
+                    auth::configure(cfg);
+                }
+                if let Some(oidc) = &oidc {
+                    cfg.app_data(Data::from(oidc.clone()));
+                    oidc::configure(cfg);
+                }
+                if let Some(guests) = &guests {
+                    cfg.app_data(Data::from(guests.clone()));
+                    guest::configure(cfg);
+                }
+            })
             .service(web::resource("/api/v1/retro/subscriptions").route(web::get().to(subscriptions)))
             .service(web::resource("/api/v1/retro").route(web::get().to(homepage)))
-            .use_jwt(authority, web::scope("")
-                .service(
-                    web::resource("/api/v1/retro/graphql")
-                        .route(web::post().to(graphql))
-                        .route(web::get().to(graphql)),
-                )
-                .service(web::resource("/api/v1/retro/playground").route(web::get().to(playground)))
-                .service(web::resource("/api/v1/retro/graphiql").route(web::get().to(graphiql)))
-                )
+            .service(
+                web::resource("/api/v1/retro/graphql")
+                    .route(web::post().to(graphql))
+                    .route(web::get().to(graphql)),
+            )
+            .service(web::resource("/api/v1/retro/playground").route(web::get().to(playground)))
+            .service(web::resource("/api/v1/retro/graphiql").route(web::get().to(graphiql)))
             .default_service(web::to(homepage))
     })
     .bind(address)?
     .run()
     .await
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use jwt_compact::{alg::Hs512, AlgorithmExt, Header};
+
+    use super::*;
+    use crate::models::{Retro, RetroStep};
+
+    fn memory() -> ContextBuilder {
+        let persistence_manager = PersistenceManager::new_memory(Arc::new(RwLock::new(HashMap::new())), Arc::new(RwLock::new(HashMap::new())));
+        ContextBuilder::new(persistence_manager, EventBus::new_memory())
+    }
+
+    fn claims(subject_id: ObjectId, retro_id: Option<ObjectId>) -> Claims {
+        Claims { subject_id, retro_id, username: None, email: None, display_name: None }
+    }
+
+    async fn guest_retro(builder: &ContextBuilder) -> Retro {
+        let retro = Retro {
+            _id: ObjectId::new(),
+            retro_name: "Sprint 1".to_string(),
+            creator_id: ObjectId::new(),
+            step: RetroStep::Writing,
+            created_at: Utc::now().to_rfc3339(),
+            participants: vec![],
+            lanes: vec![],
+            version: 0,
+            archived_at: None,
+            schema_version: database::migrations::CURRENT_SCHEMA_VERSION,
+            guest_invite: Some("invite".to_string()),
+        };
+        builder.persistence_manager.create_retro(retro).await.unwrap()
+    }
+
+    async fn guest(builder: &ContextBuilder) -> User {
+        let guest = User { _id: ObjectId::new(), username: "guest".to_string(), admin: false, guest: true, email: None, display_name: None };
+        builder.persistence_manager.create_guest(guest).await.unwrap()
+    }
+
+    // Memory mode journals every write, so the journal's length tells whether one happened
+    struct Journaled {
+        dir: std::path::PathBuf,
+        builder: ContextBuilder,
+    }
+
+    impl Journaled {
+        fn new() -> Journaled {
+            let dir = std::env::temp_dir().join(format!("retro-main-test-{}", ObjectId::new()));
+            let persistence_manager = PersistenceManager::new_snapshotted_memory(
+                Arc::new(RwLock::new(HashMap::new())), Arc::new(RwLock::new(HashMap::new())), dir.to_str().unwrap(), Duration::from_secs(3600),
+            ).unwrap();
+            Journaled { dir, builder: ContextBuilder::new(persistence_manager, EventBus::new_memory()) }
+        }
+
+        fn writes(&self) -> usize {
+            std::fs::read_to_string(self.dir.join("journal.jsonl")).unwrap_or_default().lines().count()
+        }
+    }
+
+    impl Drop for Journaled {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[tokio::test]
+    async fn users_are_only_written_when_their_claims_change() {
+        let journaled = Journaled::new();
+        let mut claims = Claims { username: Some("alice".to_string()), ..claims(ObjectId::new(), None) };
+
+        let created = load_active_user(&journaled.builder, &claims).await.unwrap();
+        assert_eq!((created._id, created.username.as_str()), (claims.subject_id, "alice"));
+        assert_eq!(journaled.writes(), 1);
+
+        let loaded = load_active_user(&journaled.builder, &claims).await.unwrap();
+        assert_eq!(loaded, created);
+        claims.username = None;
+        assert_eq!(load_active_user(&journaled.builder, &claims).await.unwrap(), created);
+        assert_eq!(journaled.writes(), 1);
+
+        claims.email = Some("alice@example.com".to_string());
+        let updated = load_active_user(&journaled.builder, &claims).await.unwrap();
+        assert_eq!(updated.email.as_deref(), Some("alice@example.com"));
+        assert_eq!(journaled.writes(), 2);
+    }
+
+    #[tokio::test]
+    async fn without_provisioning_users_are_read_as_stored() {
+        let journaled = Journaled::new();
+        let alice = Claims { username: Some("alice".to_string()), ..claims(ObjectId::new(), None) };
+        let stored = load_active_user(&journaled.builder, &alice).await.unwrap();
+        let builder = journaled.builder.clone().with_user_provisioning(false);
+
+        let renamed = Claims { username: Some("alicia".to_string()), email: Some("alice@example.com".to_string()), ..alice.clone() };
+        assert_eq!(load_active_user(&builder, &renamed).await.unwrap(), stored);
+        let stranger = Claims { username: Some("mallory".to_string()), ..claims(ObjectId::new(), None) };
+        assert!(matches!(load_active_user(&builder, &stranger).await, Err(ServiceError::UnknownUser)));
+        assert_eq!(journaled.writes(), 1);
+    }
+
+    async fn stored_api_token(builder: &ContextBuilder, user_id: ObjectId, expires_at: Option<DateTime<Utc>>) -> (String, models::ApiToken) {
+        let token = auth::new_api_token();
+        let api_token = models::ApiToken {
+            _id: ObjectId::new(),
+            user_id,
+            name: "ci".to_string(),
+            token_hash: auth::hash_api_token(&token),
+            scopes: vec![models::ApiTokenScope::WriteCards],
+            created_at: Utc::now().to_rfc3339(),
+            expires_at: expires_at.map(|expires_at| expires_at.to_rfc3339()),
+        };
+        builder.persistence_manager.create_api_token(api_token.clone()).await.unwrap();
+        (token, api_token)
+    }
+
+    #[tokio::test]
+    async fn revoked_or_expired_api_tokens_are_turned_away() {
+        let builder = memory();
+        let owner = load_active_user(&builder, &claims(ObjectId::new(), None)).await.unwrap();
+        let expiry = Utc::now() + TimeDelta::days(1);
+        let (token, api_token) = stored_api_token(&builder, owner._id, Some(expiry)).await;
+
+        let (authorized, expires_at) = authorize_api_token(builder.clone(), &token).await.unwrap();
+        assert_eq!(authorized.active_user.map(|user| user._id), Some(owner._id));
+        assert_eq!(authorized.api_scopes, Some(vec![models::ApiTokenScope::WriteCards]));
+        assert_eq!(expires_at, Some(expiry));
+
+        assert!(builder.persistence_manager.delete_api_token(&owner._id, &api_token._id).await.unwrap());
+        let revoked = authorize_api_token(builder.clone(), &token).await.err().unwrap();
+        assert!(matches!(revoked, ServiceError::InvalidApiToken));
+        assert_eq!(error::ResponseError::status_code(&revoked), StatusCode::UNAUTHORIZED);
+
+        let (expired, _) = stored_api_token(&builder, owner._id, Some(Utc::now() - TimeDelta::seconds(1))).await;
+        assert!(matches!(authorize_api_token(builder.clone(), &expired).await, Err(ServiceError::InvalidApiToken)));
+        assert!(matches!(authorize_api_token(builder.clone(), &auth::new_api_token()).await, Err(ServiceError::InvalidApiToken)));
+    }
+
+    const JWT_SECRET: &str = "test secret";
+
+    fn key_set() -> KeySet {
+        KeySet::load(&models::JwtConfig::default(), Some(JWT_SECRET)).unwrap()
+    }
+
+    fn access_token(claims: Claims, expires_at: DateTime<Utc>) -> String {
+        let mut claims = jwt_compact::Claims::new(claims);
+        claims.expiration = Some(expires_at);
+        Hs512.token(&Header::empty(), &claims, &Hs512Key::new(JWT_SECRET.as_bytes())).unwrap()
+    }
+
+    fn close_code(result: Result<Option<DateTime<Utc>>, CloseReason>) -> Option<CloseCode> {
+        result.err().map(|reason| reason.code)
+    }
+
+    #[test]
+    fn rechecks_wait_for_expiry_or_the_interval() {
+        let now = Utc::now();
+        let recheck = TimeDelta::minutes(5);
+        assert_eq!(recheck_delay(None, recheck, now), Duration::from_secs(300));
+        assert_eq!(recheck_delay(Some(now + TimeDelta::minutes(2)), recheck, now), Duration::from_secs(120));
+        assert_eq!(recheck_delay(Some(now + TimeDelta::hours(2)), recheck, now), Duration::from_secs(300));
+        // Already expired, which the next check will find
+        assert_eq!(recheck_delay(Some(now - TimeDelta::minutes(2)), recheck, now), Duration::from_secs(1));
+        assert_eq!(recheck_delay(Some(now + TimeDelta::minutes(2)), TimeDelta::MAX, now), Duration::from_secs(120));
+        assert!(recheck_delay(None, TimeDelta::MAX, now) > Duration::from_secs(3600 * 24 * 365));
+    }
+
+    #[tokio::test]
+    async fn lapsed_sessions_close_with_the_matching_code() {
+        let builder = memory();
+        let key_set = key_set();
+        let unauthorized = Some(CloseCode::Other(4401));
+
+        let user = load_active_user(&builder, &claims(ObjectId::new(), None)).await.unwrap();
+        let expiry = Utc::now() + TimeDelta::minutes(5);
+        let token = access_token(claims(user._id, None), expiry);
+        let next_expiry = recheck_session(&token, None, &builder, &key_set).await.unwrap();
+        assert_eq!(next_expiry.map(|expires_at| expires_at.timestamp()), Some((expiry + TimeDelta::minutes(15)).timestamp()));
+
+        // Past the leeway the token no longer verifies
+        let expired = access_token(claims(user._id, None), Utc::now() - TimeDelta::minutes(16));
+        assert_eq!(close_code(recheck_session(&expired, None, &builder, &key_set).await), unauthorized);
+
+        let (api_token, stored) = stored_api_token(&builder, user._id, None).await;
+        assert_eq!(recheck_session(&api_token, None, &builder, &key_set).await, Ok(None));
+        builder.persistence_manager.delete_api_token(&user._id, &stored._id).await.unwrap();
+        assert_eq!(close_code(recheck_session(&api_token, None, &builder, &key_set).await), unauthorized);
+
+        let retro = guest_retro(&builder).await;
+        let guest = guest(&builder).await;
+        let guest_token = access_token(claims(guest._id, Some(retro._id)), expiry);
+        assert!(recheck_session(&guest_token, None, &builder, &key_set).await.is_ok());
+        builder.persistence_manager.set_guest_invite(&retro._id, None, None).await.unwrap();
+        assert_eq!(close_code(recheck_session(&guest_token, None, &builder, &key_set).await), Some(CloseCode::Other(4403)));
+    }
+
+    #[tokio::test]
+    async fn watched_sessions_close_at_the_first_recheck_after_revocation() {
+        let builder = memory();
+        let retro = guest_retro(&builder).await;
+        let guest = guest(&builder).await;
+        let token = access_token(claims(guest._id, Some(retro._id)), Utc::now() + TimeDelta::hours(1));
+
+        let (authorized_tx, authorized_rx) = oneshot::channel();
+        let watch = watch_session(authorized_rx, builder.clone(), Data::new(key_set()), Duration::from_secs(1));
+        authorized_tx.send((token, None)).unwrap();
+        builder.persistence_manager.set_guest_invite(&retro._id, None, None).await.unwrap();
+
+        let reason = tokio::time::timeout(Duration::from_secs(5), watch).await.unwrap();
+        assert_eq!(reason.code, CloseCode::Other(4403));
+    }
+
+    #[tokio::test]
+    async fn guest_tokens_lapse_with_guest_access() {
+        let builder = memory();
+        let retro = guest_retro(&builder).await;
+        let guest = guest(&builder).await;
+        let guest_claims = claims(guest._id, Some(retro._id));
+
+        let authorized = authorize_claims(builder.clone(), &guest_claims).await.unwrap();
+        assert_eq!(authorized.retro_scope, Some(retro._id));
+        assert_eq!(authorized.active_user.map(|user| user._id), Some(guest._id));
+
+        // Guests never get past their retro, even when presenting a token without one
+        assert!(matches!(authorize_claims(builder.clone(), &claims(guest._id, None)).await, Err(ServiceError::UnknownUser)));
+        assert!(matches!(authorize_claims(builder.clone(), &claims(ObjectId::new(), Some(retro._id))).await, Err(ServiceError::UnknownUser)));
+
+        builder.persistence_manager.set_guest_invite(&retro._id, None, None).await.unwrap();
+        let revoked = authorize_claims(builder.clone(), &guest_claims).await.err().unwrap();
+        assert!(matches!(revoked, ServiceError::GuestAccessRevoked));
+        assert_eq!(error::ResponseError::status_code(&revoked), StatusCode::FORBIDDEN);
+
+        let deleted = guest_retro(&builder).await;
+        builder.persistence_manager.delete_retro(&deleted._id, None).await.unwrap();
+        assert!(matches!(authorize_claims(builder.clone(), &claims(guest._id, Some(deleted._id))).await, Err(ServiceError::GuestAccessRevoked)));
+    }
+}
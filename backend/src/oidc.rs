@@ -0,0 +1,559 @@
+use std::{collections::HashMap, sync::Arc, time::{Duration, Instant}};
+
+use actix_web::{
+    cookie::{time, Cookie, SameSite}, error, http::{header, StatusCode}, web::{self, Data, Query}, HttpRequest, HttpResponse,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use derive_more::derive::{Display, Error};
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::RwLock;
+
+use crate::auth::{profile_claim, random_string, AuthError, TokenIssuer, MAX_EMAIL_LENGTH, MAX_USERNAME_LENGTH};
+use crate::database::PersistenceError;
+use crate::keys::KeySet;
+use crate::models::{Identity, OidcConfig, User};
+
+const LOGIN_COOKIE_NAME: &str = "retro_oidc_login";
+const LOGIN_COOKIE_PATH: &str = "/api/v1/auth/oidc";
+// How long someone may spend at the provider before the login has to start over
+const LOGIN_TTL_SECS: u64 = 600;
+// A token signed with a key we don't know yet refetches the provider's keys, at most this often
+const KEYS_REFETCH_SECS: u64 = 60;
+const HTTP_TIMEOUT_SECS: u64 = 10;
+
+#[derive(Debug, Display, Error)]
+pub enum OidcError {
+    #[display("The identity provider could not be reached. Please try again later.")]
+    ProviderUnavailable,
+    #[display("The login expired or was started in another browser. Please try again.")]
+    LoginExpired,
+    #[display("The identity provider did not sign you in: {_0}")]
+    Denied(#[error(not(source))] String),
+    #[display("The identity provider returned an invalid ID token.")]
+    InvalidIdToken,
+    #[display("{_0}")]
+    Session(AuthError),
+}
+
+impl error::ResponseError for OidcError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            OidcError::ProviderUnavailable => StatusCode::BAD_GATEWAY,
+            OidcError::LoginExpired => StatusCode::BAD_REQUEST,
+            OidcError::Denied(_) | OidcError::InvalidIdToken => StatusCode::UNAUTHORIZED,
+            OidcError::Session(e) => e.status_code(),
+        }
+    }
+}
+
+impl From<AuthError> for OidcError {
+    fn from(error: AuthError) -> Self {
+        OidcError::Session(error)
+    }
+}
+
+fn unreachable_provider(what: &str, error: impl std::fmt::Display) -> OidcError {
+    log::error!("Failed to {}: {}", what, error);
+    OidcError::ProviderUnavailable
+}
+
+fn unavailable_persistence(error: PersistenceError) -> OidcError {
+    log::error!("OIDC login failed to reach persistence: {}", error);
+    OidcError::Session(AuthError::Unavailable)
+}
+
+// The parts of the discovery document this flow uses
+#[derive(Clone, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+struct Provider {
+    metadata: ProviderMetadata,
+    keys: KeySet,
+    keys_fetched_at: Instant,
+}
+
+// Kept in a cookie signed with the login key between sending the browser to the provider and it
+// coming back, which ties the callback to the browser that started the login
+#[derive(Clone, Serialize, Deserialize)]
+struct PendingLogin {
+    state: String,
+    nonce: String,
+    code_verifier: String,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Audience {
+    fn contains(&self, client_id: &str) -> bool {
+        match self {
+            Audience::One(audience) => audience == client_id,
+            Audience::Many(audiences) => audiences.iter().any(|audience| audience == client_id),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+struct IdTokenClaims {
+    iss: String,
+    sub: String,
+    aud: Audience,
+    #[serde(default)]
+    azp: Option<String>,
+    #[serde(default)]
+    nonce: Option<String>,
+    #[serde(flatten)]
+    profile: HashMap<String, serde_json::Value>,
+}
+
+impl IdTokenClaims {
+    fn claim(&self, name: &str) -> Option<&str> {
+        self.profile.get(name).and_then(serde_json::Value::as_str)
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+// Signs users in with an external OpenID Connect provider using the authorization code flow
+// with PKCE, then hands out this service's own tokens like a local login would
+pub struct Oidc {
+    config: OidcConfig,
+    client_secret: Option<String>,
+    tokens: Arc<TokenIssuer>,
+    http: reqwest::Client,
+    // Discovered on first use, so the service still starts while the provider is down
+    provider: RwLock<Option<Arc<Provider>>>,
+}
+
+impl Oidc {
+    pub fn new(config: OidcConfig, client_secret: Option<String>, tokens: Arc<TokenIssuer>) -> Result<Oidc, reqwest::Error> {
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_secs(HTTP_TIMEOUT_SECS))
+            .build()?;
+        Ok(Oidc { config, client_secret, tokens, http, provider: RwLock::new(None) })
+    }
+
+    async fn provider(&self) -> Result<Arc<Provider>, OidcError> {
+        if let Some(provider) = self.provider.read().await.as_ref() {
+            return Ok(provider.clone());
+        }
+        let mut cached = self.provider.write().await;
+        if let Some(provider) = cached.as_ref() {
+            return Ok(provider.clone());
+        }
+
+        let url = format!("{}/.well-known/openid-configuration", self.config.issuer.trim_end_matches('/'));
+        let metadata: ProviderMetadata = self.http.get(&url).send().await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| unreachable_provider("discover the OIDC provider", e))?
+            .json().await
+            .map_err(|e| unreachable_provider("read the OIDC discovery document", e))?;
+        // Required by OIDC discovery, so a look-alike document can't vouch for someone else's tokens
+        if metadata.issuer != self.config.issuer {
+            log::error!("OIDC discovery at {} names issuer {:?} instead of {:?}", url, metadata.issuer, self.config.issuer);
+            return Err(OidcError::ProviderUnavailable);
+        }
+
+        let provider = Arc::new(Provider {
+            keys: self.fetch_keys(&metadata.jwks_uri).await?,
+            metadata,
+            keys_fetched_at: Instant::now(),
+        });
+        log::info!("Discovered OIDC provider {}", provider.metadata.issuer);
+        *cached = Some(provider.clone());
+        Ok(provider)
+    }
+
+    async fn fetch_keys(&self, jwks_uri: &str) -> Result<KeySet, OidcError> {
+        let jwks = self.http.get(jwks_uri).send().await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| unreachable_provider("fetch the OIDC provider's keys", e))?
+            .text().await
+            .map_err(|e| unreachable_provider("read the OIDC provider's keys", e))?;
+        KeySet::from_jwks(&jwks, jwks_uri).map_err(|e| unreachable_provider("load the OIDC provider's keys", e))
+    }
+
+    // Providers rotate keys on their own schedule; None when the keys were fetched too recently
+    async fn refetch_keys(&self, stale: &Provider) -> Result<Option<Arc<Provider>>, OidcError> {
+        if stale.keys_fetched_at.elapsed() < Duration::from_secs(KEYS_REFETCH_SECS) {
+            return Ok(None);
+        }
+        let provider = Arc::new(Provider {
+            keys: self.fetch_keys(&stale.metadata.jwks_uri).await?,
+            metadata: stale.metadata.clone(),
+            keys_fetched_at: Instant::now(),
+        });
+        *self.provider.write().await = Some(provider.clone());
+        Ok(Some(provider))
+    }
+
+    async fn exchange_code(&self, provider: &Provider, code: &str, code_verifier: &str) -> Result<String, OidcError> {
+        let form = [
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &self.config.redirect_uri),
+            ("client_id", &self.config.client_id),
+            ("code_verifier", code_verifier),
+        ];
+        let mut request = self.http.post(&provider.metadata.token_endpoint).form(&form);
+        if let Some(client_secret) = &self.client_secret {
+            request = request.basic_auth(&self.config.client_id, Some(client_secret));
+        }
+        let response = request.send().await.map_err(|e| unreachable_provider("redeem the authorization code", e))?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            log::warn!("OIDC provider rejected an authorization code ({}): {}", status, body);
+            return Err(OidcError::Denied("the authorization code was not accepted".to_string()));
+        }
+        let response: TokenResponse = response.json().await.map_err(|e| unreachable_provider("read the token response", e))?;
+        response.id_token.ok_or(OidcError::InvalidIdToken)
+    }
+
+    async fn validate_id_token(&self, provider: Arc<Provider>, id_token: &str, nonce: &str) -> Result<IdTokenClaims, OidcError> {
+        let claims = match provider.keys.verify::<IdTokenClaims>(id_token) {
+            Ok(claims) => claims,
+            Err(_) => match self.refetch_keys(&provider).await? {
+                Some(provider) => provider.keys.verify(id_token).map_err(|_| OidcError::InvalidIdToken)?,
+                None => return Err(OidcError::InvalidIdToken),
+            },
+        };
+
+        if claims.iss != provider.metadata.issuer || !claims.aud.contains(&self.config.client_id) {
+            return Err(OidcError::InvalidIdToken);
+        }
+        if claims.azp.as_ref().is_some_and(|azp| *azp != self.config.client_id) {
+            return Err(OidcError::InvalidIdToken);
+        }
+        if claims.nonce.as_deref() != Some(nonce) {
+            return Err(OidcError::InvalidIdToken);
+        }
+        Ok(claims)
+    }
+
+    fn username(&self, claims: &IdTokenClaims) -> String {
+        let claim = |name: &str| profile_claim(claims.claim(name), MAX_USERNAME_LENGTH);
+        claim(&self.config.username_claim).or_else(|| claim("email"))
+            .unwrap_or_else(|| claims.sub.chars().filter(|c| !c.is_control()).take(MAX_USERNAME_LENGTH).collect())
+    }
+
+    // Users are matched by issuer and subject, never by name, so a provider account can't take
+    // over a local user who happens to share its username
+    async fn sign_in(&self, claims: &IdTokenClaims) -> Result<User, OidcError> {
+        let persistence_manager = &self.tokens.persistence_manager;
+        match persistence_manager.get_user_by_identity(&claims.iss, &claims.sub).await {
+            Ok(user) => return Ok(user),
+            Err(PersistenceError::NotFound(_)) => {}
+            Err(e) => return Err(unavailable_persistence(e)),
+        }
+
+        let user = User {
+            _id: ObjectId::new(),
+            username: self.username(claims),
+            admin: false,
+            guest: false,
+            email: profile_claim(claims.claim("email"), MAX_EMAIL_LENGTH),
+            display_name: profile_claim(claims.claim("name"), MAX_USERNAME_LENGTH),
+        };
+        let identity = Identity { issuer: claims.iss.clone(), subject: claims.sub.clone(), user_id: user._id };
+        match persistence_manager.create_user_with_identity(user, identity).await {
+            Ok(user) => {
+                log::info!("Created user {} ({}) for {} at {}", user.username, user._id, claims.sub, claims.iss);
+                Ok(user)
+            }
+            // Another request finished the same first login first
+            Err(PersistenceError::Conflict(_)) => persistence_manager.get_user_by_identity(&claims.iss, &claims.sub).await
+                .map_err(unavailable_persistence),
+            Err(e) => Err(unavailable_persistence(e)),
+        }
+    }
+
+    fn login_cookie(&self, value: String) -> Cookie<'static> {
+        Cookie::build(LOGIN_COOKIE_NAME, value)
+            .path(LOGIN_COOKIE_PATH)
+            .http_only(true)
+            .secure(self.config.redirect_uri.starts_with("https://"))
+            .same_site(SameSite::Lax)
+            .max_age(time::Duration::seconds(LOGIN_TTL_SECS as i64))
+            .finish()
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/api/v1/auth/oidc/login").route(web::get().to(login)))
+        .service(web::resource("/api/v1/auth/oidc/callback").route(web::get().to(callback)));
+}
+
+// Sends the browser to the provider's login page
+async fn login(oidc: Data<Oidc>) -> Result<HttpResponse, OidcError> {
+    let provider = oidc.provider().await?;
+    let pending = PendingLogin {
+        state: random_string(),
+        nonce: random_string(),
+        code_verifier: random_string(),
+    };
+    let code_challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(pending.code_verifier.as_bytes()));
+
+    let mut url = reqwest::Url::parse(&provider.metadata.authorization_endpoint)
+        .map_err(|e| unreachable_provider("parse the authorization endpoint", e))?;
+    url.query_pairs_mut()
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &oidc.config.client_id)
+        .append_pair("redirect_uri", &oidc.config.redirect_uri)
+        .append_pair("scope", &oidc.config.scopes.join(" "))
+        .append_pair("state", &pending.state)
+        .append_pair("nonce", &pending.nonce)
+        .append_pair("code_challenge", &code_challenge)
+        .append_pair("code_challenge_method", "S256");
+
+    let cookie = oidc.tokens.sign(pending, LOGIN_TTL_SECS, &oidc.tokens.login_key)?;
+    Ok(HttpResponse::Found()
+        .cookie(oidc.login_cookie(cookie))
+        .insert_header((header::LOCATION, url.as_str()))
+        .finish())
+}
+
+// Where the provider sends the browser back to, with an authorization code on success
+async fn callback(req: HttpRequest, query: Query<CallbackQuery>, oidc: Data<Oidc>) -> Result<HttpResponse, OidcError> {
+    let pending: PendingLogin = req.cookie(LOGIN_COOKIE_NAME)
+        .and_then(|cookie| oidc.tokens.verify(cookie.value(), &oidc.tokens.login_key))
+        .ok_or(OidcError::LoginExpired)?;
+    if query.state.as_deref() != Some(pending.state.as_str()) {
+        return Err(OidcError::LoginExpired);
+    }
+    if let Some(error) = &query.error {
+        return Err(OidcError::Denied(query.error_description.clone().unwrap_or_else(|| error.clone())));
+    }
+    let code = query.code.as_deref().ok_or(OidcError::Denied("no authorization code was returned".to_string()))?;
+
+    let provider = oidc.provider().await?;
+    let id_token = oidc.exchange_code(&provider, code, &pending.code_verifier).await?;
+    let claims = oidc.validate_id_token(provider, &id_token, &pending.nonce).await?;
+    let user = oidc.sign_in(&claims).await?;
+    let session = oidc.tokens.start_session(user._id).await?;
+
+    let mut removal = oidc.login_cookie(String::new());
+    removal.make_removal();
+    match &oidc.config.post_login_redirect {
+        // Fragments never reach servers, so the tokens stay out of access logs on the way
+        Some(redirect) => {
+            let mut location = format!("{}#token={}", redirect, session.token);
+            if let Some(refresh_token) = &session.refresh_token {
+                location.push_str(&format!("&refresh_token={}", refresh_token));
+            }
+            Ok(HttpResponse::Found().cookie(removal).insert_header((header::LOCATION, location)).finish())
+        }
+        None => Ok(HttpResponse::Ok().cookie(removal).json(session)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Mutex, RwLock as StdRwLock};
+
+    use actix_web::{test, App, HttpServer};
+    use jwt_compact::{alg::{Ed25519, Hs512Key}, jwk::JsonWebKey, AlgorithmExt, Claims, Header, TimeOptions};
+    use serde_json::json;
+
+    use super::*;
+    use crate::database::PersistenceManager;
+    use crate::models::TokenConfig;
+
+    const CLIENT_ID: &str = "retro";
+    const CODE: &str = "code-1";
+    const KEY_ID: &str = "mock-key";
+
+    // What the mock provider signs into the ID token for the code it hands out, once the code
+    // verifier matches the challenge the login started with
+    #[derive(Default)]
+    struct Pending {
+        code_challenge: String,
+        claims: serde_json::Value,
+    }
+
+    struct MockProvider {
+        issuer: String,
+        signing_key: ed25519_dalek::SigningKey,
+        pending: Mutex<Pending>,
+    }
+
+    #[derive(Deserialize)]
+    struct TokenForm {
+        grant_type: String,
+        code: String,
+        client_id: String,
+        code_verifier: String,
+    }
+
+    async fn discovery(provider: Data<MockProvider>) -> HttpResponse {
+        HttpResponse::Ok().json(json!({
+            "issuer": provider.issuer,
+            "authorization_endpoint": format!("{}/authorize", provider.issuer),
+            "token_endpoint": format!("{}/token", provider.issuer),
+            "jwks_uri": format!("{}/jwks", provider.issuer),
+        }))
+    }
+
+    async fn jwks(provider: Data<MockProvider>) -> HttpResponse {
+        let mut key = serde_json::to_value(JsonWebKey::from(&provider.signing_key.verifying_key())).unwrap();
+        key["kid"] = json!(KEY_ID);
+        HttpResponse::Ok().json(json!({ "keys": [key] }))
+    }
+
+    async fn token(provider: Data<MockProvider>, form: web::Form<TokenForm>) -> HttpResponse {
+        let pending = provider.pending.lock().unwrap();
+        let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(form.code_verifier.as_bytes()));
+        if form.grant_type != "authorization_code" || form.code != CODE || form.client_id != CLIENT_ID || challenge != pending.code_challenge {
+            return HttpResponse::BadRequest().json(json!({ "error": "invalid_grant" }));
+        }
+        let claims = Claims::new(pending.claims.clone())
+            .set_duration_and_issuance(&TimeOptions::default(), chrono::Duration::minutes(5));
+        let id_token = Ed25519.token(&Header::empty().with_key_id(KEY_ID), &claims, &provider.signing_key).unwrap();
+        HttpResponse::Ok().json(json!({ "id_token": id_token, "token_type": "Bearer" }))
+    }
+
+    async fn start_provider() -> Data<MockProvider> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let provider = Data::new(MockProvider {
+            issuer: format!("http://{}", listener.local_addr().unwrap()),
+            signing_key: ed25519_dalek::SigningKey::from_bytes(&[7; 32]),
+            pending: Mutex::new(Pending::default()),
+        });
+        let data = provider.clone();
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(data.clone())
+                .route("/.well-known/openid-configuration", web::get().to(discovery))
+                .route("/jwks", web::get().to(jwks))
+                .route("/token", web::post().to(token))
+        })
+        .workers(1)
+        .listen(listener)
+        .unwrap()
+        .run();
+        actix_web::rt::spawn(server);
+        provider
+    }
+
+    fn oidc(issuer: &str) -> Oidc {
+        let tokens = Arc::new(TokenIssuer {
+            config: TokenConfig::default(),
+            signing_key: Hs512Key::new(b"test secret"),
+            login_key: crate::auth::derived_key("test secret", "oidc-login"),
+            refresh_key: None,
+            persistence_manager: PersistenceManager::new_memory(Arc::new(StdRwLock::new(HashMap::new())), Arc::new(StdRwLock::new(HashMap::new()))),
+        });
+        let config = OidcConfig {
+            issuer: issuer.to_string(),
+            client_id: CLIENT_ID.to_string(),
+            redirect_uri: "http://localhost/api/v1/auth/oidc/callback".to_string(),
+            scopes: vec!["openid".to_string(), "email".to_string(), "profile".to_string()],
+            username_claim: "preferred_username".to_string(),
+            post_login_redirect: None,
+        };
+        Oidc::new(config, None, tokens).unwrap()
+    }
+
+    // Runs a whole login, letting `claims` shape the ID token the provider returns for the nonce
+    // the login was started with
+    async fn log_in(provider: &MockProvider, oidc: Data<Oidc>, claims: impl FnOnce(&str) -> serde_json::Value) -> StatusCode {
+        let app = test::init_service(App::new().app_data(oidc).configure(configure)).await;
+
+        let response = test::call_service(&app, test::TestRequest::get().uri("/api/v1/auth/oidc/login").to_request()).await;
+        assert_eq!(response.status(), StatusCode::FOUND);
+        let cookie = response.response().cookies().find(|c| c.name() == LOGIN_COOKIE_NAME).unwrap().into_owned();
+        let location = reqwest::Url::parse(response.headers().get(header::LOCATION).unwrap().to_str().unwrap()).unwrap();
+        let param = |name: &str| location.query_pairs().find(|(key, _)| key == name).map(|(_, value)| value.into_owned()).unwrap();
+        assert_eq!(param("code_challenge_method"), "S256");
+        *provider.pending.lock().unwrap() = Pending { code_challenge: param("code_challenge"), claims: claims(&param("nonce")) };
+
+        let callback = format!("/api/v1/auth/oidc/callback?code={}&state={}", CODE, param("state"));
+        test::call_service(&app, test::TestRequest::get().uri(&callback).cookie(cookie).to_request()).await.status()
+    }
+
+    fn id_token_claims(provider: &MockProvider, nonce: &str) -> serde_json::Value {
+        json!({
+            "iss": provider.issuer,
+            "sub": "user-1",
+            "aud": CLIENT_ID,
+            "nonce": nonce,
+            "preferred_username": "ada",
+            "email": "ada@example.com",
+            "name": "Ada Lovelace",
+        })
+    }
+
+    #[actix_web::test]
+    async fn first_login_provisions_the_user_from_the_id_token() {
+        let provider = start_provider().await;
+        let oidc = Data::new(oidc(&provider.issuer));
+
+        let status = log_in(&provider, oidc.clone(), |nonce| id_token_claims(&provider, nonce)).await;
+        assert_eq!(status, StatusCode::OK);
+        let persistence_manager = &oidc.tokens.persistence_manager;
+        let user = persistence_manager.get_user_by_identity(&provider.issuer, "user-1").await.unwrap();
+        assert_eq!(user.username, "ada");
+        assert_eq!(user.email.as_deref(), Some("ada@example.com"));
+        assert_eq!(user.display_name.as_deref(), Some("Ada Lovelace"));
+
+        // Later logins find the same user again
+        let status = log_in(&provider, oidc.clone(), |nonce| id_token_claims(&provider, nonce)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(persistence_manager.get_users().await.unwrap().len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn id_tokens_for_someone_else_are_rejected() {
+        let provider = start_provider().await;
+        let oidc = Data::new(oidc(&provider.issuer));
+
+        let tampered: [(&str, serde_json::Value); 3] = [
+            ("nonce", json!("replayed")),
+            ("aud", json!("another-client")),
+            ("azp", json!("another-client")),
+        ];
+        for (claim, value) in tampered {
+            let status = log_in(&provider, oidc.clone(), |nonce| {
+                let mut claims = id_token_claims(&provider, nonce);
+                claims[claim] = value;
+                claims
+            }).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED, "accepted an ID token with a wrong {}", claim);
+        }
+        assert!(oidc.tokens.persistence_manager.get_users().await.unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn login_state_is_not_an_access_token() {
+        let oidc = oidc("http://127.0.0.1:1");
+        let pending = PendingLogin { state: random_string(), nonce: random_string(), code_verifier: random_string() };
+        let cookie = oidc.tokens.sign(pending, LOGIN_TTL_SECS, &oidc.tokens.login_key).unwrap();
+        assert!(oidc.tokens.verify::<PendingLogin>(&cookie, &oidc.tokens.login_key).is_some());
+        assert!(oidc.tokens.verify::<PendingLogin>(&cookie, &oidc.tokens.signing_key).is_none());
+
+        let session = oidc.tokens.start_session(ObjectId::new()).await.unwrap();
+        assert!(oidc.tokens.verify::<serde_json::Value>(&session.token, &oidc.tokens.login_key).is_none());
+    }
+}
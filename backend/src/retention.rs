@@ -0,0 +1,229 @@
+use std::time::Duration;
+
+use chrono::{DateTime, TimeDelta, Utc};
+
+use crate::database::{PersistenceError, PersistenceManager};
+use crate::events::EventBus;
+use crate::models::{Retro, RetentionConfig, SubscriptionUpdate};
+
+// Periodically archives, anonymizes and deletes retros as they age past the configured limits
+pub fn spawn(persistence_manager: PersistenceManager, event_bus: EventBus, config: RetentionConfig) -> Result<(), String> {
+    let limits = Limits::new(&config)?;
+    log::info!(
+        "Retention enabled: archive after {:?} days, anonymize after {:?} days, delete after {:?} days",
+        config.archive_after_days, limits.anonymize_after, config.delete_after_days,
+    );
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(config.interval_secs));
+        loop {
+            ticker.tick().await;
+            if let Err(e) = run_once(&persistence_manager, &event_bus, &limits, Utc::now()).await {
+                log::error!("Retention run failed: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+// The configured ages, checked up front so that a value too large for a date can't fail later
+struct Limits {
+    archive_after: Option<TimeDelta>,
+    anonymize_after: Option<TimeDelta>,
+    delete_after: Option<TimeDelta>,
+}
+
+impl Limits {
+    fn new(config: &RetentionConfig) -> Result<Self, String> {
+        if config.interval_secs == 0 {
+            return Err("retention.interval_secs must be at least 1".to_string());
+        }
+        Ok(Limits {
+            archive_after: max_age("archive_after_days", config.archive_after_days)?,
+            anonymize_after: max_age("anonymize_after_days", config.anonymize_after_days)?,
+            delete_after: max_age("delete_after_days", config.delete_after_days)?,
+        })
+    }
+}
+
+fn max_age(field: &str, days: Option<u64>) -> Result<Option<TimeDelta>, String> {
+    days.map(|days| {
+        i64::try_from(days).ok()
+            .and_then(TimeDelta::try_days)
+            .ok_or_else(|| format!("retention.{} is too large: {}", field, days))
+    }).transpose()
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct RetentionReport {
+    archived: u32,
+    anonymized: u32,
+    deleted: u32,
+}
+
+fn older_than(created_at: DateTime<Utc>, age: Option<TimeDelta>, now: DateTime<Utc>) -> bool {
+    age.is_some_and(|age| now - created_at >= age)
+}
+
+async fn run_once(persistence_manager: &PersistenceManager, event_bus: &EventBus, limits: &Limits, now: DateTime<Utc>) -> Result<RetentionReport, PersistenceError> {
+    let mut report = RetentionReport::default();
+
+    for retro in persistence_manager.get_retros(true).await? {
+        let created_at = match DateTime::parse_from_rfc3339(&retro.created_at) {
+            Ok(created_at) => created_at.with_timezone(&Utc),
+            Err(e) => {
+                log::warn!("Retention skipped retro {}: unreadable created_at {:?}: {}", retro._id, retro.created_at, e);
+                continue;
+            }
+        };
+
+        // One retro failing shouldn't hold back the rest; it is picked up again on the next run
+        if let Err(e) = apply(persistence_manager, event_bus, limits, &retro, created_at, now, &mut report).await {
+            log::error!("Retention failed for retro {}: {}", retro._id, e);
+        }
+    }
+
+    if report.archived + report.anonymized + report.deleted > 0 {
+        log::info!(
+            "Retention run finished: {} archived, {} anonymized, {} deleted",
+            report.archived, report.anonymized, report.deleted,
+        );
+    }
+    Ok(report)
+}
+
+async fn apply(
+    persistence_manager: &PersistenceManager,
+    event_bus: &EventBus,
+    limits: &Limits,
+    retro: &Retro,
+    created_at: DateTime<Utc>,
+    now: DateTime<Utc>,
+    report: &mut RetentionReport,
+) -> Result<(), PersistenceError> {
+    if older_than(created_at, limits.delete_after, now) {
+        persistence_manager.delete_retro(&retro._id, None).await?;
+        event_bus.publish(SubscriptionUpdate::create_retro_deleted(retro._id)).await;
+        log::info!("Retention deleted retro {} ({:?}) created at {}", retro._id, retro.retro_name, retro.created_at);
+        report.deleted += 1;
+        return Ok(());
+    }
+
+    if older_than(created_at, limits.anonymize_after, now)
+        && persistence_manager.anonymize_card_authors(&retro._id).await?.is_some()
+    {
+        log::info!("Retention anonymized card authors in retro {} ({:?})", retro._id, retro.retro_name);
+        report.anonymized += 1;
+    }
+
+    if older_than(created_at, limits.archive_after, now) && retro.archived_at.is_none() {
+        let archived_at = now.to_rfc3339();
+        persistence_manager.set_archived(&retro._id, Some(archived_at.clone()), None).await?;
+        event_bus.publish(SubscriptionUpdate::create_retro_archived(retro._id, Some(archived_at))).await;
+        log::info!("Retention archived retro {} ({:?})", retro._id, retro.retro_name);
+        report.archived += 1;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+    use std::sync::{Arc, RwLock};
+
+    use mongodb::bson::oid::ObjectId;
+
+    use super::*;
+    use crate::database::migrations;
+    use crate::events::Topic;
+    use crate::models::{Card, Lane, RetroStep, ANONYMOUS_USER_ID};
+
+    fn config(archive: Option<u64>, anonymize: Option<u64>, delete: Option<u64>) -> RetentionConfig {
+        RetentionConfig { archive_after_days: archive, anonymize_after_days: anonymize, delete_after_days: delete, interval_secs: 3600 }
+    }
+
+    async fn stored_retro(persistence_manager: &PersistenceManager, created_at: String) -> ObjectId {
+        let retro_id = ObjectId::new();
+        let card = Card {
+            id: ObjectId::new(),
+            creator_id: ObjectId::new(),
+            retro_id,
+            text: "Shipped it".to_string(),
+            subcards: vec![],
+            votes: HashSet::new(),
+        };
+        let retro = Retro {
+            _id: retro_id,
+            retro_name: created_at.clone(),
+            creator_id: ObjectId::new(),
+            step: RetroStep::Writing,
+            created_at,
+            participants: vec![],
+            lanes: vec![Lane { id: ObjectId::new(), title: "Good".to_string(), cards: vec![card], priority: 1 }],
+            version: 0,
+            archived_at: None,
+            schema_version: migrations::CURRENT_SCHEMA_VERSION,
+            guest_invite: None,
+        };
+        persistence_manager.create_retro(retro).await.unwrap()._id
+    }
+
+    #[test]
+    fn ages_beyond_a_date_are_rejected() {
+        assert!(Limits::new(&config(Some(36500), Some(0), None)).is_ok());
+        for days in [u64::MAX, i64::MAX as u64, 1 << 60] {
+            assert!(Limits::new(&config(None, None, Some(days))).is_err(), "{} days accepted", days);
+        }
+        let mut every_instant = config(Some(1), None, None);
+        every_instant.interval_secs = 0;
+        assert!(Limits::new(&every_instant).is_err());
+    }
+
+    #[tokio::test]
+    async fn each_step_happens_once_its_age_is_reached() {
+        let persistence_manager = PersistenceManager::new_memory(Arc::new(RwLock::new(HashMap::new())), Arc::new(RwLock::new(HashMap::new())));
+        let event_bus = EventBus::new_memory();
+        let limits = Limits::new(&config(Some(10), Some(20), Some(30))).unwrap();
+        let now = Utc::now();
+        let created = |age: TimeDelta| (now - age).to_rfc3339();
+        let just_under = |days: i64| TimeDelta::days(days) - TimeDelta::seconds(1);
+
+        let fresh = stored_retro(&persistence_manager, created(just_under(10))).await;
+        let archived = stored_retro(&persistence_manager, created(TimeDelta::days(10))).await;
+        let almost_anonymized = stored_retro(&persistence_manager, created(just_under(20))).await;
+        let anonymized = stored_retro(&persistence_manager, created(TimeDelta::days(20))).await;
+        let almost_deleted = stored_retro(&persistence_manager, created(just_under(30))).await;
+        let deleted = stored_retro(&persistence_manager, created(TimeDelta::days(30))).await;
+        let unreadable = stored_retro(&persistence_manager, "last spring".to_string()).await;
+
+        let mut archivals = event_bus.subscribe(Topic::RetroArchived);
+        let mut deletions = event_bus.subscribe(Topic::RetroDeleted);
+        let report = run_once(&persistence_manager, &event_bus, &limits, now).await.unwrap();
+        assert_eq!(report, RetentionReport { archived: 4, anonymized: 2, deleted: 1 });
+
+        for id in [fresh, unreadable] {
+            let retro = persistence_manager.get_retro(&id).await.unwrap();
+            assert!(retro.archived_at.is_none() && retro.version == 0, "{} was touched", retro.retro_name);
+        }
+        for id in [archived, almost_anonymized, anonymized, almost_deleted] {
+            let retro = persistence_manager.get_retro(&id).await.unwrap();
+            assert_eq!(retro.archived_at, Some(now.to_rfc3339()), "{} wasn't archived", retro.retro_name);
+            let anonymous = retro.lanes[0].cards[0].creator_id == ANONYMOUS_USER_ID;
+            assert_eq!(anonymous, id == anonymized || id == almost_deleted, "{} has the wrong author", retro.retro_name);
+        }
+        assert!(matches!(persistence_manager.get_retro(&deleted).await, Err(PersistenceError::NotFound(_))));
+
+        let mut archived_ids = HashSet::new();
+        while let Ok(update) = archivals.try_recv() {
+            let SubscriptionUpdate::RetroArchived(update) = update else { panic!("unexpected update {:?}", update) };
+            archived_ids.insert(update.retro_id);
+        }
+        assert_eq!(archived_ids, HashSet::from([archived, almost_anonymized, anonymized, almost_deleted]));
+        assert!(matches!(deletions.try_recv(), Ok(SubscriptionUpdate::RetroDeleted(update)) if update.retro_id == deleted));
+        assert!(deletions.try_recv().is_err());
+
+        // Nothing left to do until more time passes, and nothing is counted twice
+        let report = run_once(&persistence_manager, &event_bus, &limits, now).await.unwrap();
+        assert_eq!(report, RetentionReport::default());
+        assert!(archivals.try_recv().is_err());
+    }
+}
@@ -1,12 +1,18 @@
 use juniper::{RootNode, graphql_subscription};
 use mongodb::bson::oid::ObjectId;
-use crate::models::{Retro, RetroStep, RetroParticipant, Card, Lane, SubscriptionUpdate, User, UserListUpdated};
+use crate::models::{ApiToken, ApiTokenScope, Retro, RetroCursor, RetroFilter, RetroOrderField, RetroPageQuery, RetroStep, RetroParticipant, Card, Lane, SortDirection, SubscriptionUpdate, User, UserListUpdated, ANONYMOUS_USER_ID};
+use crate::auth::{hash_api_token, new_api_token, random_string};
 use crate::context::Context;
+use crate::database::{migrations, PersistenceError};
+use crate::errors::{parse_id, ApiError, ApiResult};
+use crate::events::Topic;
+use std::collections::HashSet;
+use std::future::Future;
 use std::pin::Pin;
-use std::str::FromStr;
+use std::time::Duration;
 use chrono::prelude::*;
+use rand::Rng;
 use tokio_stream::StreamExt;
-use std::collections::HashSet;
 
 #[juniper::graphql_object(context = Context)]
 impl User {
@@ -21,6 +27,24 @@ impl User {
     fn active_user(&self, context: &Context) -> bool {
         self._id == context.active_user._id
     }
+
+    fn admin(&self) -> bool {
+        self.admin
+    }
+
+    fn guest(&self) -> bool {
+        self.guest
+    }
+
+    // Only the user themselves and admins can see an email address
+    fn email(&self, context: &Context) -> Option<&str> {
+        let visible = self._id == context.active_user._id || context.active_user.admin;
+        self.email.as_deref().filter(|_| visible)
+    }
+
+    fn display_name(&self) -> Option<&str> {
+        self.display_name.as_deref()
+    }
 }
 
 // GraphQL representation of a Card
@@ -34,8 +58,11 @@ impl Card {
         &self.text
     }
 
-    async fn creator(&self, context: &Context) -> User {
-        context.persistence_manager.get_user(&self.creator_id).await.unwrap()
+    async fn creator(&self, context: &Context) -> Result<User, PersistenceError> {
+        if self.creator_id == ANONYMOUS_USER_ID {
+            return Ok(User::anonymous());
+        }
+        context.users.load(self.creator_id).await
     }
 
     fn subcards(&self) -> &Vec<Card> {
@@ -79,12 +106,12 @@ impl Lane {
 // GraphQL representation of a RetroParticipant
 #[juniper::graphql_object(context = Context)]
 impl RetroParticipant {
-    async fn user(&self, context: &Context) -> User {
-        context.persistence_manager.get_user(&self.user).await.unwrap()
+    async fn user(&self, context: &Context) -> Result<User, PersistenceError> {
+        context.users.load(self.user).await
     }
 
-    async fn retro(&self, context: &Context) -> Retro {
-        context.persistence_manager.get_retro(&self.retro_id).await.unwrap()
+    async fn retro(&self, context: &Context) -> Result<Retro, PersistenceError> {
+        context.retros.load(self.retro_id).await
     }
 }
 
@@ -103,8 +130,8 @@ impl Retro {
         &self.step
     }
 
-    async fn creator(&self, context: &Context) -> User {
-        context.persistence_manager.get_user(&self.creator_id).await.unwrap()
+    async fn creator(&self, context: &Context) -> Result<User, PersistenceError> {
+        context.users.load(self.creator_id).await
     }
 
     fn created_at(&self) -> &str {
@@ -118,6 +145,56 @@ impl Retro {
     fn lanes(&self) -> &Vec<Lane> {
         &self.lanes
     }
+
+    fn archived(&self) -> bool {
+        self.archived_at.is_some()
+    }
+
+    fn archived_at(&self) -> Option<&str> {
+        self.archived_at.as_deref()
+    }
+
+    // Only the facilitators get to see and share the invite
+    fn guest_invite(&self, context: &Context) -> Option<&str> {
+        let owner = self.creator_id == context.active_user._id || context.active_user.admin;
+        self.guest_invite.as_deref().filter(|_| owner)
+    }
+}
+
+// GraphQL representation of a personal API token; its secret is only ever shown on creation
+#[juniper::graphql_object(context = Context)]
+impl ApiToken {
+    fn id(&self) -> String {
+        self._id.to_hex()
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn scopes(&self) -> &Vec<ApiTokenScope> {
+        &self.scopes
+    }
+
+    fn created_at(&self) -> &str {
+        &self.created_at
+    }
+
+    fn expires_at(&self) -> Option<&str> {
+        self.expires_at.as_deref()
+    }
+
+    fn expired(&self) -> bool {
+        self.is_expired()
+    }
+}
+
+#[derive(juniper::GraphQLObject)]
+#[graphql(context = Context)]
+pub struct CreatedApiToken {
+    // Pass this as the access token; it can't be retrieved again
+    pub token: String,
+    pub api_token: ApiToken,
 }
 
 #[derive(juniper::GraphQLInputObject)]
@@ -132,6 +209,77 @@ pub struct AddCardInput {
     pub text: String,
 }
 
+#[derive(juniper::GraphQLInputObject)]
+pub struct RetroFilterInput {
+    pub creator_id: Option<String>,
+    pub participant_id: Option<String>,
+    pub step: Option<RetroStep>,
+    pub name_contains: Option<String>,
+    // RFC 3339 timestamps, both inclusive
+    pub created_after: Option<String>,
+    pub created_before: Option<String>,
+    pub include_archived: Option<bool>,
+}
+
+#[derive(juniper::GraphQLInputObject)]
+pub struct RetroOrderInput {
+    pub field: RetroOrderField,
+    pub direction: Option<SortDirection>,
+}
+
+#[derive(juniper::GraphQLObject)]
+#[graphql(context = Context)]
+pub struct RetroEdge {
+    pub cursor: String,
+    pub node: Retro,
+}
+
+#[derive(juniper::GraphQLObject)]
+pub struct PageInfo {
+    pub has_next_page: bool,
+    pub has_previous_page: bool,
+    pub start_cursor: Option<String>,
+    pub end_cursor: Option<String>,
+}
+
+#[derive(juniper::GraphQLObject)]
+#[graphql(context = Context)]
+pub struct RetroConnection {
+    pub edges: Vec<RetroEdge>,
+    pub page_info: PageInfo,
+}
+
+const DEFAULT_PAGE_SIZE: i32 = 20;
+const MAX_UPDATE_ATTEMPTS: u32 = 8;
+// The longest a conflicting write waits before its first retry; doubled for every retry after
+const RETRY_BACKOFF_MS: u64 = 5;
+const MAX_API_TOKEN_NAME_LENGTH: usize = 100;
+const MAX_PAGE_SIZE: i32 = 100;
+
+fn parse_datetime(value: &str) -> ApiResult<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|t| t.with_timezone(&Utc))
+        .map_err(|_| ApiError::InvalidArgument(format!("{:?} is not an RFC 3339 timestamp", value)))
+}
+
+fn parse_timestamp(value: &str) -> ApiResult<String> {
+    parse_datetime(value).map(|t| t.to_rfc3339())
+}
+
+impl RetroFilterInput {
+    fn into_filter(self) -> ApiResult<RetroFilter> {
+        Ok(RetroFilter {
+            creator_id: self.creator_id.as_deref().map(parse_id).transpose()?,
+            participant_id: self.participant_id.as_deref().map(parse_id).transpose()?,
+            step: self.step,
+            name_contains: self.name_contains,
+            created_from: self.created_after.as_deref().map(parse_timestamp).transpose()?,
+            created_to: self.created_before.as_deref().map(parse_timestamp).transpose()?,
+            include_archived: self.include_archived.unwrap_or(false),
+        })
+    }
+}
+
 // Subscription root
 type SubStream = Pin<Box<dyn futures::Stream<Item = SubscriptionUpdate> + Send>>;
 
@@ -140,9 +288,10 @@ pub struct SubscriptionRoot;
 #[graphql_subscription(context = Context)]
 impl SubscriptionRoot {
     // Subscription for added cards
-    async fn card_added(context: &Context, retro_id: String) -> SubStream {
-        let rx = context.card_addition_sender.subscribe();
-        let rid = ObjectId::from_str(&retro_id).unwrap();
+    async fn card_added(context: &Context, retro_id: String) -> ApiResult<SubStream> {
+        let rid = parse_id(&retro_id)?;
+        context.require_retro_access(&rid)?;
+        let rx = context.event_bus.subscribe(Topic::CardAdded);
 
         let stream = tokio_stream::wrappers::BroadcastStream::new(rx)
             .filter_map(move |result| {
@@ -157,13 +306,14 @@ impl SubscriptionRoot {
                 }
             });
 
-        Box::pin(stream)
+        Ok(Box::pin(stream))
     }
 
     // Subscription for user list updates
-    async fn user_list_updated(context: &Context, retro_id: String) -> SubStream {
-        let rx = context.user_update_sender.subscribe();
-        let rid = ObjectId::from_str(&retro_id).unwrap();
+    async fn user_list_updated(context: &Context, retro_id: String) -> ApiResult<SubStream> {
+        let rid = parse_id(&retro_id)?;
+        context.require_retro_access(&rid)?;
+        let rx = context.event_bus.subscribe(Topic::UserListUpdated);
 
         let stream = tokio_stream::wrappers::BroadcastStream::new(rx)
             .filter_map(move |result| {
@@ -178,12 +328,13 @@ impl SubscriptionRoot {
                 }
             });
 
-        Box::pin(stream)
+        Ok(Box::pin(stream))
     }
 
-    async fn step_update(context: &Context, retro_id: String) -> SubStream {
-        let rx = context.step_update_sender.subscribe();
-        let rid = ObjectId::from_str(&retro_id).unwrap();
+    async fn step_update(context: &Context, retro_id: String) -> ApiResult<SubStream> {
+        let rid = parse_id(&retro_id)?;
+        context.require_retro_access(&rid)?;
+        let rx = context.event_bus.subscribe(Topic::StepUpdated);
 
         let stream = tokio_stream::wrappers::BroadcastStream::new(rx)
             .filter_map(move |result| {
@@ -198,8 +349,51 @@ impl SubscriptionRoot {
                 }
             });
 
-        Box::pin(stream)
+        Ok(Box::pin(stream))
+    }
+
+    // Tells open views when the retro is archived or brought back
+    async fn retro_archived(context: &Context, retro_id: String) -> ApiResult<SubStream> {
+        let rid = parse_id(&retro_id)?;
+        context.require_retro_access(&rid)?;
+        let rx = context.event_bus.subscribe(Topic::RetroArchived);
 
+        let stream = tokio_stream::wrappers::BroadcastStream::new(rx)
+            .filter_map(move |result| {
+                match result {
+                    Ok(update) => {
+                        match update.clone() {
+                            SubscriptionUpdate::RetroArchived(a) if a.retro_id == rid => Some(update),
+                            _ => None,
+                        }
+                    }
+                    Err(_) => None,
+                }
+            });
+
+        Ok(Box::pin(stream))
+    }
+
+    // Tells open views the retro is gone
+    async fn retro_deleted(context: &Context, retro_id: String) -> ApiResult<SubStream> {
+        let rid = parse_id(&retro_id)?;
+        context.require_retro_access(&rid)?;
+        let rx = context.event_bus.subscribe(Topic::RetroDeleted);
+
+        let stream = tokio_stream::wrappers::BroadcastStream::new(rx)
+            .filter_map(move |result| {
+                match result {
+                    Ok(update) => {
+                        match update.clone() {
+                            SubscriptionUpdate::RetroDeleted(d) if d.retro_id == rid => Some(update),
+                            _ => None,
+                        }
+                    }
+                    Err(_) => None,
+                }
+            });
+
+        Ok(Box::pin(stream))
     }
 }
 
@@ -208,25 +402,126 @@ pub struct QueryRoot;
 
 #[juniper::graphql_object(context = Context)]
 impl QueryRoot {
-    // Fetch all retrospectives
-    async fn all_retros(context: &Context) -> Vec<Retro> {
-        context.persistence_manager.get_retros().await.unwrap()
+    // Fetch all retrospectives, leaving out archived ones unless asked for
+    async fn all_retros(context: &Context, include_archived: Option<bool>) -> ApiResult<Vec<Retro>> {
+        context.require_unscoped()?;
+        Ok(context.persistence_manager.get_retros(include_archived.unwrap_or(false)).await?)
+    }
+
+    // Page through retros, newest first unless ordered otherwise
+    async fn retros(
+        context: &Context,
+        first: Option<i32>,
+        after: Option<String>,
+        filter: Option<RetroFilterInput>,
+        order_by: Option<RetroOrderInput>,
+    ) -> ApiResult<RetroConnection> {
+        context.require_unscoped()?;
+        let first = first.unwrap_or(DEFAULT_PAGE_SIZE);
+        if !(0..=MAX_PAGE_SIZE).contains(&first) {
+            return Err(ApiError::InvalidArgument(format!("first must be between 0 and {}", MAX_PAGE_SIZE)));
+        }
+        let after = after
+            .map(|cursor| RetroCursor::decode(&cursor).ok_or(ApiError::InvalidArgument(format!("{:?} is not a valid cursor", cursor))))
+            .transpose()?;
+        let filter = filter.map(RetroFilterInput::into_filter).transpose()?.unwrap_or_default();
+        let (order_by, direction) = order_by
+            .map(|o| (o.field, o.direction.unwrap_or_default()))
+            .unwrap_or_default();
+
+        let query = RetroPageQuery {
+            filter,
+            order_by,
+            direction,
+            first: first as usize,
+            after,
+        };
+        let page = context.persistence_manager.get_retro_page(&query).await?;
+
+        let edges: Vec<RetroEdge> = page.retros.into_iter()
+            .map(|retro| RetroEdge {
+                cursor: RetroCursor::for_retro(&retro, order_by).encode(),
+                node: retro,
+            })
+            .collect();
+        let page_info = PageInfo {
+            has_next_page: page.has_next_page,
+            has_previous_page: query.after.is_some(),
+            start_cursor: edges.first().map(|e| e.cursor.clone()),
+            end_cursor: edges.last().map(|e| e.cursor.clone()),
+        };
+        Ok(RetroConnection { edges, page_info })
     }
 
     // Fetch a specific retro by ID
-    async fn retro_by_id(context: &Context, id: String) -> Option<Retro> {
-        let rid = ObjectId::from_str(&id).unwrap();
-        context.persistence_manager.get_retro(&rid).await.ok()
+    async fn retro_by_id(context: &Context, id: String) -> ApiResult<Option<Retro>> {
+        let rid = parse_id(&id)?;
+        context.require_retro_access(&rid)?;
+        match context.persistence_manager.get_retro(&rid).await {
+            Ok(retro) => Ok(Some(retro)),
+            Err(PersistenceError::NotFound(_)) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
     }
 
-    async fn all_users(context: &Context) -> Vec<User> {
-        context.persistence_manager.get_users().await.unwrap()
+    // The active user's API tokens, oldest first
+    async fn api_tokens(context: &Context) -> ApiResult<Vec<ApiToken>> {
+        context.require_login()?;
+        context.require_unscoped()?;
+        Ok(context.persistence_manager.get_api_tokens(&context.active_user._id).await?)
     }
 
-    async fn user_by_id(context: &Context, id: String) -> Option<User> {
-        let uid = ObjectId::from_str(&id).unwrap();
-        context.persistence_manager.get_user(&uid).await.ok()
+    async fn all_users(context: &Context) -> ApiResult<Vec<User>> {
+        context.require_unscoped()?;
+        Ok(context.persistence_manager.get_users().await?)
     }
+
+    async fn user_by_id(context: &Context, id: String) -> ApiResult<Option<User>> {
+        context.require_unscoped()?;
+        let uid = parse_id(&id)?;
+        match context.persistence_manager.get_user(&uid).await {
+            Ok(user) => Ok(Some(user)),
+            Err(PersistenceError::NotFound(_)) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+// Runs `attempt` again while its write fails with Conflict, which is how a write conditional on
+// the version `attempt` read reports that someone else changed the retro in between. The random
+// wait keeps writers that collided from colliding again on the next try.
+async fn retry_on_conflict<T, F, Fut>(mut attempt: F) -> ApiResult<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = ApiResult<T>>,
+{
+    let mut attempts = 1;
+    loop {
+        match attempt().await {
+            Err(ApiError::Persistence(PersistenceError::Conflict(_))) if attempts < MAX_UPDATE_ATTEMPTS => {
+                let backoff = rand::thread_rng().gen_range(0..RETRY_BACKOFF_MS << (attempts - 1));
+                tokio::time::sleep(Duration::from_millis(backoff)).await;
+                attempts += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+// Archiving and deleting are reserved for the retro's creator and admins
+fn require_owner(context: &Context, retro: &Retro) -> ApiResult<()> {
+    if retro.creator_id != context.active_user._id && !context.active_user.admin {
+        return Err(ApiError::Forbidden("Only the creator of this retro or an admin can do that".to_string()));
+    }
+    Ok(())
+}
+
+fn validate_api_token_name(name: &str) -> ApiResult<String> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_API_TOKEN_NAME_LENGTH || name.chars().any(char::is_control) {
+        return Err(ApiError::InvalidArgument(format!("API token names must be 1 to {} characters", MAX_API_TOKEN_NAME_LENGTH)));
+    }
+    Ok(name.to_string())
 }
 
 // Root Mutation object
@@ -235,7 +530,9 @@ pub struct MutationRoot;
 #[juniper::graphql_object(context = Context)]
 impl MutationRoot {
     // Create a new retro
-    async fn create_retro(context: &Context, input: CreateRetroInput) -> Retro {
+    async fn create_retro(context: &Context, input: CreateRetroInput) -> ApiResult<Retro> {
+        context.require_api_scope(ApiTokenScope::ManageRetros)?;
+        context.require_unscoped()?;
         let new_id = ObjectId::new();
         let created_at = Utc::now().to_rfc3339();
 
@@ -268,62 +565,76 @@ impl MutationRoot {
             created_at,
             participants: vec![],
             lanes: default_lanes,
+            version: 0,
+            archived_at: None,
+            schema_version: migrations::CURRENT_SCHEMA_VERSION,
+            guest_invite: None,
         };
-        context.persistence_manager.create_retro(new_retro.clone()).await.unwrap();
+        context.persistence_manager.create_retro(new_retro.clone()).await?;
+        context.retros.prime(&new_retro);
 
         // Broadcast user list update for the new retro (initially empty)
-        let _ = context.user_update_sender.send(SubscriptionUpdate::UserListUpdated ( UserListUpdated {
+        context.event_bus.publish(SubscriptionUpdate::UserListUpdated ( UserListUpdated {
             retro_id: new_retro._id,
             participants: new_retro.participants.clone(),
-        }));
+        })).await;
 
-        new_retro
+        Ok(new_retro)
     }
 
     // Add a user to a retro
-    async fn enter_retro(context: &Context, retro_id: String) -> Vec<RetroParticipant> {
+    async fn enter_retro(context: &Context, retro_id: String) -> ApiResult<Vec<RetroParticipant>> {
+        context.require_api_scope(ApiTokenScope::JoinRetros)?;
         let uid = context.active_user._id;
-        let rid = ObjectId::from_str(&retro_id).unwrap();
-        let mut retro = context.persistence_manager.get_retro(&rid).await.unwrap();
-        if !retro.participants.iter().any(|p| p.user == uid) {
-            let participant = RetroParticipant {
-                user: uid,
-                retro_id: rid,
-            };
-            retro.participants.push(participant.clone());
-            context.persistence_manager.update_retro(retro.clone()).await.unwrap();
-
-            // Broadcast user list update
-            let _ = context.user_update_sender.send(SubscriptionUpdate::create_user_list_update(
-                rid,
-                retro.participants.clone(),
-            ));
+        let rid = parse_id(&retro_id)?;
+        context.require_retro_access(&rid)?;
+        let participant = RetroParticipant {
+            user: uid,
+            retro_id: rid,
+        };
+
+        match context.persistence_manager.add_participant(&rid, participant).await? {
+            Some(retro) => {
+                context.retros.prime(&retro);
+                // Broadcast user list update
+                context.event_bus.publish(SubscriptionUpdate::create_user_list_update(
+                    rid,
+                    retro.participants.clone(),
+                )).await;
+                Ok(retro.participants)
+            }
+            None => Ok(context.persistence_manager.get_retro(&rid).await?.participants),
         }
-        retro.participants.clone()
     }
 
     // Remove a user from a retro
-    async fn leave_retro(context: &Context, retro_id: String) -> Vec<RetroParticipant> {
+    async fn leave_retro(context: &Context, retro_id: String) -> ApiResult<Vec<RetroParticipant>> {
+        context.require_api_scope(ApiTokenScope::JoinRetros)?;
         let uid = context.active_user._id;
-        let rid = ObjectId::from_str(&retro_id).unwrap();
-        let mut retro = context.persistence_manager.get_retro(&rid).await.unwrap();
-        retro.participants.retain(|p| p.user != uid);
-        context.persistence_manager.update_retro(retro.clone()).await.unwrap();
-
-        // Broadcast user list update
-        let _ = context.user_update_sender.send(SubscriptionUpdate::create_user_list_update(
-            retro._id,
-            retro.participants.clone(),
-        ));
-
-        retro.participants.clone()
+        let rid = parse_id(&retro_id)?;
+        context.require_retro_access(&rid)?;
+        match context.persistence_manager.remove_participant(&rid, &uid).await? {
+            Some(retro) => {
+                context.retros.prime(&retro);
+                // Broadcast user list update
+                context.event_bus.publish(SubscriptionUpdate::create_user_list_update(
+                    retro._id,
+                    retro.participants.clone(),
+                )).await;
+                Ok(retro.participants)
+            }
+            None => Ok(context.persistence_manager.get_retro(&rid).await?.participants),
+        }
     }
 
     // Add a card to a retro
-    async fn add_card(context: &Context, input: AddCardInput) -> Option<Card> {
+    async fn add_card(context: &Context, input: AddCardInput) -> ApiResult<Option<Card>> {
+        context.require_api_scope(ApiTokenScope::WriteCards)?;
         let uid = context.active_user._id;
-        let rid = ObjectId::from_str(&input.retro_id).unwrap();
-        let mut retro = context.persistence_manager.get_retro(&rid).await.unwrap();
+        let rid = parse_id(&input.retro_id)?;
+        let lane_id = parse_id(&input.lane_id)?;
+        context.require_retro_access(&rid)?;
+
         let new_card = Card {
             id: ObjectId::new(),
             retro_id: rid,
@@ -333,97 +644,204 @@ impl MutationRoot {
             votes: HashSet::new(),
         };
 
-        let lane_id = ObjectId::from_str(&input.lane_id).unwrap();
-        let lane = retro.lanes.iter_mut().find(|l| l.id == lane_id);
+        let Some(retro) = context.persistence_manager.push_card(&rid, &lane_id, new_card.clone(), None).await? else {
+            return Ok(None);
+        };
+        context.retros.prime(&retro);
+        context.event_bus.publish(SubscriptionUpdate::create_card_added(
+            retro._id,
+            lane_id,
+            new_card.clone(),
+        )).await;
 
-        if let Some(l) = lane {
-            l.cards.push(new_card.clone());
-            let lane_id = l.id;
-            context.persistence_manager.update_retro(retro.clone()).await.unwrap();
+        Ok(Some(new_card))
+    }
 
-            let _ = context.card_addition_sender.send(SubscriptionUpdate::create_card_added(
-                retro._id,
-                lane_id,
-                new_card.clone(),
-            ));
+    async fn edit_card(context: &Context,  retro_id: String, card_id: String, text: String) -> ApiResult<Option<Card>> {
+        context.require_api_scope(ApiTokenScope::WriteCards)?;
+        let rid = parse_id(&retro_id)?;
+        context.require_retro_access(&rid)?;
+        let cid = parse_id(&card_id)?;
 
-            Some(new_card)
-        } else {
-            None
-        }
-    }
+        let Some(retro) = context.persistence_manager.update_card_text(&rid, &cid, text, None).await? else {
+            return Ok(None);
+        };
+        context.retros.prime(&retro);
+        let Some((lane, card)) = retro.find_card(&cid) else {
+            return Ok(None);
+        };
+        let new_card = card.clone();
 
-    async fn edit_card(context: &Context,  retro_id: String, card_id: String, text: String) -> Option<Card> {
-        let rid = ObjectId::from_str(&retro_id).unwrap();
-        let cid = ObjectId::from_str(&card_id).unwrap();
-        let mut retro = context.persistence_manager.get_retro(&rid).await.unwrap();
-        let lane = retro.lanes.iter_mut().find(|l| l.cards.iter().any(|c| c.id == cid));
-
-        if let Some(l) = lane {
-            let card = l.cards.iter_mut().find(|c| c.id == cid).unwrap();
-            card.text = text;
-            let lane_id = l.id;
-            let new_card = card.clone();
-            context.persistence_manager.update_retro(retro.clone()).await.unwrap();
-
-            let _ = context.card_addition_sender.send(SubscriptionUpdate::create_card_added(
-                retro._id,
-                lane_id,
-                new_card.clone(),
-            ));
-            Some(new_card)
-        } else {
-            None
-        }
+        context.event_bus.publish(SubscriptionUpdate::create_card_added(
+            retro._id,
+            lane.id,
+            new_card.clone(),
+        )).await;
+        Ok(Some(new_card))
     }
 
     // Vote for a card in the retro
-    async fn vote_card(context: &Context, retro_id: String, card_id: String, vote: bool) -> Option<Card> {
+    async fn vote_card(context: &Context, retro_id: String, card_id: String, vote: bool) -> ApiResult<Option<Card>> {
+        context.require_api_scope(ApiTokenScope::WriteCards)?;
         let uid = context.active_user._id;
-        let rid = ObjectId::from_str(&retro_id).unwrap();
-        let cid = ObjectId::from_str(&card_id).unwrap();
-        let mut retro = context.persistence_manager.get_retro(&rid).await.unwrap();
-        if let Some(lane) = retro.lanes.iter_mut().find(|l| l.cards.iter().any(|c| c.id == cid)) {
-            let lane_id = lane.id;
-            let card = lane.cards.iter_mut().find(|c| c.id == cid).unwrap();
+        let rid = parse_id(&retro_id)?;
+        context.require_retro_access(&rid)?;
+        let cid = parse_id(&card_id)?;
+
+        let updated = retry_on_conflict(|| async {
+            let retro = context.persistence_manager.get_retro(&rid).await?;
+            let Some((_, card)) = retro.find_card(&cid) else {
+                return Ok(None);
+            };
 
             if card.creator_id != uid {
-                return None;
+                return Err(ApiError::Forbidden("You cannot vote on this card".to_string()));
             }
 
             if vote {
-                card.votes.insert(uid);
+                Ok(context.persistence_manager.add_vote(&rid, &cid, &uid, Some(retro.version)).await?)
             } else {
-                card.votes.remove(&uid);
+                Ok(context.persistence_manager.remove_vote(&rid, &cid, &uid, Some(retro.version)).await?)
             }
+        }).await?;
+        let Some(retro) = updated else {
+            return Ok(None);
+        };
+        context.retros.prime(&retro);
+        let Some((lane, card)) = retro.find_card(&cid) else {
+            return Ok(None);
+        };
+        let new_card = card.clone();
 
-            let new_card = card.clone();
-
-            context.persistence_manager.update_retro(retro.clone()).await.unwrap();
+        context.event_bus.publish(SubscriptionUpdate::create_card_added(
+            retro._id,
+            lane.id,
+            new_card.clone(),
+        )).await;
+        Ok(Some(new_card))
+    }
 
-            let _ = context.card_addition_sender.send(SubscriptionUpdate::create_card_added(
-                retro._id,
-                lane_id,
-                new_card.clone(),
-            ));
-            Some(new_card)
-        } else {
-            None
+    async fn update_retro_step(context: &Context, retro_id: String, step: RetroStep) -> ApiResult<Option<Retro>> {
+        context.require_api_scope(ApiTokenScope::ManageRetros)?;
+        let rid = parse_id(&retro_id)?;
+        context.require_retro_access(&rid)?;
+        match context.persistence_manager.set_step(&rid, step.clone()).await? {
+            Some(retro) => {
+                context.retros.prime(&retro);
+                context.event_bus.publish(SubscriptionUpdate::create_step_update(
+                    retro._id,
+                    step,
+                )).await;
+                Ok(Some(retro))
+            }
+            None => Ok(Some(context.persistence_manager.get_retro(&rid).await?)),
         }
     }
 
-    async fn update_retro_step(context: &Context, retro_id: String, step: RetroStep) -> Option<Retro> {
-        let rid = ObjectId::from_str(&retro_id).unwrap();
-        let mut retro = context.persistence_manager.get_retro(&rid).await.unwrap();
-        retro.step = step.clone();
-        context.persistence_manager.update_retro(retro.clone()).await.unwrap();
+    async fn archive_retro(context: &Context, retro_id: String) -> ApiResult<Retro> {
+        context.require_api_scope(ApiTokenScope::ManageRetros)?;
+        let rid = parse_id(&retro_id)?;
+        context.require_retro_access(&rid)?;
+
+        let archived_at = Utc::now().to_rfc3339();
+        let retro = retry_on_conflict(|| async {
+            let retro = context.persistence_manager.get_retro(&rid).await?;
+            require_owner(context, &retro)?;
+            let updated = context.persistence_manager.set_archived(&rid, Some(archived_at.clone()), Some(retro.version)).await?;
+            Ok(updated.unwrap_or(retro))
+        }).await?;
+        context.retros.prime(&retro);
+        context.event_bus.publish(SubscriptionUpdate::create_retro_archived(rid, retro.archived_at.clone())).await;
+        Ok(retro)
+    }
 
-        let _ = context.step_update_sender.send(SubscriptionUpdate::create_step_update(
-            retro._id,
-            step,
-        ));
+    async fn unarchive_retro(context: &Context, retro_id: String) -> ApiResult<Retro> {
+        context.require_api_scope(ApiTokenScope::ManageRetros)?;
+        let rid = parse_id(&retro_id)?;
+        context.require_retro_access(&rid)?;
+
+        let retro = retry_on_conflict(|| async {
+            let retro = context.persistence_manager.get_retro(&rid).await?;
+            require_owner(context, &retro)?;
+            let updated = context.persistence_manager.set_archived(&rid, None, Some(retro.version)).await?;
+            Ok(updated.unwrap_or(retro))
+        }).await?;
+        context.retros.prime(&retro);
+        context.event_bus.publish(SubscriptionUpdate::create_retro_archived(rid, None)).await;
+        Ok(retro)
+    }
 
-        Some(retro.clone())
+    // Opens the retro to guests with a fresh invite code, or turns guests away when disabled
+    async fn set_guest_access(context: &Context, retro_id: String, enabled: bool) -> ApiResult<Retro> {
+        context.require_api_scope(ApiTokenScope::ManageRetros)?;
+        let rid = parse_id(&retro_id)?;
+        context.require_retro_access(&rid)?;
+
+        let invite = enabled.then(random_string);
+        let retro = retry_on_conflict(|| async {
+            let retro = context.persistence_manager.get_retro(&rid).await?;
+            require_owner(context, &retro)?;
+            let updated = context.persistence_manager.set_guest_invite(&rid, invite.clone(), Some(retro.version)).await?;
+            Ok(updated.unwrap_or(retro))
+        }).await?;
+        context.retros.prime(&retro);
+        Ok(retro)
+    }
+
+    // Creates a personal API token acting as the active user within `scopes`, returning its
+    // secret this one time
+    async fn create_api_token(context: &Context, name: String, scopes: Vec<ApiTokenScope>, expires_at: Option<String>) -> ApiResult<CreatedApiToken> {
+        context.require_login()?;
+        context.require_unscoped()?;
+        let name = validate_api_token_name(&name)?;
+        let now = Utc::now();
+        let expires_at = expires_at.as_deref().map(parse_datetime).transpose()?;
+        if expires_at.is_some_and(|expires_at| expires_at <= now) {
+            return Err(ApiError::InvalidArgument("expiresAt must be in the future".to_string()));
+        }
+        let mut scopes = scopes;
+        scopes.sort();
+        scopes.dedup();
+
+        let token = new_api_token();
+        let api_token = ApiToken {
+            _id: ObjectId::new(),
+            user_id: context.active_user._id,
+            name,
+            token_hash: hash_api_token(&token),
+            scopes,
+            created_at: now.to_rfc3339(),
+            expires_at: expires_at.map(|expires_at| expires_at.to_rfc3339()),
+        };
+        context.persistence_manager.create_api_token(api_token.clone()).await?;
+        Ok(CreatedApiToken { token, api_token })
+    }
+
+    // Revokes one of the active user's API tokens, returning its id
+    async fn revoke_api_token(context: &Context, id: String) -> ApiResult<String> {
+        context.require_login()?;
+        context.require_unscoped()?;
+        let token_id = parse_id(&id)?;
+        if !context.persistence_manager.delete_api_token(&context.active_user._id, &token_id).await? {
+            return Err(ApiError::NotFound("API token"));
+        }
+        Ok(id)
+    }
+
+    // Permanently removes a retro and everything in it, returning its id
+    async fn delete_retro(context: &Context, retro_id: String) -> ApiResult<String> {
+        context.require_api_scope(ApiTokenScope::ManageRetros)?;
+        let rid = parse_id(&retro_id)?;
+        context.require_retro_access(&rid)?;
+
+        retry_on_conflict(|| async {
+            let retro = context.persistence_manager.get_retro(&rid).await?;
+            require_owner(context, &retro)?;
+            Ok(context.persistence_manager.delete_retro(&rid, Some(retro.version)).await?)
+        }).await?;
+        context.retros.forget(&rid);
+        context.event_bus.publish(SubscriptionUpdate::create_retro_deleted(rid)).await;
+        Ok(retro_id)
     }
 }
 
@@ -433,3 +851,221 @@ pub type Schema = RootNode<'static, QueryRoot, MutationRoot, SubscriptionRoot>;
 pub fn create_schema() -> Schema {
     Schema::new(QueryRoot, MutationRoot, SubscriptionRoot)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, RwLock};
+
+    use juniper::{graphql_value, DefaultScalarValue, ExecutionError, IntoFieldError, Variables};
+
+    use super::*;
+    use crate::context::ContextBuilder;
+    use crate::database::PersistenceManager;
+    use crate::events::EventBus;
+
+    fn user(admin: bool) -> User {
+        User { _id: ObjectId::new(), username: "someone".to_string(), admin, guest: false, email: None, display_name: None }
+    }
+
+    fn memory() -> ContextBuilder {
+        let persistence_manager = PersistenceManager::new_memory(Arc::new(RwLock::new(HashMap::new())), Arc::new(RwLock::new(HashMap::new())));
+        ContextBuilder::new(persistence_manager, EventBus::new_memory())
+    }
+
+    async fn stored_retro(builder: &ContextBuilder, creator: &User) -> Retro {
+        let retro_id = ObjectId::new();
+        let retro = Retro {
+            _id: retro_id,
+            retro_name: "Sprint 1".to_string(),
+            creator_id: creator._id,
+            step: RetroStep::Writing,
+            created_at: Utc::now().to_rfc3339(),
+            participants: vec![RetroParticipant { user: creator._id, retro_id }],
+            lanes: vec![Lane { id: ObjectId::new(), title: "Good".to_string(), cards: vec![], priority: 1 }],
+            version: 0,
+            archived_at: None,
+            schema_version: migrations::CURRENT_SCHEMA_VERSION,
+            guest_invite: None,
+        };
+        builder.persistence_manager.create_retro(retro).await.unwrap()
+    }
+
+    async fn run(builder: &ContextBuilder, active_user: &User, query: &str) -> (juniper::Value, Vec<ExecutionError<DefaultScalarValue>>) {
+        let context = builder.clone().with_active_user(active_user.clone()).build();
+        juniper::execute(query, None, &create_schema(), &Variables::new(), &context).await.unwrap()
+    }
+
+    fn error_codes(errors: &[ExecutionError<DefaultScalarValue>]) -> Vec<String> {
+        errors.iter()
+            .map(|e| e.error().extensions().as_object_value()
+                .and_then(|extensions| extensions.get_field_value("code"))
+                .and_then(|code| code.as_scalar_value::<String>())
+                .cloned()
+                .unwrap_or_default())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn only_the_creator_or_an_admin_can_archive_or_delete() {
+        let builder = memory();
+        let creator = user(false);
+        let retro = stored_retro(&builder, &creator).await;
+        let id = retro._id.to_hex();
+
+        for mutation in ["archiveRetro", "unarchiveRetro"] {
+            let (_, errors) = run(&builder, &user(false), &format!(r#"mutation {{ {}(retroId: "{}") {{ id }} }}"#, mutation, id)).await;
+            assert_eq!(error_codes(&errors), ["FORBIDDEN"], "{} let someone else in", mutation);
+        }
+        let (_, errors) = run(&builder, &user(false), &format!(r#"mutation {{ deleteRetro(retroId: "{}") }}"#, id)).await;
+        assert_eq!(error_codes(&errors), ["FORBIDDEN"]);
+        assert_eq!(builder.persistence_manager.get_retro(&retro._id).await.unwrap().version, 0);
+
+        let (value, errors) = run(&builder, &creator, &format!(r#"mutation {{ archiveRetro(retroId: "{}") {{ archived }} }}"#, id)).await;
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(value, graphql_value!({ "archiveRetro": { "archived": true } }));
+        let (value, errors) = run(&builder, &user(true), &format!(r#"mutation {{ unarchiveRetro(retroId: "{}") {{ archived }} }}"#, id)).await;
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(value, graphql_value!({ "unarchiveRetro": { "archived": false } }));
+        let (_, errors) = run(&builder, &user(true), &format!(r#"mutation {{ deleteRetro(retroId: "{}") }}"#, id)).await;
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert!(builder.persistence_manager.get_retro(&retro._id).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn guests_only_reach_the_retro_they_joined() {
+        let builder = memory();
+        let creator = user(false);
+        let joined = stored_retro(&builder, &creator).await;
+        let other = stored_retro(&builder, &creator).await;
+        let guest = User { guest: true, ..user(false) };
+        let scoped = builder.clone().with_retro_scope(Some(joined._id));
+
+        let (value, errors) = run(&scoped, &guest, &format!(r#"{{ retroById(id: "{}") {{ retroName }} }}"#, joined._id.to_hex())).await;
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(value, graphql_value!({ "retroById": { "retroName": "Sprint 1" } }));
+        let (_, errors) = run(&scoped, &guest, &format!(r#"mutation {{ updateRetroStep(retroId: "{}", step: Voting) {{ id }} }}"#, joined._id.to_hex())).await;
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        for query in [
+            format!(r#"{{ retroById(id: "{}") {{ id }} }}"#, other._id.to_hex()),
+            format!(r#"mutation {{ updateRetroStep(retroId: "{}", step: Voting) {{ id }} }}"#, other._id.to_hex()),
+            "{ allRetros { id } }".to_string(),
+            "{ allUsers { id } }".to_string(),
+        ] {
+            let (_, errors) = run(&scoped, &guest, &query).await;
+            assert_eq!(error_codes(&errors), ["FORBIDDEN"], "{} let the guest through", query);
+        }
+        assert_eq!(builder.persistence_manager.get_retro(&other._id).await.unwrap().step, RetroStep::Writing);
+    }
+
+    #[tokio::test]
+    async fn api_tokens_are_held_to_their_scopes() {
+        let builder = memory();
+        let creator = user(false);
+        let retro = stored_retro(&builder, &creator).await;
+        let cards_only = builder.clone().with_api_scopes(vec![ApiTokenScope::WriteCards]);
+        let step = format!(r#"mutation {{ updateRetroStep(retroId: "{}", step: Voting) {{ step }} }}"#, retro._id.to_hex());
+
+        let (_, errors) = run(&cards_only, &creator, &step).await;
+        assert_eq!(error_codes(&errors), ["FORBIDDEN"]);
+        assert_eq!(builder.persistence_manager.get_retro(&retro._id).await.unwrap().step, RetroStep::Writing);
+        let (value, errors) = run(&builder.clone().with_api_scopes(vec![ApiTokenScope::ManageRetros]), &creator, &step).await;
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(value, graphql_value!({ "updateRetroStep": { "step": "Voting" } }));
+
+        // Not even with every scope can a token mint, list or revoke tokens
+        let every_scope = builder.clone().with_api_scopes(vec![ApiTokenScope::ManageRetros, ApiTokenScope::JoinRetros, ApiTokenScope::WriteCards]);
+        for query in [
+            r#"mutation { createApiToken(name: "ci", scopes: [WriteCards]) { token } }"#.to_string(),
+            "{ apiTokens { id } }".to_string(),
+            format!(r#"mutation {{ revokeApiToken(id: "{}") }}"#, ObjectId::new().to_hex()),
+        ] {
+            let (_, errors) = run(&every_scope, &creator, &query).await;
+            assert_eq!(error_codes(&errors), ["FORBIDDEN"], "{} let the token through", query);
+        }
+    }
+
+    #[tokio::test]
+    async fn api_tokens_need_a_name_and_a_future_expiry() {
+        let builder = memory();
+        let owner = user(false);
+        let create = |name: &str, expires_at: &str| format!(r#"mutation {{ createApiToken(name: "{}", scopes: [WriteCards], expiresAt: "{}") {{ apiToken {{ name expiresAt }} }} }}"#, name, expires_at);
+
+        let expires_at = Utc::now() + chrono::TimeDelta::days(30);
+        let local = expires_at.with_timezone(&FixedOffset::east_opt(2 * 3600).unwrap()).to_rfc3339();
+        let (value, errors) = run(&builder, &owner, &create(" ci ", &local)).await;
+        assert!(errors.is_empty(), "{:?}", errors);
+        let stored = expires_at.to_rfc3339();
+        assert_eq!(value, graphql_value!({ "createApiToken": { "apiToken": { "name": "ci", "expiresAt": stored } } }));
+
+        let too_long = "a".repeat(MAX_API_TOKEN_NAME_LENGTH + 1);
+        let past = (Utc::now() - chrono::TimeDelta::minutes(1)).to_rfc3339();
+        for query in [create("", &local), create(&too_long, &local), create("ci", &past), create("ci", "next week")] {
+            let (_, errors) = run(&builder, &owner, &query).await;
+            assert_eq!(error_codes(&errors), ["INVALID_ARGUMENT"], "{} was accepted", query);
+        }
+        let (_, errors) = run(&builder, &owner, &create(&"a".repeat(MAX_API_TOKEN_NAME_LENGTH), &local)).await;
+        assert!(errors.is_empty(), "{:?}", errors);
+    }
+
+    #[tokio::test]
+    async fn archiving_and_deleting_reach_open_subscriptions() {
+        let builder = memory();
+        let creator = user(false);
+        let retro = stored_retro(&builder, &creator).await;
+        let id = retro._id.to_hex();
+        let mut archived = builder.event_bus.subscribe(Topic::RetroArchived);
+        let mut deleted = builder.event_bus.subscribe(Topic::RetroDeleted);
+
+        run(&builder, &creator, &format!(r#"mutation {{ archiveRetro(retroId: "{}") {{ id }} }}"#, id)).await;
+        match archived.recv().await.unwrap() {
+            SubscriptionUpdate::RetroArchived(update) => {
+                assert_eq!(update.retro_id, retro._id);
+                assert!(update.archived_at.is_some());
+            }
+            update => panic!("unexpected update {:?}", update),
+        }
+        run(&builder, &creator, &format!(r#"mutation {{ unarchiveRetro(retroId: "{}") {{ id }} }}"#, id)).await;
+        assert!(matches!(archived.recv().await.unwrap(), SubscriptionUpdate::RetroArchived(update) if update.archived_at.is_none()));
+
+        run(&builder, &creator, &format!(r#"mutation {{ deleteRetro(retroId: "{}") }}"#, id)).await;
+        assert!(matches!(deleted.recv().await.unwrap(), SubscriptionUpdate::RetroDeleted(update) if update.retro_id == retro._id));
+    }
+
+    // The mutations write conditional on the version they read and retry when it moved on; a
+    // retro that keeps changing underneath them ends in CONFLICT
+    #[tokio::test]
+    async fn writes_from_a_stale_version_conflict() {
+        let builder = memory();
+        let creator = user(false);
+        let retro = stored_retro(&builder, &creator).await;
+        let persistence_manager = &builder.persistence_manager;
+
+        let stale = Some(retro.version);
+        persistence_manager.set_archived(&retro._id, Some(Utc::now().to_rfc3339()), stale).await.unwrap().unwrap();
+        let unarchived = persistence_manager.set_archived(&retro._id, None, stale).await.map(|_| ());
+        let deleted = persistence_manager.delete_retro(&retro._id, stale).await;
+        for result in [unarchived, deleted] {
+            let error: juniper::FieldError = ApiError::from(result.unwrap_err()).into_field_error();
+            assert_eq!(error.extensions(), &graphql_value!({ "code": "CONFLICT" }));
+        }
+        assert!(persistence_manager.get_retro(&retro._id).await.unwrap().archived_at.is_some());
+
+        let mut attempts = 0;
+        let result = retry_on_conflict(|| {
+            attempts += 1;
+            async {
+                let read = persistence_manager.get_retro(&retro._id).await?;
+                // Someone else gets in between every read and write
+                let step = if read.step == RetroStep::Writing { RetroStep::Voting } else { RetroStep::Writing };
+                persistence_manager.set_step(&retro._id, step).await?;
+                Ok(persistence_manager.delete_retro(&retro._id, Some(read.version)).await?)
+            }
+        }).await;
+        assert_eq!(attempts, MAX_UPDATE_ATTEMPTS);
+        let error: juniper::FieldError = result.unwrap_err().into_field_error();
+        assert_eq!(error.extensions(), &graphql_value!({ "code": "CONFLICT" }));
+        assert!(persistence_manager.get_retro(&retro._id).await.is_ok());
+    }
+}
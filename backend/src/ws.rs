@@ -0,0 +1,119 @@
+use std::{future::Future, pin::pin, sync::Arc};
+
+use actix_web::{http::header::{HeaderName, HeaderValue}, web, HttpRequest, HttpResponse};
+use actix_ws::{CloseCode, CloseReason, Session};
+use derive_more::derive::{Display, Error};
+use futures::{future::{self, Either, LocalBoxFuture}, stream::LocalBoxStream, FutureExt, SinkExt, StreamExt};
+use juniper::DefaultScalarValue;
+use juniper_graphql_ws::{graphql_transport_ws, graphql_ws, ArcSchema, Init};
+
+use crate::context::Context;
+use crate::schema::Schema;
+
+// Serves subscriptions over either GraphQL websocket protocol like juniper_actix does, but also
+// closes the connection with whatever `lapsed` resolves to, so a session that is no longer valid
+// stops receiving updates
+pub async fn handler<I, L>(req: HttpRequest, stream: web::Payload, schema: Arc<Schema>, init: I, lapsed: L) -> Result<HttpResponse, actix_web::Error>
+where
+    I: Init<DefaultScalarValue, Context> + Send,
+    L: Future<Output = CloseReason> + 'static,
+{
+    let legacy = req.headers().get("sec-websocket-protocol").map(AsRef::as_ref) == Some("graphql-ws".as_bytes());
+    let (mut response, session, messages) = actix_ws::handle(&req, stream)?;
+
+    let (input, output, protocol): (LocalBoxFuture<'static, ()>, LocalBoxStream<'static, Outgoing>, &'static str) = if legacy {
+        let (sink, output) = graphql_ws::Connection::new(ArcSchema(schema), init).split();
+        let input = messages.map(|m| m.map(Message)).forward(sink.sink_map_err(|e| match e {}));
+        let output = output.map(|message| Outgoing::Text(serde_json::to_string(&message)));
+        (input.map(|_| ()).boxed_local(), output.boxed_local(), "graphql-ws")
+    } else {
+        let (sink, output) = graphql_transport_ws::Connection::new(ArcSchema(schema), init).split();
+        let input = messages.map(|m| m.map(Message)).forward(sink.sink_map_err(|e| match e {}));
+        let output = output.map(|output| match output {
+            graphql_transport_ws::Output::Message(message) => Outgoing::Text(serde_json::to_string(&message)),
+            graphql_transport_ws::Output::Close { code, message } => Outgoing::Close(CloseReason {
+                code: code.into(),
+                description: Some(message),
+            }),
+        });
+        (input.map(|_| ()).boxed_local(), output.boxed_local(), "graphql-transport-ws")
+    };
+
+    actix_web::rt::spawn(serve(session, input, output, lapsed));
+
+    response.headers_mut().insert(HeaderName::from_static("sec-websocket-protocol"), HeaderValue::from_static(protocol));
+    Ok(response)
+}
+
+enum Outgoing {
+    Text(serde_json::Result<String>),
+    Close(CloseReason),
+}
+
+async fn serve(session: Session, input: LocalBoxFuture<'static, ()>, mut output: LocalBoxStream<'static, Outgoing>, lapsed: impl Future<Output = CloseReason>) {
+    let mut sender = session.clone();
+    let forward = pin!(async move {
+        while let Some(outgoing) = output.next().await {
+            match outgoing {
+                Outgoing::Text(Ok(text)) => {
+                    if sender.text(text).await.is_err() {
+                        return None;
+                    }
+                }
+                Outgoing::Text(Err(e)) => return Some(CloseReason {
+                    code: CloseCode::Error,
+                    description: Some(format!("error serializing response: {}", e)),
+                }),
+                Outgoing::Close(reason) => return Some(reason),
+            }
+        }
+        Some((CloseCode::Normal, "Normal Closure").into())
+    });
+
+    // Nothing is left to close once the client has gone
+    let reason = match future::select(input, future::select(forward, pin!(lapsed))).await {
+        Either::Left(_) => None,
+        Either::Right((Either::Left((reason, _)), _)) => reason,
+        Either::Right((Either::Right((reason, _)), _)) => Some(reason),
+    };
+    if let Some(reason) = reason {
+        let _ = session.close(Some(reason)).await;
+    }
+}
+
+#[derive(Debug)]
+struct Message(actix_ws::Message);
+
+impl TryFrom<Message> for graphql_transport_ws::Input<DefaultScalarValue> {
+    type Error = MessageError;
+
+    fn try_from(message: Message) -> Result<Self, Self::Error> {
+        match message.0 {
+            actix_ws::Message::Text(text) => serde_json::from_slice(text.as_bytes()).map(Self::Message).map_err(MessageError::Serde),
+            actix_ws::Message::Binary(bytes) => serde_json::from_slice(bytes.as_ref()).map(Self::Message).map_err(MessageError::Serde),
+            actix_ws::Message::Close(_) => Ok(Self::Close),
+            other => Err(MessageError::Unexpected(other)),
+        }
+    }
+}
+
+impl TryFrom<Message> for graphql_ws::ClientMessage<DefaultScalarValue> {
+    type Error = MessageError;
+
+    fn try_from(message: Message) -> Result<Self, Self::Error> {
+        match message.0 {
+            actix_ws::Message::Text(text) => serde_json::from_slice(text.as_bytes()).map_err(MessageError::Serde),
+            actix_ws::Message::Binary(bytes) => serde_json::from_slice(bytes.as_ref()).map_err(MessageError::Serde),
+            actix_ws::Message::Close(_) => Ok(Self::ConnectionTerminate),
+            other => Err(MessageError::Unexpected(other)),
+        }
+    }
+}
+
+#[derive(Debug, Display, Error)]
+enum MessageError {
+    #[display("invalid message from client: {_0}")]
+    Serde(serde_json::Error),
+    #[display("unexpected message from client: {_0:?}")]
+    Unexpected(#[error(not(source))] actix_ws::Message),
+}